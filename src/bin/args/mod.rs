@@ -0,0 +1,89 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+// Parsing of the command line arguments shared by the binaries
+
+use std;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+
+use clap::ArgMatches;
+
+// Returns the name the program was run with, to prefix the error messages
+fn program_name() -> String
+{
+  /*return*/ std::env::args().next()
+    .and_then(|path| Path::new(&path).file_name()
+      .map(|name| name.to_string_lossy().into_owned()))
+    .unwrap_or(String::from("paralio"))
+}
+
+// Prints an error message prefixed by the name of the program and exits
+pub fn exit_with_error(message: &str) -> !
+{
+  eprintln!("{}: {}", program_name(), message);
+  std::process::exit(1);
+}
+
+// Returns the value of a numeric argument, exiting with an error if it's not
+// a number or it's less than min
+pub fn number_arg<T>(matches: &ArgMatches, name: &str, min: T) -> T
+  where T: FromStr + PartialOrd + Display
+{
+  let value = matches.value_of(name).unwrap();
+  match value.parse()
+  {
+    Ok(number) if number >= min => number,
+    _ => exit_with_error(&format!(
+      "invalid --{} '{}', it must be a number of at least {}", name, value, min
+    ))
+  }
+}
+
+// Returns the value of an optional numeric argument, exiting with an error if
+// it's given and it's not a number or it's less than min
+#[allow(dead_code)]
+pub fn optional_number_arg<T>(matches: &ArgMatches, name: &str, min: T)
+  -> Option<T>
+  where T: FromStr + PartialOrd + Display
+{
+  /*return*/ match matches.is_present(name)
+  {
+    true => Some(number_arg(matches, name, min)),
+    false => None
+  }
+}
+
+// Returns the number of jobs, exiting with an error if it's not a positive
+// number
+pub fn jobs_arg(matches: &ArgMatches) -> i32
+{
+  /*return*/ number_arg(matches, "jobs", 1)
+}
+
+// Returns the list of key fields of an argument like "0,3", exiting with an
+// error if any of them is not a field number
+#[allow(dead_code)]
+pub fn key_fields_arg(matches: &ArgMatches, name: &str) -> Vec<usize>
+{
+  let fields = matches.value_of(name).unwrap();
+  /*return*/ fields.split(',').map(|field| field.parse())
+    .collect::<Result<Vec<usize>, _>>()
+    .unwrap_or_else(|_| exit_with_error(&format!(
+      "invalid --{} '{}', it must be a list of field numbers like 0,3", name, fields
+    )))
+}
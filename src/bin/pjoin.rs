@@ -19,9 +19,98 @@ extern crate clap;
 
 extern crate paralio;
 
+mod args;
+
 use clap::App;
+use clap::ArgMatches;
+
+use std::fs;
+use std::path::Path;
 
 use paralio::execute_parallel_join;
+use paralio::execute_hash_join;
+use paralio::execute_grace_hash_join;
+use paralio::JoinType;
+use paralio::Collation;
+use paralio::CsvDialect;
+use paralio::FailurePolicy;
+use paralio::Manifest;
+use paralio::print_output_stats;
+use paralio::hex_digest;
+use paralio::parts_path;
+use paralio::concatenate_outputs;
+use paralio::STDOUT_PATH;
+use paralio::SpooledInput;
+use paralio::STDIN_PATH;
+use paralio::file_sources;
+use paralio::OutputFile;
+use paralio::write_success_marker;
+
+use args::jobs_arg;
+use args::key_fields_arg;
+use args::number_arg;
+use args::optional_number_arg;
+
+// Returns the list of files of an input, given either as a list of files or
+// as a manifest
+fn input_file_list(matches: &ArgMatches, files_arg: &str, manifest_arg: &str)
+  -> Vec<String>
+{
+  match matches.value_of(manifest_arg)
+  {
+    Some(manifest_path) => Manifest::read_file_list(manifest_path)
+      .unwrap_or_else(|error| {
+        eprintln!("pjoin: {}", error);
+        std::process::exit(1);
+      }),
+    None => matches.values_of(files_arg).unwrap().map(String::from).collect()
+  }
+}
+
+// Returns the position of the join key fields in the output lines, used for
+// the first and last keys of the manifest. Empty if the output lines don't
+// contain the key, or if it's not in the same fields in all the lines.
+fn output_key_fields(
+  join_type: JoinType,
+  output_fields_str_list: &Vec<String>,
+  field1: &Vec<usize>,
+  field2: &Vec<usize>
+) -> Vec<usize>
+{
+  if join_type.whole_lines()
+  {
+    let file1_lines = join_type.unpaired1() || join_type == JoinType::Semi;
+    return match (file1_lines, join_type.unpaired2())
+    {
+      (true, false) => field1.clone(),
+      (false, true) => field2.clone(),
+      _ if field1 == field2 => field1.clone(),
+      _ => vec![]
+    }
+  }
+
+  let output_fields: Vec<(bool, usize)> = output_fields_str_list.iter()
+    .filter_map(|s| OutputFile::pair_split(s).ok())
+    .collect();
+  let key_positions = |is_file1: bool, fields: &Vec<usize>| -> Option<Vec<usize>>
+  {
+    fields.iter()
+      .map(|field| output_fields.iter().position(|&f| f == (is_file1, *field)))
+      .collect()
+  };
+  // the fields of the file without a line are empty in unpaired lines
+  let key1 = match join_type.unpaired2()
+  {
+    false => key_positions(true, field1),
+    true => None
+  };
+  let key2 = match join_type.unpaired1()
+  {
+    false => key_positions(false, field2),
+    true => None
+  };
+  /*return*/ key1.or(key2).unwrap_or(vec![])
+}
 
 fn main()
 {
@@ -37,40 +126,318 @@ fn main()
   let yaml = load_yaml!("pjoin.yml");
   let matches = App::from_yaml(yaml).get_matches();
 
-  let file1_str_list: Vec<String> = matches.values_of("file1").unwrap()
-    .map(String::from).collect();
+  let file1_str_list: Vec<String> = input_file_list(&matches, "file1", "manifest1");
+
+  let file2_str_list: Vec<String> = input_file_list(&matches, "file2", "manifest2");
 
-  let file2_str_list: Vec<String> = matches.values_of("file2").unwrap()
-    .map(String::from).collect();
+  // an empty separator separates fields by blanks
+  let separator = match matches.is_present("blanks")
+  {
+    true => String::new(),
+    false => matches.value_of("separator").unwrap().to_string()
+  };
 
-  let separator = matches.value_of("separator").unwrap().to_string();
+  let csv: Option<CsvDialect> = match matches.is_present("csv")
+  {
+    true => Some(
+      CsvDialect::parse(matches.value_of("quote").unwrap(), matches.value_of("escape"))
+        .unwrap_or_else(|error| {
+          eprintln!("pjoin: {}", error);
+          std::process::exit(1);
+        })
+    ),
+    false => None
+  };
 
-  let field1: u32 = matches.value_of("field1").unwrap().parse().unwrap();
+  let field1: Vec<usize> = key_fields_arg(&matches, "field1");
 
-  let field2: u32 = matches.value_of("field2").unwrap().parse().unwrap();
+  let field2: Vec<usize> = key_fields_arg(&matches, "field2");
 
-  let buffer_size: u32 = matches.value_of("field2").unwrap().parse().unwrap();
+  let collation = Collation::from_name(
+    matches.value_of("collation").unwrap()
+  ).unwrap();
+
+  let buffer_size: u32 = number_arg(&matches, "buffer-size", 1);
 
   let output_fields_str_list: Vec<String> = matches.values_of("output-fields")
     .unwrap().map(String::from).collect();
 
+  let unpaired: Vec<&str> = match matches.values_of("unpaired")
+  {
+    Some(values) => values.collect(),
+    None => vec![]
+  };
+  let only_unpaired: Vec<&str> = match matches.values_of("only-unpaired")
+  {
+    Some(values) => values.collect(),
+    None => vec![]
+  };
+  let join_type = if matches.is_present("semi")
+  {
+    JoinType::Semi
+  }
+  else if !only_unpaired.is_empty()
+  {
+    JoinType::from_only_unpaired(
+      only_unpaired.contains(&"1"),
+      only_unpaired.contains(&"2")
+    )
+  }
+  else
+  {
+    JoinType::from_unpaired(
+      unpaired.contains(&"1"),
+      unpaired.contains(&"2")
+    )
+  };
+
+  let empty = matches.value_of("empty").unwrap().to_string();
+
   let output_path = matches.value_of("output").unwrap().to_string();
 
+  // with --single-file, the numbered output files are written to a hidden
+  // directory next to the output file, and then concatenated into it
+  let single_file: bool = matches.is_present("single-file");
+  let output_dir_path = match single_file
+  {
+    true => parts_path(&output_path).to_string_lossy().into_owned(),
+    false => output_path.clone()
+  };
+
+  let on_failure = FailurePolicy::from_name(
+    matches.value_of("on-failure").unwrap()
+  ).unwrap();
+
+  let write_manifest: bool = matches.is_present("write-manifest");
+
+  let success_marker: bool = matches.is_present("success-marker");
+
   let verbose: bool = matches.is_present("verbose");
 
-  let njobs: i32 = matches.value_of("jobs").unwrap().parse().unwrap();
-
-  execute_parallel_join(
-    &file1_str_list,
-    &file2_str_list,
-    &separator,
-    field1,
-    field2,
-    &output_fields_str_list,
-    &output_path,
-    verbose,
-    njobs,
-    buffer_size
+  let print_digest: bool = matches.is_present("print-digest");
+
+  // the digests are only computed if they are going to be printed or written
+  let sha256: bool = verbose || write_manifest || print_digest;
+
+  // the output of a hash join is not sorted, and verbose info is written to
+  // stdout too
+  let streaming = output_path == STDOUT_PATH;
+  if streaming && (
+    matches.is_present("hash") || matches.is_present("grace") || single_file ||
+    write_manifest || success_marker || verbose
+  )
+  {
+    eprintln!(
+      "pjoin: --output - can't be used with --hash, --grace, --single-file, \
+      --write-manifest, --success-marker or --verbose"
+    );
+    std::process::exit(1);
+  }
+  // the digests of the output files are printed with --verbose instead
+  if print_digest && !streaming
+  {
+    eprintln!("pjoin: --print-digest can only be used with --output -");
+    std::process::exit(1);
+  }
+
+  if single_file
+  {
+    if let Err(error) = fs::create_dir_all(&output_dir_path)
+    {
+      eprintln!("pjoin: cannot create '{}': {}", output_dir_path, error);
+      std::process::exit(1);
+    }
+  }
+
+  let njobs: i32 = jobs_arg(&matches);
+
+  let grace: Option<u32> = optional_number_arg(&matches, "grace", 1);
+
+  let tmp_path = match matches.value_of("tmp-dir")
+  {
+    Some(tmp_path) => tmp_path.to_string(),
+    // there is no output directory when writing to stdout
+    None if streaming => std::env::temp_dir().to_string_lossy().into_owned(),
+    None => output_dir_path.clone()
+  };
+
+  // stdin and named pipes can't be read in parallel, so they are copied to
+  // temporary files first, removed once joined
+  if file1_str_list.contains(&String::from(STDIN_PATH)) &&
+    file2_str_list.contains(&String::from(STDIN_PATH))
+  {
+    eprintln!("pjoin: only one of the input files can be read from stdin");
+    std::process::exit(1);
+  }
+  let spooled1 = match SpooledInput::new(&file1_str_list, &tmp_path, verbose)
+  {
+    Ok(spooled1) => spooled1,
+    Err(error) =>
+    {
+      eprintln!("pjoin: {}", error);
+      std::process::exit(1);
+    }
+  };
+  let spooled2 = match SpooledInput::new(&file2_str_list, &tmp_path, verbose)
+  {
+    Ok(spooled2) => spooled2,
+    Err(error) =>
+    {
+      eprintln!("pjoin: {}", error);
+      drop(spooled1);
+      std::process::exit(1);
+    }
+  };
+
+  // with --mmap each input file is mapped only once here, and the threads
+  // share the maps
+  let mmap: bool = matches.is_present("mmap");
+  let sources = file_sources(&spooled1.file_str_list, mmap).and_then(
+    |sources1| Ok((sources1, file_sources(&spooled2.file_str_list, mmap)?))
   );
+  let (sources1, sources2) = match sources
+  {
+    Ok(sources) => sources,
+    Err(error) =>
+    {
+      eprintln!("pjoin: {}", error);
+      drop(spooled1);
+      drop(spooled2);
+      std::process::exit(1);
+    }
+  };
+
+  let result = if let Some(num_partitions) = grace
+  {
+    execute_grace_hash_join(
+      &sources1,
+      &sources2,
+      &separator,
+      csv,
+      &field1,
+      &field2,
+      collation,
+      &output_fields_str_list,
+      join_type,
+      &empty,
+      &output_dir_path,
+      sha256,
+      &tmp_path,
+      num_partitions,
+      verbose,
+      njobs,
+      buffer_size
+    )
+  }
+  else if matches.is_present("hash")
+  {
+    execute_hash_join(
+      &sources1,
+      &sources2,
+      &separator,
+      csv,
+      &field1,
+      &field2,
+      collation,
+      &output_fields_str_list,
+      join_type,
+      &empty,
+      &output_dir_path,
+      sha256,
+      verbose,
+      njobs,
+      buffer_size
+    )
+  }
+  else
+  {
+    execute_parallel_join(
+      &sources1,
+      &sources2,
+      &separator,
+      csv,
+      &field1,
+      &field2,
+      collation,
+      &output_fields_str_list,
+      join_type,
+      &empty,
+      &output_dir_path,
+      sha256,
+      verbose,
+      njobs,
+      buffer_size
+    )
+  };
+
+  drop(sources1);
+  drop(sources2);
+  drop(spooled1);
+  drop(spooled2);
 
+  let result = match result
+  {
+    Ok(outputs) if single_file =>
+      concatenate_outputs(&outputs, Path::new(&output_path), verbose).and_then(|output|
+      {
+        fs::remove_dir(&output_dir_path)?;
+        Ok(vec![output])
+      }),
+    result => result
+  };
+  // the failure policy only applies to the output files of a failed join,
+  // not to the ones already published when writing the manifest or the
+  // success marker fails
+  let published: bool = result.is_ok();
+  let result = result.and_then(|outputs|
+  {
+    if verbose
+    {
+      print_output_stats(&outputs);
+    }
+    // only what was written to stdout is returned
+    if let Some(sha256) = outputs.first().and_then(|output| output.sha256.as_ref())
+    {
+      if print_digest
+      {
+        eprintln!("sha256={}", hex_digest(sha256));
+      }
+    }
+    if write_manifest
+    {
+      Manifest::from_output(
+        &outputs,
+        &separator,
+        csv,
+        &output_key_fields(join_type, &output_fields_str_list, &field1, &field2),
+        std::env::args().collect()
+      )?.write(&output_path)?;
+    }
+    Ok(())
+  });
+  let result = match result
+  {
+    Ok(()) if success_marker => write_success_marker(&output_path),
+    result => result
+  };
+  if let Err(error) = result
+  {
+    eprintln!("pjoin: {}", error);
+    // there are no output files when writing to stdout
+    if !streaming && !published
+    {
+      // a hash join writes the unmatched lines of the build side to one more
+      // output file
+      if let Err(error) = on_failure.apply(&output_dir_path, njobs as usize + 1, verbose)
+      {
+        eprintln!("pjoin: {}", error);
+      }
+    }
+    if single_file && !published
+    {
+      // only removed if the failure policy left it empty
+      let _ = fs::remove_dir(&output_dir_path);
+    }
+    std::process::exit(1);
+  }
 }
@@ -0,0 +1,186 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+
+#[macro_use]
+extern crate clap;
+
+extern crate paralio;
+
+mod args;
+
+use std::path::Path;
+
+use clap::App;
+
+use paralio::execute_parallel_merge;
+use paralio::file_sources;
+use paralio::Collation;
+use paralio::CsvDialect;
+use paralio::FailurePolicy;
+use paralio::Manifest;
+use paralio::print_output_stats;
+use paralio::numbered_file_list;
+use paralio::write_success_marker;
+
+use args::jobs_arg;
+use args::key_fields_arg;
+use args::number_arg;
+
+// Returns the list of files of an input. An input can be a single file, a
+// directory with numbered and ordered files, like the output of pjoin, psort
+// or ppdump, or a manifest of those files.
+fn input_file_list(input_path: &str, is_manifest: bool) -> Vec<String>
+{
+  let file_list = if is_manifest
+  {
+    Manifest::read_file_list(input_path)
+  }
+  else if Path::new(input_path).is_dir()
+  {
+    numbered_file_list(input_path)
+  }
+  else
+  {
+    Ok(vec![String::from(input_path)])
+  };
+  /*return*/ file_list.unwrap_or_else(|error| {
+    eprintln!("pmerge: {}", error);
+    std::process::exit(1);
+  })
+}
+
+fn main()
+{
+  // Executes a parallel k-way merge
+  //
+  // 1. Split the key space in N slices (one per job/thread)
+  //
+  // 2. for each slice:
+  // 2.1. find the slice in every input
+  // 2.2. spawn a thread that merges the slices of all the inputs
+
+  let yaml = load_yaml!("pmerge.yml");
+  let matches = App::from_yaml(yaml).get_matches();
+
+  let input_list: Vec<Vec<String>> = match matches.values_of("manifest")
+  {
+    Some(manifest_paths) => manifest_paths
+      .map(|manifest_path| input_file_list(manifest_path, true)).collect(),
+    None => matches.values_of("input").unwrap()
+      .map(|input_path| input_file_list(input_path, false)).collect()
+  };
+
+  // an empty separator separates fields by blanks
+  let separator = match matches.is_present("blanks")
+  {
+    true => String::new(),
+    false => matches.value_of("separator").unwrap().to_string()
+  };
+
+  let csv: Option<CsvDialect> = match matches.is_present("csv")
+  {
+    true => Some(
+      CsvDialect::parse(matches.value_of("quote").unwrap(), matches.value_of("escape"))
+        .unwrap_or_else(|error| {
+          eprintln!("pmerge: {}", error);
+          std::process::exit(1);
+        })
+    ),
+    false => None
+  };
+
+  let key_fields: Vec<usize> = key_fields_arg(&matches, "key");
+
+  let collation = Collation::from_name(
+    matches.value_of("collation").unwrap()
+  ).unwrap();
+
+  let output_path = matches.value_of("output").unwrap().to_string();
+
+  let on_failure = FailurePolicy::from_name(
+    matches.value_of("on-failure").unwrap()
+  ).unwrap();
+
+  let write_manifest: bool = matches.is_present("write-manifest");
+
+  let success_marker: bool = matches.is_present("success-marker");
+
+  let buffer_size: u32 = number_arg(&matches, "buffer-size", 1);
+
+  let verbose: bool = matches.is_present("verbose");
+
+  // the digests are only computed if they are going to be printed or written
+  let sha256: bool = verbose || write_manifest;
+
+  let njobs: i32 = jobs_arg(&matches);
+
+  let mmap: bool = matches.is_present("mmap");
+  let source_list: Vec<Vec<_>> = input_list.iter().map(
+    |file_list| file_sources(file_list, mmap).unwrap_or_else(|error| {
+      eprintln!("pmerge: {}", error);
+      std::process::exit(1);
+    })
+  ).collect();
+
+  let result = execute_parallel_merge(
+    &source_list,
+    &separator,
+    csv,
+    &key_fields,
+    collation,
+    &output_path,
+    sha256,
+    verbose,
+    njobs,
+    buffer_size
+  );
+  // the failure policy only applies to the output files of a failed merge,
+  // not to the ones already published when writing the manifest or the
+  // success marker fails
+  let published: bool = result.is_ok();
+  let result = result.and_then(|outputs|
+  {
+    if verbose
+    {
+      print_output_stats(&outputs);
+    }
+    if write_manifest
+    {
+      Manifest::from_output(
+        &outputs, &separator, csv, &key_fields, std::env::args().collect()
+      )?.write(&output_path)?;
+    }
+    Ok(())
+  });
+  let result = match result
+  {
+    Ok(()) if success_marker => write_success_marker(&output_path),
+    result => result
+  };
+  if let Err(error) = result
+  {
+    eprintln!("pmerge: {}", error);
+    if !published
+    {
+      if let Err(error) = on_failure.apply(&output_path, njobs as usize, verbose)
+      {
+        eprintln!("pmerge: {}", error);
+      }
+    }
+    std::process::exit(1);
+  }
+}
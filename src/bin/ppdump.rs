@@ -20,17 +20,31 @@ extern crate time;
 #[macro_use]
 extern crate clap;
 
+extern crate paralio;
+
+mod args;
+
 use clap::App;
+use std::cmp;
 use std::thread;
 use std::time::Instant;
 use std::io::prelude::*;
-use std::fs::File;
 use std::path::Path;
-use std::io::BufWriter;
 use std::fmt::Write as StdWrite;
 
 use postgres::{Connection, TlsMode};
 
+use paralio::create_partial_file;
+use paralio::sync_file;
+use paralio::publish_outputs;
+use paralio::print_output_stats;
+use paralio::Manifest;
+use paralio::write_success_marker;
+use paralio::FailurePolicy;
+
+use args::jobs_arg;
+use args::number_arg;
+
 fn main()
 {
   let yaml = load_yaml!("ppdump.yml");
@@ -41,10 +55,17 @@ fn main()
   let connection_str = matches.value_of("connection").unwrap().to_string();
   let directory = matches.value_of("directory").unwrap().to_string();
   let separator = matches.value_of("separator").unwrap().to_string();
-  let batch_size: i64 = matches.value_of("batch-size").unwrap().parse().unwrap();
+  let batch_size: i64 = number_arg(&matches, "batch-size", 1);
   let verbose: bool = matches.is_present("verbose");
-
-  let njobs: i32 = matches.value_of("jobs").unwrap().parse().unwrap();
+  let write_manifest: bool = matches.is_present("write-manifest");
+  // the digests are only computed if they are going to be printed or written
+  let sha256: bool = verbose || write_manifest;
+  let success_marker: bool = matches.is_present("success-marker");
+  let on_failure = FailurePolicy::from_name(
+    matches.value_of("on-failure").unwrap()
+  ).unwrap();
+
+  let njobs: i32 = jobs_arg(&matches);
   let mut children = vec![];
 
   let start = Instant::now();
@@ -91,8 +112,10 @@ fn main()
         let mut limited_query_str = String::new();
         write!(&mut limited_query_str, "{} limit $1 offset $2", query_str).unwrap();
         let stmt = conn.prepare(&limited_query_str).unwrap();
+        // written to a partial file, published once all the threads finish
         let path = Path::new(&directory).join(thread_num.to_string());
-        let mut file = BufWriter::new(File::create(path).unwrap());
+        let mut file = create_partial_file(&path).unwrap();
+        file.set_sha256(sha256);
 
         for batch_num in 0..num_batches
         {
@@ -141,9 +164,13 @@ fn main()
               start.elapsed().as_secs(), thread_num, batch_num, batch_size);
           }
         }
+        sync_file(&mut file).unwrap()
       }));
   }
 
+  let mut outputs = vec![];
+  // each partition and the rows [start, end) it dumps
+  let mut failed_partitions: Vec<(i32, i64, i64)> = vec![];
   let mut i: i32 = 0;
   for child in children {
     // Wait for the thread to finish. Returns a result.
@@ -151,11 +178,59 @@ fn main()
       println!("{} secs \tbase_thread: finishing thread {}", start.elapsed().as_secs(), i);
     }
 
-    let _ = child.join();
+    match child.join()
+    {
+      Ok(output) => outputs.push(output),
+      Err(_) => failed_partitions.push((
+        i,
+        i as i64 * thread_size,
+        cmp::min((i as i64 + 1) * thread_size, total_count)
+      ))
+    }
 
     if verbose {
       println!("{} secs \tbase_thread: ... finished thread {}", start.elapsed().as_secs(), i);
     }
     i = i + 1;
   }
+
+  if !failed_partitions.is_empty()
+  {
+    for &(partition, start, end) in failed_partitions.iter()
+    {
+      eprintln!("ppdump: partition {} (rows {}..{}) failed", partition, start, end);
+    }
+    eprintln!("ppdump: no output file was published");
+    if let Err(error) = on_failure.apply(&directory, njobs as usize, verbose)
+    {
+      eprintln!("ppdump: {}", error);
+    }
+    std::process::exit(1);
+  }
+
+  let result = publish_outputs(outputs).and_then(|outputs|
+  {
+    if verbose
+    {
+      print_output_stats(&outputs);
+    }
+    // the order of the rows is not known, so keys are not written
+    if write_manifest
+    {
+      Manifest::from_output(
+        &outputs, &separator, None, &vec![], std::env::args().collect()
+      )?.write(&directory)?;
+    }
+    Ok(())
+  });
+  let result = match result
+  {
+    Ok(()) if success_marker => write_success_marker(&directory),
+    result => result
+  };
+  if let Err(error) = result
+  {
+    eprintln!("ppdump: {}", error);
+    std::process::exit(1);
+  }
 }
\ No newline at end of file
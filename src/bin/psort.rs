@@ -0,0 +1,171 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+
+#[macro_use]
+extern crate clap;
+
+extern crate paralio;
+
+mod args;
+
+use clap::App;
+
+use paralio::execute_parallel_sort;
+use paralio::Collation;
+use paralio::CsvDialect;
+use paralio::FailurePolicy;
+use paralio::Manifest;
+use paralio::print_output_stats;
+use paralio::write_success_marker;
+use paralio::file_sources;
+
+use args::jobs_arg;
+use args::key_fields_arg;
+use args::number_arg;
+
+fn main()
+{
+  // Executes a parallel external merge sort
+  //
+  // 1. Divide the input in N ranges (one per job/thread)
+  //
+  // 2. for each range, spawn a thread that sorts it in runs of up to
+  //    memory-limit bytes, written to temporary files
+  //
+  // 3. k-way merge the runs in parallel into N ordered output files
+
+  let yaml = load_yaml!("psort.yml");
+  let matches = App::from_yaml(yaml).get_matches();
+
+  let file_str_list: Vec<String> = match matches.value_of("manifest")
+  {
+    Some(manifest_path) => Manifest::read_file_list(manifest_path)
+      .unwrap_or_else(|error| {
+        eprintln!("psort: {}", error);
+        std::process::exit(1);
+      }),
+    None => matches.values_of("input").unwrap().map(String::from).collect()
+  };
+
+  // an empty separator separates fields by blanks
+  let separator = match matches.is_present("blanks")
+  {
+    true => String::new(),
+    false => matches.value_of("separator").unwrap().to_string()
+  };
+
+  let csv: Option<CsvDialect> = match matches.is_present("csv")
+  {
+    true => Some(
+      CsvDialect::parse(matches.value_of("quote").unwrap(), matches.value_of("escape"))
+        .unwrap_or_else(|error| {
+          eprintln!("psort: {}", error);
+          std::process::exit(1);
+        })
+    ),
+    false => None
+  };
+
+  let key_fields: Vec<usize> = key_fields_arg(&matches, "key");
+
+  let collation = Collation::from_name(
+    matches.value_of("collation").unwrap()
+  ).unwrap();
+
+  let output_path = matches.value_of("output").unwrap().to_string();
+
+  let on_failure = FailurePolicy::from_name(
+    matches.value_of("on-failure").unwrap()
+  ).unwrap();
+
+  let write_manifest: bool = matches.is_present("write-manifest");
+
+  let success_marker: bool = matches.is_present("success-marker");
+
+  let tmp_path = match matches.value_of("tmp-dir")
+  {
+    Some(tmp_path) => tmp_path.to_string(),
+    None => output_path.clone()
+  };
+
+  let memory_limit: u64 = number_arg(&matches, "memory-limit", 1);
+
+  let buffer_size: u32 = number_arg(&matches, "buffer-size", 1);
+
+  let verbose: bool = matches.is_present("verbose");
+
+  // the digests are only computed if they are going to be printed or written
+  let sha256: bool = verbose || write_manifest;
+
+  let njobs: i32 = jobs_arg(&matches);
+
+  let sources = file_sources(&file_str_list, matches.is_present("mmap"))
+    .unwrap_or_else(|error| {
+      eprintln!("psort: {}", error);
+      std::process::exit(1);
+    });
+
+  let result = execute_parallel_sort(
+    &sources,
+    &separator,
+    csv,
+    &key_fields,
+    collation,
+    &output_path,
+    sha256,
+    &tmp_path,
+    memory_limit,
+    verbose,
+    njobs,
+    buffer_size
+  );
+  // the failure policy only applies to the output files of a failed sort,
+  // not to the ones already published when writing the manifest or the
+  // success marker fails
+  let published: bool = result.is_ok();
+  let result = result.and_then(|outputs|
+  {
+    if verbose
+    {
+      print_output_stats(&outputs);
+    }
+    if write_manifest
+    {
+      Manifest::from_output(
+        &outputs, &separator, csv, &key_fields, std::env::args().collect()
+      )?.write(&output_path)?;
+    }
+    Ok(())
+  });
+  let result = match result
+  {
+    Ok(()) if success_marker => write_success_marker(&output_path),
+    result => result
+  };
+  if let Err(error) = result
+  {
+    eprintln!("psort: {}", error);
+    if !published
+    {
+      if let Err(error) = on_failure.apply(&output_path, njobs as usize, verbose)
+      {
+        eprintln!("psort: {}", error);
+      }
+    }
+    std::process::exit(1);
+  }
+}
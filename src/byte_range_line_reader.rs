@@ -14,11 +14,19 @@
  * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
 **/
 
-use std::io::Result;
+use std::io;
 use std::cmp;
 
 use ReadLiner;
 use MultiFileReader;
+use multi_file_reader::FindKeyPosition;
+use multi_file_reader::get_key;
+use multi_file_reader::remove_new_line;
+use source::ToSource;
+use source::to_sources;
+use Collation;
+use FieldFormat;
+use Result;
 
 /// ByteRangeLineReader allows to read sequencially only a slice of a
 /// MultiFileReader, from the current position to a specified multi-file end
@@ -34,23 +42,30 @@ pub struct ByteRangeLineReader
 impl ByteRangeLineReader
 {
   /// Returns a deep clone a ByteRangeLineReader
-  pub fn clone(&self) -> ByteRangeLineReader
+  pub fn clone(&self) -> Result<ByteRangeLineReader>
   {
-    return ByteRangeLineReader
+    return Ok(ByteRangeLineReader
     {
-      reader: self.reader.clone(),
+      reader: self.reader.clone()?,
       end: self.end,
       current: self.current,
       buffer_size: self.buffer_size
-    }
+    })
   }
 
   /// Divides a file in multiple ByteRangeLineReaders, trying to divide the
   /// readers with roughly the same number of bytes and dividing whole lines.
-  pub fn open(file_list: &Vec<String>, num_readers: u64, verbose: bool, buffer_size: u32)
-    -> Vec<ByteRangeLineReader>
+  ///
+  /// The file is a list of files, given as paths or as any other Source.
+  pub fn open<S: ToSource>(
+    file_list: &Vec<S>,
+    num_readers: u64,
+    verbose: bool,
+    buffer_size: u32
+  ) -> Result<Vec<ByteRangeLineReader>>
   {
-    let length = MultiFileReader::len(file_list);
+    let file_list = to_sources(file_list);
+    let length = MultiFileReader::len(&file_list)?;
     // make range a little bigger, so that the last range might be a bit overrun
     // (but of course we will control it) instead of not reading the final bytes
     let range_size: u64 = (length as f64 / num_readers as f64).ceil() as u64;
@@ -68,29 +83,93 @@ impl ByteRangeLineReader
         }
         let mut ret = ByteRangeLineReader
         {
-          reader: MultiFileReader::open(file_list, i * range_size),
-          end: (i + 1) * range_size,
+          reader: MultiFileReader::open(&file_list, i * range_size)?,
+          // the end is exclusive, but the line starting at the cut position
+          // belongs to this range, as the next one will skip it
+          end: (i + 1) * range_size + 1,
           current: i * range_size,
           buffer_size: buffer_size
         };
         if i > 0 {
-          let mut s: String = String::new();
-          ret.read_line(&mut s, false).unwrap();
+          ret.current = ret.reader.next_line_pos(i * range_size)?;
+          ret.reader.seek(ret.current)?;
         }
-        return ret
+        return Ok(ret)
+      }
+    ).collect()
+  }
+
+  /// Divides a file whose lines are sorted by the values at the `key_fields`
+  /// positions, in the order defined by `collation`, in multiple
+  /// ByteRangeLineReaders, like `open` does, but moving the limit between
+  /// each two ranges forward to the next change of key, so that all the lines
+  /// with the same key are in the same range.
+  ///
+  /// Note that some of the returned ranges might be empty, for example when
+  /// most of the lines have the same key.
+  pub fn open_by_key<S: ToSource>(
+    file_list: &Vec<S>,
+    num_readers: u64,
+    format: &FieldFormat,
+    key_fields: &Vec<usize>,
+    collation: Collation,
+    verbose: bool,
+    buffer_size: u32
+  ) -> Result<Vec<ByteRangeLineReader>>
+  {
+    let file_list = format.sources(file_list);
+    let length = MultiFileReader::len(&file_list)?;
+    let ranges = ByteRangeLineReader::open(
+      &file_list, num_readers, verbose, buffer_size
+    )?;
+    if ranges.is_empty()
+    {
+      return Ok(ranges)
+    }
+
+    // the limits between ranges are found using the last line of the byte
+    // ranges: the next range starts at the first line with a bigger key
+    let mut limits: Vec<u64> = vec![0];
+    for range in ranges.iter().take(ranges.len() - 1)
+    {
+      let limit: u64 = if range.end() >= length
+      {
+        length
+      }
+      else
+      {
+        let last_key: Vec<Vec<u8>> = get_key(
+          &range.last_line()?, format, key_fields
+        )?;
+        MultiFileReader::find_key_upper_bound(
+          last_key, &file_list, format, key_fields, collation, verbose
+        )?
+      };
+      let last_limit: u64 = *limits.last().unwrap();
+      limits.push(cmp::max(last_limit, limit));
+    }
+    limits.push(length);
+
+    return limits.windows(2).map(
+      |limit|
+      {
+        ByteRangeLineReader::open_range(
+          file_list.clone(), limit[0], limit[1], verbose, buffer_size
+        )
       }
     ).collect()
   }
 
   /// Creates a ByteRangeLineReader that reads a list of files from some
-  /// specific multi-file start & end positions.
-  pub fn open_range(
-    file_list: Vec<String>,
+  /// specific multi-file start & end positions. The lines read are the ones
+  /// starting at or after `start_pos` and before `end_pos`.
+  pub fn open_range<S: ToSource>(
+    file_list: Vec<S>,
     start_pos: u64,
     end_pos: u64,
     verbose: bool,
     buffer_size: u32
-  ) -> ByteRangeLineReader
+  ) -> Result<ByteRangeLineReader>
   {
     if verbose {
       println!(
@@ -99,13 +178,13 @@ impl ByteRangeLineReader
         end_pos
       );
     }
-    ByteRangeLineReader
+    Ok(ByteRangeLineReader
     {
-      reader: MultiFileReader::open(&file_list, start_pos),
+      reader: MultiFileReader::open(&file_list, start_pos)?,
       end: end_pos,
       current: start_pos,
       buffer_size: buffer_size
-    }
+    })
   }
 
   /// Returns the multi-file current position
@@ -114,84 +193,71 @@ impl ByteRangeLineReader
     self.current
   }
 
-  /// Returns the multi-file end position
+  /// Returns the multi-file end position. Lines starting at or after this
+  /// position are not part of the range.
   pub fn end(&self) -> u64
   {
     self.end
   }
 
+  /// Returns the size of the underlying MultiFileReader. A range whose end is
+  /// at or after this size is the last range of the files.
+  pub fn own_len(&self) -> u64
+  {
+    self.reader.own_len()
+  }
+
   /// Read the last line of th range. Note that the problem here is that the end
-  /// of the range is "orientative" and not exact. The end is defined by the
-  /// next line
-  pub fn last_line(&self) -> String
+  /// of the range is "orientative" and not exact. The last line is the one
+  /// that contains the last byte of the range, which might finish after the
+  /// end of the range.
+  ///
+  /// The last line can be longer than `buffer_size`, see
+  /// MultiFileReader::line_pos.
+  pub fn last_line(&self) -> Result<Vec<u8>>
   {
-    let seek_pos: u64 = cmp::max(
-      0,
-      (self.end as i64) - self.buffer_size as i64
-    ) as u64;
-    let buf_size: usize = (self.end - seek_pos) as usize + cmp::min(
-      self.buffer_size as i64,
-      cmp::max(
-        0,
-        self.reader.own_len() as i64 - self.end as i64
-      )
-     ) as usize;
-
-    let mut buf = vec![0;  buf_size];
-    let mut reader = self.reader.clone();
-    reader.seek(seek_pos);
-    reader.read(&mut buf).unwrap();
-
-    let lines: String;
-    let split: Vec<&str>;
-    let last_line: String;
-    let buf_end_pos: usize = (self.end - seek_pos + 1) as usize;
-
-    // case A: The end of the buffer is the end of the last file, so the last
-    // line is the last line in the buffer.
-    if buf_end_pos >= buf.len()
-    {
-      lines = String::from_utf8(buf).unwrap();
-      split = lines.split('\n').collect();
-      last_line = split[split.len()-2].to_string();
-    }
-    // case B: The end of the buffer is not the end of the last file, but
-    // the end of the byte range is a \n. This means that the next line
-    // after that new line char at self.end is what is considered the
-    // last line
-    else if buf[buf_end_pos] == ('\n' as u8)
-    {
-      lines = String::from_utf8(buf[buf_end_pos-1..buf.len()].to_vec()).unwrap();
-      split = lines.split('\n').collect();
-      last_line = split[0].to_string();
-    }
-    // case C: self.end is not a new line and because all lines end with a new
-    // line, it follows that self.end is not the end of the multi-file. This
-    // means that the last line starts after the first \n before self.end
-    else
+    // multi-file position of the last byte of the range
+    let last_pos: u64 = cmp::min(self.end, self.reader.own_len()) - 1;
+
+    let mut reader = self.reader.clone()?;
+    let line_pos: u64 = reader.line_pos(last_pos, self.buffer_size)?;
+
+    let mut last_line: Vec<u8> = vec![];
+    reader.seek(line_pos)?;
+    reader.read_line(&mut last_line, false)?;
+    remove_new_line(&mut last_line);
+    return Ok(last_line)
+  }
+
+  /// Reads the first line of the range, without changing the current position
+  /// of the ByteRangeLineReader. Returns None if the range has no lines left.
+  pub fn first_line(&self) -> Result<Option<Vec<u8>>>
+  {
+    let mut reader = self.clone()?;
+    let mut first_line: Vec<u8> = vec![];
+    match reader.read_line(&mut first_line, false)?
     {
-      let first_part = String::from_utf8(buf[0..(buf_end_pos - 1)].to_vec()).unwrap();
-      let start_pos = first_part.rfind('\n').unwrap();
-      lines = String::from_utf8(buf[(start_pos+1)..(buf.len())].to_vec()).unwrap();
-      split = lines.split('\n').collect();
-      last_line = split[0].to_string();
+      0 => Ok(None),
+      _ =>
+      {
+        remove_new_line(&mut first_line);
+        Ok(Some(first_line))
+      }
     }
-
-    return last_line
   }
 }
 
 impl ReadLiner for ByteRangeLineReader
 {
   /// Reads one line from the ByteRangeLineReader
-  fn read_line(&mut self, buf: &mut String, verbose: bool) -> Result<usize>
+  fn read_line(&mut self, buf: &mut Vec<u8>, verbose: bool) -> io::Result<usize>
   {
     if verbose {
       println!("ByteRangeLineReader::read_line {:p} ", self);
     }
-    if self.current <= self.end
+    if self.current < self.end
     {
-      let ret = self.reader.read_line(buf, verbose).unwrap();
+      let ret = self.reader.read_line(buf, verbose)?;
       self.current += ret as u64;
       return Ok(ret)
     } else
@@ -208,8 +274,11 @@ mod test
 
   use ByteRangeLineReader;
   use ReadLiner;
+  use Collation;
 
   use test_helpers::_write_files;
+  use test_helpers::_memory_files;
+  use test_helpers::_format;
 
   // Compares the output of a reader with some example string.
   // For example if the string is "0,1,2" it means that the reader will
@@ -219,16 +288,16 @@ mod test
     println!("assert_eq s={}", s);
     for x in s.to_string().split(",")
     {
-      let mut buf = String::new();
+      let mut buf: Vec<u8> = vec![];
       assert_eq!(reader.read_line(&mut buf, false).unwrap(), x.to_string().len() + 1);
-      assert_eq!(buf, x.to_string()+"\n");
+      assert_eq!(buf, (x.to_string()+"\n").into_bytes());
     }
-    let mut buf = String::new();
+    let mut buf: Vec<u8> = vec![];
     assert_eq!(reader.read_line(&mut buf, false).unwrap(), 0);
-    assert_eq!(buf, String::new());
+    assert_eq!(buf, Vec::<u8>::new());
 
     let expected_last_line: String = s.to_string().split(",").last().unwrap().to_string();
-    assert_eq!(expected_last_line, reader.last_line());
+    assert_eq!(expected_last_line.into_bytes(), reader.last_line().unwrap());
   }
 
   fn test_files(input: &str, output: &str)
@@ -240,13 +309,22 @@ mod test
 
     let mut readers = ByteRangeLineReader::open(
       &files, output_split.len() as u64, true, /*buffer_size*/16384
-      );
+      ).unwrap();
     assert_eq!(readers.len(), output_split.len());
 
     for (i, x) in output_split.iter().enumerate()
     {
       assert_eq(&mut readers[i], x);
     }
+
+    // the same files, kept in memory, must be read the same way
+    let mut readers = ByteRangeLineReader::open(
+      &_memory_files(input), output_split.len() as u64, false, /*buffer_size*/16384
+      ).unwrap();
+    for (i, x) in output_split.iter().enumerate()
+    {
+      assert_eq(&mut readers[i], x);
+    }
   }
 
   #[test]
@@ -315,21 +393,97 @@ mod test
 
     let mut readers = ByteRangeLineReader::open(
       &files, output_split.len() as u64, true, /*buffer_size*/16384
-    );
+    ).unwrap();
     assert_eq!(readers.len(), output_split.len());
 
-    let mut buf = String::new();
+    let mut buf: Vec<u8> = vec![];
     readers[0].read_line(&mut buf, false).unwrap();
-    assert_eq!(buf, String::from("0\n"));
+    assert_eq!(buf, b"0\n");
 
-    let mut buf = String::new();
+    let mut buf: Vec<u8> = vec![];
     readers[0].read_line(&mut buf, false).unwrap();
-    assert_eq!(buf, String::from("1\n"));
+    assert_eq!(buf, b"1\n");
 
-    assert_eq!(readers[0].last_line(), String::from("5"));
+    assert_eq!(readers[0].last_line().unwrap(), b"5");
 
-    let mut buf = String::new();
+    let mut buf: Vec<u8> = vec![];
     readers[0].read_line(&mut buf, false).unwrap();
-    assert_eq!(buf, String::from("2\n"));
+    assert_eq!(buf, b"2\n");
+  }
+
+  #[test]
+  fn test_last_line_longer_than_buffer()
+  {
+    let input: &str = "0000000000,1111111111|2222222222222222222222,3333333333333333";
+    let tmp_dir = TempDir::new("byterange").expect("create temp dir");
+    let files = _write_files(input, &tmp_dir);
+
+    let readers = ByteRangeLineReader::open(
+      &files, 2, false, /*buffer_size*/4
+    ).unwrap();
+    assert_eq!(readers.len(), 2);
+    assert_eq!(readers[0].last_line().unwrap(), b"2222222222222222222222");
+    assert_eq!(readers[1].last_line().unwrap(), b"3333333333333333");
+  }
+
+  #[test]
+  fn test_open_by_key()
+  {
+    let v: Vec<(&str, &str)> = vec![
+      (
+        "0,1,2,3,4,5,6,7,8,9",
+        "0,1,2,3,4,5|6,7,8,9"
+      ),
+      (
+        "0,1,1,1,1,1,2,3",
+        "0,1,1,1,1,1|2,3"
+      ),
+      (
+        "0,1,1|1,1,1,1|2,3",
+        "0,1,1,1,1,1,1|2,3"
+      ),
+      (
+        "0,1,1,1,1,1,1,1",
+        "0,1,1,1,1,1,1,1|"
+      ),
+      (
+        "0,0,1,1,2,2,3,3,4,4,5,5",
+        "0,0,1,1,2,2|3,3,4,4|5,5"
+      ),
+    ];
+    for &(input, output) in v.iter()
+    {
+      let tmp_dir = TempDir::new("byterange").expect("create temp dir");
+      let files = _write_files(input, &tmp_dir);
+      let output_split: Vec<&str> = output.split('|').collect();
+
+      let mut readers = ByteRangeLineReader::open_by_key(
+        &files, output_split.len() as u64, &_format(","), &vec![0], Collation::Bytewise,
+        false, /*buffer_size*/16384
+      ).unwrap();
+      assert_eq!(readers.len(), output_split.len());
+
+      for (i, x) in output_split.iter().enumerate()
+      {
+        let mut lines: Vec<String> = Vec::new();
+        let mut buf: Vec<u8> = vec![];
+        while readers[i].read_line(&mut buf, false).unwrap() > 0
+        {
+          buf.pop();
+          lines.push(String::from_utf8(buf.clone()).unwrap());
+          buf.clear();
+        }
+        assert_eq!(lines.join(","), x.to_string());
+      }
+    }
+
+    // no ranges
+    let tmp_dir = TempDir::new("byterange").expect("create temp dir");
+    let files = _write_files("0,1,2", &tmp_dir);
+    let readers = ByteRangeLineReader::open_by_key(
+      &files, 0, &_format(","), &vec![0], Collation::Bytewise,
+      false, /*buffer_size*/16384
+    ).unwrap();
+    assert_eq!(readers.len(), 0);
   }
 }
@@ -0,0 +1,270 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::cmp::Ordering;
+
+/// Defines how keys are compared, which must be the same order in which the
+/// input files are sorted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collation
+{
+  /// Compares the bytes of the keys, like `LC_ALL=C sort`.
+  Bytewise,
+  /// Compares the keys as integer numbers, like `sort -n` with integers.
+  Integer,
+  /// Compares the keys as decimal numbers, like `sort -n`.
+  Decimal,
+  /// Compares the keys ignoring the case of ASCII letters, like `sort -f`,
+  /// which folds them to uppercase, so that `_` sorts after letters.
+  IgnoreCase,
+  /// Compares the keys as version numbers, like `sort -V`: the numbers inside
+  /// the keys are compared as numbers and the rest bytewise.
+  Version
+}
+
+impl Collation
+{
+  /// Returns the collation with the given name, as used in the command line,
+  /// or None if there is no collation with that name.
+  pub fn from_name(name: &str) -> Option<Collation>
+  {
+    match name
+    {
+      "bytes" => Some(Collation::Bytewise),
+      "integer" => Some(Collation::Integer),
+      "decimal" => Some(Collation::Decimal),
+      "ignore-case" => Some(Collation::IgnoreCase),
+      "version" => Some(Collation::Version),
+      _ => None
+    }
+  }
+
+  /// Compares two key values, which can be any bytes, not only UTF-8 text
+  pub fn compare(&self, a: &[u8], b: &[u8]) -> Ordering
+  {
+    match *self
+    {
+      Collation::Bytewise => a.cmp(b),
+      Collation::Integer => compare_numbers(a, b, false),
+      Collation::Decimal => compare_numbers(a, b, true),
+      Collation::IgnoreCase => {
+        let a_upper = a.iter().map(|c| c.to_ascii_uppercase());
+        let b_upper = b.iter().map(|c| c.to_ascii_uppercase());
+        a_upper.cmp(b_upper)
+      },
+      Collation::Version => compare_versions(a, b)
+    }
+  }
+
+  /// Compares two keys composed of multiple values lexicographically: first
+  /// by the first value, then by the second, and so on.
+  pub fn compare_keys<T: AsRef<[u8]>>(&self, a: &[T], b: &[T]) -> Ordering
+  {
+    for (a_value, b_value) in a.iter().zip(b.iter())
+    {
+      match self.compare(a_value.as_ref(), b_value.as_ref())
+      {
+        Ordering::Equal => continue,
+        ordering => return ordering
+      }
+    }
+    a.len().cmp(&b.len())
+  }
+}
+
+/// Splits a number in its sign, integer digits without leading zeros and, if
+/// `decimal` is set, fractional digits without trailing zeros. Like `sort -n`
+/// does, only the leading numeric part of the string is used, after any
+/// leading blanks, so that a string with no number is equal to zero.
+fn split_number(s: &[u8], decimal: bool) -> (bool, &[u8], &[u8])
+{
+  let blanks = s.iter().take_while(|c| c.is_ascii_whitespace()).count();
+  let bytes = &s[blanks..];
+  let (negative, bytes) = match bytes.first()
+  {
+    Some(&b'-') => (true, &bytes[1..]),
+    Some(&b'+') => (false, &bytes[1..]),
+    _ => (false, bytes)
+  };
+  let integer_len = bytes.iter().take_while(|c| c.is_ascii_digit()).count();
+  let integer = &bytes[..integer_len];
+  let integer_start = integer.iter().take_while(|&&c| c == b'0').count();
+
+  let mut fraction: &[u8] = &[];
+  if decimal && bytes.get(integer_len) == Some(&b'.')
+  {
+    let rest = &bytes[integer_len + 1..];
+    let fraction_len = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+    fraction = &rest[..fraction_len];
+    while fraction.last() == Some(&b'0')
+    {
+      fraction = &fraction[..fraction.len() - 1];
+    }
+  }
+  let integer = &integer[integer_start..];
+
+  // zero has no sign
+  let negative = negative && !(integer.is_empty() && fraction.is_empty());
+  (negative, integer, fraction)
+}
+
+/// Compares two strings as numbers, without any limit in the number of
+/// digits.
+fn compare_numbers(a: &[u8], b: &[u8], decimal: bool) -> Ordering
+{
+  let (a_negative, a_integer, a_fraction) = split_number(a, decimal);
+  let (b_negative, b_integer, b_fraction) = split_number(b, decimal);
+
+  let absolute_ordering = a_integer.len().cmp(&b_integer.len())
+    .then(a_integer.cmp(b_integer))
+    .then(a_fraction.cmp(b_fraction));
+
+  match (a_negative, b_negative)
+  {
+    (false, false) => absolute_ordering,
+    (true, true) => absolute_ordering.reverse(),
+    (true, false) => Ordering::Less,
+    (false, true) => Ordering::Greater
+  }
+}
+
+/// Compares two strings as versions, splitting them in runs of digits, that
+/// are compared as numbers, and runs of other characters, compared bytewise.
+fn compare_versions(a: &[u8], b: &[u8]) -> Ordering
+{
+  let mut a_bytes = a;
+  let mut b_bytes = b;
+  while !a_bytes.is_empty() && !b_bytes.is_empty()
+  {
+    let a_digits = a_bytes[0].is_ascii_digit();
+    let b_digits = b_bytes[0].is_ascii_digit();
+    if a_digits != b_digits
+    {
+      return a_bytes[0].cmp(&b_bytes[0])
+    }
+    let a_len = a_bytes.iter().take_while(|c| c.is_ascii_digit() == a_digits)
+      .count();
+    let b_len = b_bytes.iter().take_while(|c| c.is_ascii_digit() == b_digits)
+      .count();
+    let ordering = if a_digits
+    {
+      compare_numbers(&a_bytes[..a_len], &b_bytes[..b_len], false)
+    }
+    else
+    {
+      a_bytes[..a_len].cmp(&b_bytes[..b_len])
+    };
+    if ordering != Ordering::Equal
+    {
+      return ordering
+    }
+    a_bytes = &a_bytes[a_len..];
+    b_bytes = &b_bytes[b_len..];
+  }
+  a_bytes.len().cmp(&b_bytes.len())
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::cmp::Ordering;
+
+  use Collation;
+
+  #[test]
+  fn test_from_name()
+  {
+    assert_eq!(Collation::from_name("bytes"), Some(Collation::Bytewise));
+    assert_eq!(Collation::from_name("integer"), Some(Collation::Integer));
+    assert_eq!(Collation::from_name("decimal"), Some(Collation::Decimal));
+    assert_eq!(Collation::from_name("ignore-case"), Some(Collation::IgnoreCase));
+    assert_eq!(Collation::from_name("version"), Some(Collation::Version));
+    assert_eq!(Collation::from_name("whatever"), None);
+  }
+
+  #[test]
+  fn test_compare()
+  {
+    let l: Vec<(Collation, &str, &str, Ordering)> = vec![
+      (Collation::Bytewise, "10", "9", Ordering::Less),
+      (Collation::Bytewise, "B", "a", Ordering::Less),
+      (Collation::Bytewise, "a", "a", Ordering::Equal),
+      (Collation::Integer, "10", "9", Ordering::Greater),
+      (Collation::Integer, "010", "10", Ordering::Equal),
+      (Collation::Integer, "-10", "-9", Ordering::Less),
+      (Collation::Integer, "-1", "0", Ordering::Less),
+      (Collation::Integer, "-0", "0", Ordering::Equal),
+      (Collation::Integer, "", "0", Ordering::Equal),
+      (Collation::Integer, "123456789012345678901234567890", "9", Ordering::Greater),
+      (Collation::Integer, "1.5", "1", Ordering::Equal),
+      (Collation::Decimal, "1.5", "1", Ordering::Greater),
+      (Collation::Decimal, "1.50", "1.5", Ordering::Equal),
+      (Collation::Decimal, "-1.5", "-1.25", Ordering::Less),
+      (Collation::Decimal, "0.5", ".5", Ordering::Equal),
+      (Collation::Decimal, "10.1", "9.99", Ordering::Greater),
+      (Collation::IgnoreCase, "B", "a", Ordering::Greater),
+      (Collation::IgnoreCase, "ABC", "abc", Ordering::Equal),
+      (Collation::IgnoreCase, "_", "a", Ordering::Greater),
+      (Collation::IgnoreCase, "[", "B", Ordering::Greater),
+      (Collation::IgnoreCase, "^", "z", Ordering::Greater),
+      (Collation::Version, "file10", "file9", Ordering::Greater),
+      (Collation::Version, "1.10.0", "1.9.2", Ordering::Greater),
+      (Collation::Version, "1.2", "1.2.1", Ordering::Less),
+      (Collation::Version, "a01", "a1", Ordering::Equal),
+    ];
+    for &(collation, a, b, ordering) in l.iter()
+    {
+      assert_eq!(collation.compare(a.as_bytes(), b.as_bytes()), ordering, "{:?} {} {}", collation, a, b);
+      assert_eq!(collation.compare(b.as_bytes(), a.as_bytes()), ordering.reverse(), "{:?} {} {}", collation, b, a);
+    }
+  }
+
+  #[test]
+  fn test_compare_bytes()
+  {
+    // not valid UTF-8
+    let latin1: &[u8] = b"caf\xe9";
+    assert_eq!(Collation::Bytewise.compare(latin1, b"cafe"), Ordering::Greater);
+    assert_eq!(Collation::IgnoreCase.compare(latin1, b"CAF\xe9"), Ordering::Equal);
+    assert_eq!(Collation::Version.compare(b"\xff10", b"\xff9"), Ordering::Greater);
+    assert_eq!(Collation::Integer.compare(b"\xe9", b"0"), Ordering::Equal);
+  }
+
+  #[test]
+  fn test_compare_keys()
+  {
+    let key = |values: Vec<&str>| -> Vec<Vec<u8>>
+    {
+      values.iter().map(|value| value.as_bytes().to_vec()).collect()
+    };
+    assert_eq!(
+      Collation::Integer.compare_keys(&key(vec!["2", "10"]), &key(vec!["2", "9"])),
+      Ordering::Greater
+    );
+    assert_eq!(
+      Collation::Integer.compare_keys(&key(vec!["10", "1"]), &key(vec!["9", "2"])),
+      Ordering::Greater
+    );
+    assert_eq!(
+      Collation::Bytewise.compare_keys(&key(vec!["10", "1"]), &key(vec!["9", "2"])),
+      Ordering::Less
+    );
+    assert_eq!(
+      Collation::IgnoreCase.compare_keys(&key(vec!["A", "b"]), &key(vec!["a", "B"])),
+      Ordering::Equal
+    );
+  }
+}
@@ -0,0 +1,534 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::io;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use Error;
+use Result;
+use source::Source;
+use source::SeekRead;
+use memchr::memchr3;
+
+// Every how many bytes of a CsvFile its index keeps the position of a record
+const INDEX_CHUNK_SIZE: u64 = 65536;
+
+/// Quoting rules of CSV files, as in RFC 4180: fields can be enclosed in
+/// quotes, and then contain separators, new lines and quotes, written twice.
+/// Optionally, an escape char inside quotes makes the next char literal.
+///
+/// Every quote char toggles whether the text is quoted, wherever it is in a
+/// field, and so a record ends at the first new line outside quotes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CsvDialect
+{
+  quote: u8,
+  escape: Option<u8>
+}
+
+/// Whether the text being read is quoted or not
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuoteState
+{
+  Unquoted,
+  Quoted,
+  /// Quoted, after an escape char
+  Escaped
+}
+
+impl CsvDialect
+{
+  /// Returns the dialect of RFC 4180: double quotes, written twice inside
+  /// quotes
+  pub fn rfc4180() -> CsvDialect
+  {
+    CsvDialect
+    {
+      quote: b'"',
+      escape: None
+    }
+  }
+
+  /// Creates a CsvDialect with the given quote and escape chars, which must be
+  /// ASCII and not new lines. An escape char equal to the quote char means
+  /// that quotes are written twice inside quotes, as with no escape char.
+  pub fn new(quote: char, escape: Option<char>) -> Result<CsvDialect>
+  {
+    let to_byte = |c: char| -> Result<u8>
+    {
+      if !c.is_ascii() || c == '\n' || c == '\r'
+      {
+        return Err(Error::InvalidArgument(
+          format!("CSV quote and escape chars must be ASCII: {:?}", c)
+        ))
+      }
+      /*return*/ Ok(c as u8)
+    };
+    let quote = to_byte(quote)?;
+    let escape = match escape
+    {
+      Some(escape) => Some(to_byte(escape)?).filter(|&escape| escape != quote),
+      None => None
+    };
+    /*return*/ Ok(CsvDialect
+    {
+      quote: quote,
+      escape: escape
+    })
+  }
+
+  /// Creates a CsvDialect from the quote and escape chars given as strings,
+  /// like in command line options, which must have a single char
+  pub fn parse(quote: &str, escape: Option<&str>) -> Result<CsvDialect>
+  {
+    let to_char = |value: &str| -> Result<char>
+    {
+      let mut chars = value.chars();
+      match (chars.next(), chars.next())
+      {
+        (Some(c), None) => Ok(c),
+        _ => Err(Error::InvalidArgument(
+          format!("CSV quote and escape chars must be a single char: '{}'", value)
+        ))
+      }
+    };
+    let escape = match escape
+    {
+      Some(escape) => Some(to_char(escape)?),
+      None => None
+    };
+    CsvDialect::new(to_char(quote)?, escape)
+  }
+
+  /// Returns the position of the first new line, quote char or escape char
+  /// of `bytes`, the only chars that can end a record or change whether the
+  /// text is quoted
+  pub fn find_new_line_or_quote(&self, bytes: &[u8]) -> Option<usize>
+  {
+    memchr3(b'\n', self.quote, self.escape.unwrap_or(self.quote), bytes)
+  }
+
+  /// Returns the state after reading `bytes` in the `state` state
+  pub fn scan(&self, state: QuoteState, bytes: &[u8]) -> QuoteState
+  {
+    bytes.iter().fold(state, |state, &byte| self.next_state(state, byte))
+  }
+
+  /// Returns whether the text is quoted after reading `byte` in `state`
+  pub fn next_state(&self, state: QuoteState, byte: u8) -> QuoteState
+  {
+    match state
+    {
+      QuoteState::Unquoted if byte == self.quote => QuoteState::Quoted,
+      QuoteState::Unquoted => QuoteState::Unquoted,
+      QuoteState::Quoted if byte == self.quote => QuoteState::Unquoted,
+      QuoteState::Quoted if Some(byte) == self.escape => QuoteState::Escaped,
+      QuoteState::Quoted => QuoteState::Quoted,
+      QuoteState::Escaped => QuoteState::Quoted
+    }
+  }
+
+  /// Splits a record, without its ending new line, in the values of its
+  /// fields, removing the quotes and escape chars
+  pub fn split(&self, record: &[u8], separator: &[u8]) -> Vec<Vec<u8>>
+  {
+    let mut values: Vec<u8> = vec![];
+    let mut ranges: Vec<Range<usize>> = vec![];
+    self.split_into(record, separator, &mut values, &mut ranges);
+    ranges.into_iter().map(|range| values[range].to_vec()).collect()
+  }
+
+  /// Splits a record like `split` does, but writing the values of its fields
+  /// one after the other in `values` and their ranges in `ranges`, which are
+  /// cleared first, so that their memory can be reused for each record
+  pub fn split_into(
+    &self,
+    record: &[u8],
+    separator: &[u8],
+    values: &mut Vec<u8>,
+    ranges: &mut Vec<Range<usize>>
+  )
+  {
+    values.clear();
+    ranges.clear();
+    let mut record = record;
+    while record.last() == Some(&b'\r')
+    {
+      record = &record[..record.len() - 1];
+    }
+
+    let mut field_start: usize = 0;
+    let mut state = QuoteState::Unquoted;
+    // a quote right after the one that closes a quoted text is a literal quote
+    let mut closed_quote = false;
+    let mut i: usize = 0;
+    while i < record.len()
+    {
+      let byte = record[i];
+      let after_quote = closed_quote;
+      closed_quote = false;
+      match state
+      {
+        QuoteState::Unquoted if record[i..].starts_with(separator) =>
+        {
+          ranges.push(field_start..values.len());
+          field_start = values.len();
+          // skip the rest of the separator
+          i += separator.len() - 1;
+        },
+        QuoteState::Unquoted if byte == self.quote =>
+        {
+          if after_quote
+          {
+            values.push(self.quote);
+          }
+          state = QuoteState::Quoted;
+        },
+        QuoteState::Quoted if byte == self.quote =>
+        {
+          closed_quote = true;
+          state = QuoteState::Unquoted;
+        },
+        QuoteState::Quoted if Some(byte) == self.escape =>
+          state = QuoteState::Escaped,
+        QuoteState::Escaped =>
+        {
+          values.push(byte);
+          state = QuoteState::Quoted;
+        },
+        _ => values.push(byte)
+      }
+      i += 1;
+    }
+    ranges.push(field_start..values.len());
+  }
+
+  /// Joins the values of some fields in a record, without ending new line,
+  /// quoting the values that need it
+  pub fn join<T: AsRef<[u8]>>(&self, fields: &[T], separator: &[u8]) -> Vec<u8>
+  {
+    let mut record: Vec<u8> = vec![];
+    self.join_into(fields.iter(), separator, &mut record);
+    /*return*/ record
+  }
+
+  /// Joins the values of some fields like `join` does, but appending the
+  /// record to `record`
+  pub fn join_into<T, I>(&self, fields: I, separator: &[u8], record: &mut Vec<u8>)
+    where T: AsRef<[u8]>, I: IntoIterator<Item = T>
+  {
+    for (i, field) in fields.into_iter().enumerate()
+    {
+      let field: &[u8] = field.as_ref();
+      if i > 0
+      {
+        record.extend_from_slice(separator);
+      }
+      let needs_quotes = field.windows(separator.len()).any(|w| w == separator) ||
+        field.iter().any(
+          |&byte| byte == self.quote || byte == b'\n' || byte == b'\r' ||
+            Some(byte) == self.escape
+        );
+      if !needs_quotes
+      {
+        record.extend_from_slice(field);
+        continue;
+      }
+      record.push(self.quote);
+      for &byte in field.iter()
+      {
+        match self.escape
+        {
+          Some(escape) if byte == self.quote || byte == escape =>
+            record.push(escape),
+          None if byte == self.quote => record.push(self.quote),
+          _ => ()
+        }
+        record.push(byte);
+      }
+      record.push(self.quote);
+    }
+  }
+}
+
+/// A CSV file, whose records might contain new lines inside quotes. It reads
+/// another Source, and MultiFileReader reads whole records from it instead of
+/// lines.
+///
+/// To find where a record starts from any position, like when dividing the
+/// file in byte ranges or searching for a key, the file is read once from the
+/// start the first time it's needed, keeping the position of a record every
+/// 64 KiB. Then finding a record only reads from the closest one.
+pub struct CsvFile
+{
+  source: Arc<dyn Source>,
+  dialect: CsvDialect,
+  index: Mutex<Option<Arc<Vec<u64>>>>
+}
+
+impl CsvFile
+{
+  pub fn new(source: Arc<dyn Source>, dialect: CsvDialect) -> CsvFile
+  {
+    CsvFile
+    {
+      source: source,
+      dialect: dialect,
+      index: Mutex::new(None)
+    }
+  }
+
+  /// Returns the quoting rules of the file
+  pub fn dialect(&self) -> CsvDialect
+  {
+    self.dialect
+  }
+
+  /// Returns the index of the file, reading it if it's the first time. The
+  /// index has the position of the first record starting at or after each
+  /// multiple of INDEX_CHUNK_SIZE, when there is one, in order.
+  fn index(&self) -> io::Result<Arc<Vec<u64>>>
+  {
+    let mut index = self.index.lock().unwrap();
+    if let Some(ref index) = *index
+    {
+      return Ok(index.clone())
+    }
+
+    let mut starts: Vec<u64> = vec![0];
+    let mut next_chunk: u64 = INDEX_CHUNK_SIZE;
+    let mut state = QuoteState::Unquoted;
+    let mut pos: u64 = 0;
+    let mut reader = BufReader::with_capacity(
+      INDEX_CHUNK_SIZE as usize, self.source.open_at(0)?
+    );
+    loop
+    {
+      let len = {
+        let buf = reader.fill_buf()?;
+        for (i, &byte) in buf.iter().enumerate()
+        {
+          if byte == b'\n' && state == QuoteState::Unquoted
+          {
+            let start = pos + i as u64 + 1;
+            if start >= next_chunk
+            {
+              starts.push(start);
+              next_chunk = (start / INDEX_CHUNK_SIZE + 1) * INDEX_CHUNK_SIZE;
+            }
+          }
+          state = self.dialect.next_state(state, byte);
+        }
+        buf.len()
+      };
+      if len == 0
+      {
+        break;
+      }
+      reader.consume(len);
+      pos += len as u64;
+    }
+    let starts = Arc::new(starts);
+    *index = Some(starts.clone());
+    /*return*/ Ok(starts)
+  }
+
+  /// Reads the records from `start`, which must be the position of a record,
+  /// until the one containing the byte at `pos`, returning its position and
+  /// the position of the next record, or the length of the file.
+  fn find_record(&self, start: u64, pos: u64) -> io::Result<(u64, u64)>
+  {
+    let mut record_pos = start;
+    let mut state = QuoteState::Unquoted;
+    let mut read_pos: u64 = start;
+    let mut reader = BufReader::new(self.source.open_at(start)?);
+    loop
+    {
+      let len = {
+        let buf = reader.fill_buf()?;
+        for (i, &byte) in buf.iter().enumerate()
+        {
+          if byte == b'\n' && state == QuoteState::Unquoted
+          {
+            let next_record_pos = read_pos + i as u64 + 1;
+            if next_record_pos > pos
+            {
+              return Ok((record_pos, next_record_pos))
+            }
+            record_pos = next_record_pos;
+          }
+          state = self.dialect.next_state(state, byte);
+        }
+        buf.len()
+      };
+      if len == 0
+      {
+        return Ok((record_pos, read_pos))
+      }
+      reader.consume(len);
+      read_pos += len as u64;
+    }
+  }
+
+  /// Returns the position of the record containing the byte at `pos`, and the
+  /// position of the next record, or the length of the file
+  pub fn record_at(&self, pos: u64) -> io::Result<(u64, u64)>
+  {
+    let index = self.index()?;
+    // the last indexed record starting at or before pos. The first record of
+    // the file is always indexed
+    let i = match index.binary_search(&pos)
+    {
+      Ok(i) => i,
+      Err(i) => i - 1
+    };
+    self.find_record(index[i], pos)
+  }
+}
+
+impl Source for CsvFile
+{
+  fn name(&self) -> String
+  {
+    self.source.name()
+  }
+
+  fn len(&self) -> io::Result<u64>
+  {
+    self.source.len()
+  }
+
+  fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize>
+  {
+    self.source.read_at(pos, buf)
+  }
+
+  fn open_at(&self, pos: u64) -> io::Result<Box<dyn SeekRead>>
+  {
+    self.source.open_at(pos)
+  }
+
+  fn csv(&self) -> Option<&CsvFile>
+  {
+    Some(self)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+
+  use CsvDialect;
+  use CsvFile;
+  use MemoryFile;
+  use QuoteState;
+  use source::Source;
+
+  #[test]
+  fn test_split_join()
+  {
+    struct Data {
+      record: &'static str,
+      fields: Vec<&'static str>,
+      joined: &'static str
+    }
+
+    let dialect = CsvDialect::rfc4180();
+    let l: Vec<Data> = vec![
+      Data {
+        record: "a,b,c",
+        fields: vec!["a", "b", "c"],
+        joined: "a,b,c"
+      },
+      Data {
+        record: "\"a,b\",c\r",
+        fields: vec!["a,b", "c"],
+        joined: "\"a,b\",c"
+      },
+      Data {
+        record: "\"say \"\"hi\"\"\",\"two\nlines\",",
+        fields: vec!["say \"hi\"", "two\nlines", ""],
+        joined: "\"say \"\"hi\"\"\",\"two\nlines\","
+      },
+      Data {
+        record: "\"\",\"\"\"\"",
+        fields: vec!["", "\""],
+        joined: ",\"\"\"\""
+      },
+    ];
+    for ref s in l.iter()
+    {
+      let fields = dialect.split(s.record.as_bytes(), b",");
+      let expected: Vec<&[u8]> = s.fields.iter().map(|f| f.as_bytes()).collect();
+      assert_eq!(fields, expected);
+      assert_eq!(dialect.join(&fields, b","), s.joined.as_bytes());
+    }
+
+    // not valid UTF-8
+    let fields = dialect.split(b"\"caf\xe9,\"\"\",\xff", b",");
+    assert_eq!(fields, vec![b"caf\xe9,\"".to_vec(), b"\xff".to_vec()]);
+
+    let dialect = CsvDialect::parse("'", Some("\\")).unwrap();
+    let fields = dialect.split(b"'it\\'s';a\\b;'c;d'", b";");
+    assert_eq!(fields, vec![b"it's".to_vec(), b"a\\b".to_vec(), b"c;d".to_vec()]);
+    assert_eq!(dialect.join(&fields, b";"), b"'it\\'s';'a\\\\b';'c;d'".to_vec());
+  }
+
+  #[test]
+  fn test_dialect()
+  {
+    assert_eq!(CsvDialect::parse("\"", None).unwrap(), CsvDialect::rfc4180());
+    assert_eq!(
+      CsvDialect::parse("\"", Some("\"")).unwrap(), CsvDialect::rfc4180()
+    );
+    assert!(CsvDialect::parse("", None).is_err());
+    assert!(CsvDialect::parse("ab", None).is_err());
+    assert!(CsvDialect::parse("\"", Some("\n")).is_err());
+    assert!(CsvDialect::new('ñ', None).is_err());
+
+    let dialect = CsvDialect::parse("\"", Some("\\")).unwrap();
+    assert_eq!(dialect.scan(QuoteState::Unquoted, b"a,\"b"), QuoteState::Quoted);
+    assert_eq!(dialect.scan(QuoteState::Quoted, b"\\"), QuoteState::Escaped);
+    assert_eq!(dialect.scan(QuoteState::Quoted, b"\\\""), QuoteState::Quoted);
+    assert_eq!(dialect.scan(QuoteState::Quoted, b"\"\""), QuoteState::Quoted);
+    assert_eq!(dialect.scan(QuoteState::Quoted, b"b\","), QuoteState::Unquoted);
+  }
+
+  #[test]
+  fn test_record_at()
+  {
+    let data = "a,\"1\n2\"\nb,\"3\n\"\"4\n5\"\"\"\nc,6\n";
+    let source: Arc<dyn Source> = Arc::new(
+      MemoryFile::new("0", data.as_bytes().to_vec())
+    );
+    let file = CsvFile::new(source, CsvDialect::rfc4180());
+    assert!(file.csv().is_some());
+
+    let second = data.find('b').unwrap() as u64;
+    let third = data.find('c').unwrap() as u64;
+    let len = data.len() as u64;
+    assert_eq!(file.record_at(0).unwrap(), (0, second));
+    assert_eq!(file.record_at(4).unwrap(), (0, second));
+    assert_eq!(file.record_at(second - 1).unwrap(), (0, second));
+    assert_eq!(file.record_at(second).unwrap(), (second, third));
+    assert_eq!(file.record_at(second + 5).unwrap(), (second, third));
+    assert_eq!(file.record_at(third + 2).unwrap(), (third, len));
+  }
+}
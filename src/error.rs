@@ -0,0 +1,278 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std;
+use std::fmt;
+use std::io;
+use std::any::Any;
+use std::thread::JoinHandle;
+use std::string::FromUtf8Error;
+
+/// Errors returned by paralio
+#[derive(Debug)]
+pub enum Error
+{
+  /// An I/O error
+  Io(io::Error),
+  /// An I/O error opening or reading the metadata of the file with the given
+  /// path
+  Open(String, io::Error),
+  /// Data that is not valid UTF-8
+  Encoding(String),
+  /// A line that can't be parsed, like the last line of a file that is longer
+  /// than the buffer size
+  MalformedLine(String),
+  /// A line that doesn't have a field with the given index
+  MissingField(String, usize),
+  /// A line whose key is lower than the key of the previous line, in an input
+  /// that should be sorted
+  UnsortedInput(String),
+  /// An invalid argument, like an unknown collation name
+  InvalidArgument(String),
+  /// A manifest file with the given path that can't be parsed or doesn't
+  /// match the files it describes
+  InvalidManifest(String, String),
+  /// A worker thread that panicked, with the panic message
+  Panic(String),
+  /// The worker threads of some partitions failed. The output files of those
+  /// partitions are missing or truncated
+  Workers(Vec<PartitionError>)
+}
+
+/// The failure of the worker thread of a partition
+#[derive(Debug)]
+pub struct PartitionError
+{
+  /// Number of the partition, which is also the name of its output file
+  pub partition: usize,
+  /// Byte ranges [start, end) of the partition in each of the inputs, in the
+  /// same order as the inputs. Empty if the partition has no byte ranges
+  pub ranges: Vec<(u64, u64)>,
+  /// The error returned by the worker, or Error::Panic if it panicked
+  pub error: Error
+}
+
+/// Result type returned by paralio
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+  {
+    match *self
+    {
+      Error::Io(ref error) => write!(f, "I/O error: {}", error),
+      Error::Open(ref path, ref error) =>
+        write!(f, "cannot open '{}': {}", path, error),
+      Error::Encoding(ref message) => write!(f, "invalid UTF-8: {}", message),
+      Error::MalformedLine(ref line) => write!(f, "malformed line: '{}'", line),
+      Error::MissingField(ref line, field) =>
+        write!(f, "missing field {} in line: '{}'", field, line),
+      Error::UnsortedInput(ref line) =>
+        write!(f, "input is not sorted, at line: '{}'", line),
+      Error::InvalidArgument(ref message) =>
+        write!(f, "invalid argument: {}", message),
+      Error::InvalidManifest(ref path, ref message) =>
+        write!(f, "invalid manifest '{}': {}", path, message),
+      Error::Panic(ref message) =>
+        write!(f, "worker thread panicked: {}", message),
+      Error::Workers(ref failures) =>
+      {
+        write!(f, "{} partition(s) failed", failures.len())?;
+        for failure in failures.iter()
+        {
+          write!(f, "\n  {}", failure)?;
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+impl fmt::Display for PartitionError
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+  {
+    write!(f, "partition {}", self.partition)?;
+    if !self.ranges.is_empty()
+    {
+      let ranges: Vec<String> = self.ranges.iter()
+        .map(|&(start, end)| format!("{}..{}", start, end))
+        .collect();
+      write!(f, " (bytes {})", ranges.join(", "))?;
+    }
+    write!(f, ": {}", self.error)
+  }
+}
+
+impl std::error::Error for Error
+{
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+  {
+    match *self
+    {
+      Error::Io(ref error) => Some(error),
+      Error::Open(_, ref error) => Some(error),
+      Error::Workers(ref failures) =>
+        failures.first().map(|failure| &failure.error as &(dyn std::error::Error + 'static)),
+      _ => None
+    }
+  }
+}
+
+impl From<io::Error> for Error
+{
+  /// Reading lines as strings fails with InvalidData on invalid UTF-8
+  fn from(error: io::Error) -> Error
+  {
+    match error.kind()
+    {
+      io::ErrorKind::InvalidData => Error::Encoding(error.to_string()),
+      _ => Error::Io(error)
+    }
+  }
+}
+
+impl From<FromUtf8Error> for Error
+{
+  fn from(error: FromUtf8Error) -> Error
+  {
+    Error::Encoding(error.to_string())
+  }
+}
+
+/// Returns the message of a panic payload
+fn panic_message(payload: Box<dyn Any + Send>) -> String
+{
+  match payload.downcast::<String>()
+  {
+    Ok(message) => *message,
+    Err(payload) => match payload.downcast::<&'static str>()
+    {
+      Ok(message) => String::from(*message),
+      Err(_) => String::from("unknown panic")
+    }
+  }
+}
+
+/// Returns an InvalidArgument error if there are less than 1 jobs, as there
+/// would be no threads to write the output.
+pub fn check_jobs(njobs: i32) -> Result<()>
+{
+  if njobs < 1
+  {
+    return Err(Error::InvalidArgument(format!(
+      "invalid number of jobs {}, it must be at least 1", njobs
+    )))
+  }
+  Ok(())
+}
+
+/// Waits for the worker thread of a partition to finish. If it returned an
+/// error or panicked, appends a PartitionError to `failures` and returns None.
+pub fn join_worker<T>(
+  child: JoinHandle<Result<T>>,
+  partition: usize,
+  ranges: Vec<(u64, u64)>,
+  failures: &mut Vec<PartitionError>
+) -> Option<T>
+{
+  let error = match child.join()
+  {
+    Ok(Ok(value)) => return Some(value),
+    Ok(Err(error)) => error,
+    Err(payload) => Error::Panic(panic_message(payload))
+  };
+  failures.push(PartitionError
+  {
+    partition: partition,
+    ranges: ranges,
+    error: error
+  });
+  /*return*/ None
+}
+
+/// Returns an Error::Workers with the given failures, if there are any
+pub fn check_workers(failures: Vec<PartitionError>) -> Result<()>
+{
+  match failures.is_empty()
+  {
+    true => Ok(()),
+    false => Err(Error::Workers(failures))
+  }
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::io;
+  use std::thread;
+
+  use Error;
+  use Result;
+  use error::join_worker;
+  use error::check_workers;
+
+  #[test]
+  fn test_from_io_error()
+  {
+    let error: Error = io::Error::new(io::ErrorKind::InvalidData, "bad").into();
+    match error
+    {
+      Error::Encoding(_) => (),
+      _ => panic!("expected an encoding error, got {:?}", error)
+    }
+
+    let error: Error = io::Error::new(io::ErrorKind::NotFound, "nope").into();
+    match error
+    {
+      Error::Io(_) => (),
+      _ => panic!("expected an I/O error, got {:?}", error)
+    }
+  }
+
+  #[test]
+  fn test_display()
+  {
+    assert_eq!(
+      Error::MissingField(String::from("a,b"), 3).to_string(),
+      "missing field 3 in line: 'a,b'"
+    );
+  }
+
+  #[test]
+  fn test_join_worker()
+  {
+    let mut failures = vec![];
+    let ok = thread::spawn(|| -> Result<u32> { Ok(3) });
+    assert_eq!(join_worker(ok, 0, vec![(0, 10)], &mut failures), Some(3));
+    assert!(failures.is_empty());
+
+    let failed = thread::spawn(|| -> Result<u32> {
+      Err(Error::MissingField(String::from("a"), 1))
+    });
+    assert_eq!(join_worker(failed, 1, vec![(10, 20), (5, 8)], &mut failures), None);
+
+    let panicked = thread::spawn(|| -> Result<u32> { panic!("boom {}", 1) });
+    assert_eq!(join_worker(panicked, 2, vec![], &mut failures), None);
+
+    assert_eq!(
+      check_workers(failures).unwrap_err().to_string(),
+      "2 partition(s) failed\n  partition 1 (bytes 10..20, 5..8): missing \
+      field 1 in line: 'a'\n  partition 2: worker thread panicked: boom 1"
+    );
+  }
+}
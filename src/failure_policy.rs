@@ -0,0 +1,147 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::fs;
+use std::path::Path;
+
+use Result;
+use publish::partial_path;
+
+/// Defines what to do with the output files of a failed run, which might be
+/// missing or truncated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailurePolicy
+{
+  /// Leaves the output files as they are.
+  Keep,
+  /// Removes the output files.
+  Delete,
+  /// Renames the partial file of each output file `N` to `N.failed`, so that
+  /// they are kept for inspection but not read as a numbered output.
+  Quarantine
+}
+
+impl FailurePolicy
+{
+  /// Returns the policy with the given name, as used in the command line, or
+  /// None if there is no policy with that name.
+  pub fn from_name(name: &str) -> Option<FailurePolicy>
+  {
+    match name
+    {
+      "keep" => Some(FailurePolicy::Keep),
+      "delete" => Some(FailurePolicy::Delete),
+      "quarantine" => Some(FailurePolicy::Quarantine),
+      _ => None
+    }
+  }
+
+  /// Applies the policy to the partial files of the output files `0` to
+  /// `partitions - 1` inside `output_path`, see partial_path, which a failed
+  /// run leaves unpublished. Other files are left untouched, even numbered
+  /// ones, as they were not written by the run.
+  pub fn apply(&self, output_path: &String, partitions: usize, verbose: bool) -> Result<()>
+  {
+    if *self == FailurePolicy::Keep
+    {
+      return Ok(())
+    }
+    for partition in 0..partitions
+    {
+      let path = partial_path(Path::new(output_path).join(partition.to_string()));
+      if !path.is_file()
+      {
+        continue;
+      }
+      if verbose {
+        println!("FailurePolicy::apply: {:?} {:?}", self, path);
+      }
+      match *self
+      {
+        FailurePolicy::Delete => fs::remove_file(&path)?,
+        _ => fs::rename(&path, Path::new(output_path).join(format!("{}.failed", partition)))?
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::fs;
+  use std::fs::File;
+  use tempdir::TempDir;
+
+  use FailurePolicy;
+
+  // returns the sorted names of the files in a directory
+  fn _file_names(dir_path: &String) -> Vec<String>
+  {
+    let mut names: Vec<String> = fs::read_dir(dir_path).unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect();
+    names.sort();
+    /*return*/ names
+  }
+
+  #[test]
+  fn test_from_name()
+  {
+    assert_eq!(FailurePolicy::from_name("keep"), Some(FailurePolicy::Keep));
+    assert_eq!(FailurePolicy::from_name("delete"), Some(FailurePolicy::Delete));
+    assert_eq!(FailurePolicy::from_name("quarantine"), Some(FailurePolicy::Quarantine));
+    assert_eq!(FailurePolicy::from_name("whatever"), None);
+  }
+
+  #[test]
+  fn test_apply()
+  {
+    struct Data {
+      policy: FailurePolicy,
+      output_names: Vec<&'static str>
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        policy: FailurePolicy::Keep,
+        output_names: vec![".1.partial", ".5.partial", "0", "2024", "7", "notes"]
+      },
+      Data {
+        policy: FailurePolicy::Delete,
+        output_names: vec![".5.partial", "0", "2024", "7", "notes"]
+      },
+      Data {
+        policy: FailurePolicy::Quarantine,
+        output_names: vec![".5.partial", "0", "1.failed", "2024", "7", "notes"]
+      },
+    ];
+    for ref s in l.iter()
+    {
+      let tmp_dir = TempDir::new("failure_policy").expect("create temp dir");
+      let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+      // only the partial files of the 2 partitions of the run are touched,
+      // not the published ones nor other numbered files in the directory
+      for name in ["0", ".1.partial", ".5.partial", "7", "2024", "notes"].iter()
+      {
+        File::create(tmp_dir.path().join(name)).expect("create temp file");
+      }
+
+      s.policy.apply(&tmp_dir_path, 2, false).unwrap();
+      assert_eq!(_file_names(&tmp_dir_path), s.output_names);
+    }
+  }
+}
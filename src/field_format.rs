@@ -0,0 +1,412 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use Error;
+use Result;
+use csv::CsvDialect;
+use csv::CsvFile;
+use source::Source;
+use source::ToSource;
+
+/// How the fields of each line are separated: by a separator, and quoted as in
+/// CSV files if given a CsvDialect. Then the lines are the records of the CSV
+/// files, which might contain new lines inside quotes.
+///
+/// The separator can have any length. An empty separator means that fields
+/// are separated by runs of blanks, spaces and tabs, like GNU join does
+/// without -t: blanks at the start and end of a line are ignored, and output
+/// fields are separated by a single space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldFormat
+{
+  separator: String,
+  csv: Option<CsvDialect>
+}
+
+impl FieldFormat
+{
+  /// Creates a FieldFormat, or returns an InvalidArgument error if the
+  /// separator is empty in CSV files
+  pub fn new(separator: &String, csv: Option<CsvDialect>) -> Result<FieldFormat>
+  {
+    if separator.is_empty() && csv.is_some()
+    {
+      return Err(Error::InvalidArgument(String::from("empty separator in CSV files")))
+    }
+    /*return*/ Ok(FieldFormat
+    {
+      separator: separator.clone(),
+      csv: csv
+    })
+  }
+
+  /// Returns the field separator, empty if fields are separated by blanks
+  pub fn separator(&self) -> &String
+  {
+    &self.separator
+  }
+
+  /// Returns whether fields are separated by runs of blanks
+  pub fn blanks(&self) -> bool
+  {
+    self.separator.is_empty()
+  }
+
+  /// Returns the quoting rules of the lines, if they are CSV records
+  pub fn csv(&self) -> Option<CsvDialect>
+  {
+    self.csv
+  }
+
+  /// Splits a line, without its ending new line, in the values of its fields.
+  /// The line can have any bytes, not only UTF-8 text.
+  pub fn split(&self, line: &[u8]) -> Vec<Vec<u8>>
+  {
+    let mut fields = Fields::new();
+    self.split_into(line, &mut fields);
+    fields.iter().map(|value| value.to_vec()).collect()
+  }
+
+  /// Splits a line like `split` does, but into `fields`, reusing its memory
+  pub fn split_into(&self, line: &[u8], fields: &mut Fields)
+  {
+    let separator = self.separator.as_bytes();
+    if let Some(ref dialect) = self.csv
+    {
+      return dialect.split_into(
+        line, separator, &mut fields.values, &mut fields.ranges
+      )
+    }
+    fields.values.clear();
+    fields.values.extend_from_slice(line);
+    fields.ranges.clear();
+    let mut start: usize = 0;
+    if self.blanks()
+    {
+      loop
+      {
+        while start < line.len() && is_blank(line[start])
+        {
+          start += 1;
+        }
+        if start == line.len()
+        {
+          break;
+        }
+        let end = match line[start..].iter().position(|&byte| is_blank(byte))
+        {
+          Some(i) => start + i,
+          None => line.len()
+        };
+        fields.ranges.push(start..end);
+        start = end;
+      }
+      // like with any other separator, a line without fields has an empty one
+      if fields.ranges.is_empty()
+      {
+        fields.ranges.push(0..0);
+      }
+      return
+    }
+    while let Some(i) = find_bytes(&line[start..], separator)
+    {
+      fields.ranges.push(start..start + i);
+      start += i + separator.len();
+    }
+    fields.ranges.push(start..line.len());
+  }
+
+  /// Joins the values of some fields in a line, without ending new line
+  pub fn join<T: AsRef<[u8]>>(&self, fields: &[T]) -> Vec<u8>
+  {
+    let mut line: Vec<u8> = vec![];
+    self.join_into(fields.iter(), &mut line);
+    /*return*/ line
+  }
+
+  /// Joins the values of some fields like `join` does, but appending the line
+  /// to `line`
+  pub fn join_into<T, I>(&self, fields: I, line: &mut Vec<u8>)
+    where T: AsRef<[u8]>, I: IntoIterator<Item = T>
+  {
+    let separator = match self.blanks()
+    {
+      true => &b" "[..],
+      false => self.separator.as_bytes()
+    };
+    if let Some(ref dialect) = self.csv
+    {
+      return dialect.join_into(fields, separator, line)
+    }
+    for (i, field) in fields.into_iter().enumerate()
+    {
+      if i > 0
+      {
+        line.extend_from_slice(separator);
+      }
+      line.extend_from_slice(field.as_ref());
+    }
+  }
+
+  /// Returns the sources of a list of files whose lines have this format, so
+  /// that CSV files are read as CsvFiles
+  pub fn sources<S: ToSource>(&self, file_list: &Vec<S>) -> Vec<Arc<dyn Source>>
+  {
+    file_list.iter().map(
+      |file|
+      {
+        let source = file.to_source();
+        match self.csv
+        {
+          Some(dialect) if source.csv().is_none() =>
+          {
+            let csv_file: Arc<dyn Source> = Arc::new(CsvFile::new(source, dialect));
+            /*return*/ csv_file
+          },
+          _ => source
+        }
+      }
+    ).collect()
+  }
+}
+
+/// The values of the fields of a line, kept one after the other in a buffer.
+///
+/// Splitting every line into the same Fields with `FieldFormat::split_into`
+/// reuses its memory, and so does `clone_from`, so that reading lines doesn't
+/// allocate memory for each of them.
+#[derive(Debug, PartialEq)]
+pub struct Fields
+{
+  values: Vec<u8>,
+  ranges: Vec<Range<usize>>
+}
+
+impl Fields
+{
+  /// Creates the Fields of an empty line, which has one empty field
+  pub fn new() -> Fields
+  {
+    Fields
+    {
+      values: vec![],
+      ranges: vec![0..0]
+    }
+  }
+
+  /// Returns the number of fields
+  pub fn len(&self) -> usize
+  {
+    self.ranges.len()
+  }
+
+  /// Returns the value of the field at index `i`, or None if there is no such
+  /// field
+  pub fn get(&self, i: usize) -> Option<&[u8]>
+  {
+    self.ranges.get(i).map(|range| &self.values[range.clone()])
+  }
+
+  /// Returns an iterator over the values of the fields
+  pub fn iter(&self) -> FieldsIter<'_>
+  {
+    FieldsIter
+    {
+      fields: self,
+      index: 0
+    }
+  }
+
+  /// Returns the values of the fields
+  pub fn to_vec(&self) -> Vec<&[u8]>
+  {
+    self.iter().collect()
+  }
+}
+
+impl Clone for Fields
+{
+  fn clone(&self) -> Fields
+  {
+    Fields
+    {
+      values: self.values.clone(),
+      ranges: self.ranges.clone()
+    }
+  }
+
+  fn clone_from(&mut self, source: &Fields)
+  {
+    self.values.clone_from(&source.values);
+    self.ranges.clone_from(&source.ranges);
+  }
+}
+
+/// Iterator over the values of some Fields
+pub struct FieldsIter<'a>
+{
+  fields: &'a Fields,
+  index: usize
+}
+
+impl<'a> Iterator for FieldsIter<'a>
+{
+  type Item = &'a [u8];
+
+  fn next(&mut self) -> Option<&'a [u8]>
+  {
+    let value = self.fields.get(self.index);
+    self.index += 1;
+    value
+  }
+}
+
+/// Returns whether a byte is a blank, a space or a tab
+fn is_blank(byte: u8) -> bool
+{
+  byte == b' ' || byte == b'\t'
+}
+
+/// Returns the position of the first occurrence of `needle`, which must not be
+/// empty, in `haystack`
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize>
+{
+  if needle.len() == 1
+  {
+    return haystack.iter().position(|&byte| byte == needle[0])
+  }
+  haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+  use CsvDialect;
+  use FieldFormat;
+  use Fields;
+
+  #[test]
+  fn test_split_into()
+  {
+    struct Data {
+      separator: &'static str,
+      csv: bool,
+      line: &'static str,
+      fields: Vec<&'static str>
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        separator: ";",
+        csv: false,
+        line: "a;bb;;c",
+        fields: vec!["a", "bb", "", "c"]
+      },
+      Data {
+        separator: "::",
+        csv: false,
+        line: "a::b:c",
+        fields: vec!["a", "b:c"]
+      },
+      Data {
+        separator: ";",
+        csv: false,
+        line: "",
+        fields: vec![""]
+      },
+      Data {
+        separator: "\t\t",
+        csv: false,
+        line: "a\tb\t\t\tc",
+        fields: vec!["a\tb", "\tc"]
+      },
+      Data {
+        separator: ",",
+        csv: true,
+        line: "\"a,b\",\"c\"\"\",d",
+        fields: vec!["a,b", "c\"", "d"]
+      },
+    ];
+    // the same Fields are reused for all the lines
+    let mut fields = Fields::new();
+    for ref s in l.iter()
+    {
+      let csv = if s.csv { Some(CsvDialect::rfc4180()) } else { None };
+      let format = FieldFormat::new(&String::from(s.separator), csv).unwrap();
+      format.split_into(s.line.as_bytes(), &mut fields);
+      let expected: Vec<&[u8]> = s.fields.iter().map(|f| f.as_bytes()).collect();
+      assert_eq!(fields.len(), expected.len());
+      assert_eq!(fields.to_vec(), expected);
+      assert_eq!(fields.get(expected.len()), None);
+
+      let mut line: Vec<u8> = b"x".to_vec();
+      format.join_into(fields.iter(), &mut line);
+      assert_eq!(line, [&b"x"[..], s.line.as_bytes()].concat());
+    }
+  }
+
+  #[test]
+  fn test_blanks()
+  {
+    struct Data {
+      line: &'static str,
+      fields: Vec<&'static str>,
+      joined: &'static str
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        line: "a b",
+        fields: vec!["a", "b"],
+        joined: "a b"
+      },
+      Data {
+        line: "  a \t\t b\tc  ",
+        fields: vec!["a", "b", "c"],
+        joined: "a b c"
+      },
+      Data {
+        line: "a,b;c",
+        fields: vec!["a,b;c"],
+        joined: "a,b;c"
+      },
+      Data {
+        line: " \t ",
+        fields: vec![""],
+        joined: ""
+      },
+      Data {
+        line: "",
+        fields: vec![""],
+        joined: ""
+      },
+    ];
+    let format = FieldFormat::new(&String::new(), None).unwrap();
+    assert!(format.blanks());
+    let mut fields = Fields::new();
+    for ref s in l.iter()
+    {
+      format.split_into(s.line.as_bytes(), &mut fields);
+      let expected: Vec<&[u8]> = s.fields.iter().map(|f| f.as_bytes()).collect();
+      assert_eq!(fields.to_vec(), expected);
+      assert_eq!(format.join(&fields.to_vec()), s.joined.as_bytes());
+    }
+
+    assert!(FieldFormat::new(&String::new(), Some(CsvDialect::rfc4180())).is_err());
+  }
+}
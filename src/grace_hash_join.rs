@@ -0,0 +1,560 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::thread;
+use std::time::Instant;
+use std::path::Path;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::prelude::*;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+
+use tempdir::TempDir;
+
+use ReadLiner;
+use LineReader;
+use MultiFileReader;
+use ByteRangeLineReader;
+use JoinType;
+use Error;
+use Result;
+use PartitionError;
+use error::join_worker;
+use error::check_workers;
+use error::check_jobs;
+use parallel_join::check_key_fields;
+use multi_file_reader::get_key;
+use output_file::create_file;
+use source::ToSource;
+use FieldFormat;
+use CsvDialect;
+use Collation;
+use publish::OutputStats;
+use publish::create_partial_file;
+use publish::sync_file;
+use publish::publish_outputs;
+use hash_join::HashTable;
+use hash_join::HashJoiner;
+use hash_join::check_hash_collation;
+
+/// Returns the partition of a key
+fn key_partition(key: &Vec<Vec<u8>>, num_partitions: u32) -> usize
+{
+  let mut hasher = DefaultHasher::new();
+  key.hash(&mut hasher);
+  /*return*/ (hasher.finish() % num_partitions as u64) as usize
+}
+
+/// Splits the lines of the input files in `num_partitions` partitions by the
+/// hash of their key, written in files inside `tmp_dir` whose name starts with
+/// `name`. The input is divided in one range per thread, and each thread
+/// writes its own files for each partition.
+///
+/// Returns the list of files of each partition, in input order. The files
+/// without lines are not created, so a list might be empty.
+fn partition_input<S: ToSource>(
+  file_str_list: &Vec<S>,
+  format: &FieldFormat,
+  key_fields: &Vec<usize>,
+  num_partitions: u32,
+  tmp_dir: &Path,
+  name: &str,
+  verbose: bool,
+  njobs: i32,
+  buffer_size: u32
+) -> Result<Vec<Vec<String>>>
+{
+  let ranges = ByteRangeLineReader::open(
+    &format.sources(file_str_list), njobs as u64, verbose, buffer_size
+  )?;
+
+  let mut children = vec![];
+  let mut thread_ranges: Vec<(u64, u64)> = vec![];
+  for (thread_num, range) in ranges.into_iter().enumerate()
+  {
+    thread_ranges.push((range.pos(), range.end()));
+    let format = format.clone();
+    let key_fields = key_fields.clone();
+    let tmp_dir = tmp_dir.to_path_buf();
+    let name = String::from(name);
+
+    children.push( thread::spawn(move || -> Result<Vec<Option<String>>>
+    {
+      let mut range = range;
+      let mut paths: Vec<Option<String>> = vec![None; num_partitions as usize];
+      let mut writers: Vec<Option<BufWriter<File>>> =
+        (0..num_partitions).map(|_| None).collect();
+      loop
+      {
+        let mut line: Vec<u8> = vec![];
+        if range.read_line(&mut line, verbose)? == 0
+        {
+          break;
+        }
+        if line.last() != Some(&b'\n')
+        {
+          line.push(b'\n');
+        }
+        let partition = key_partition(
+          &get_key(&line[..line.len() - 1], &format, &key_fields)?,
+          num_partitions
+        );
+        if writers[partition].is_none()
+        {
+          let path = String::from(
+            tmp_dir.join(format!("{}_{}_{}", name, partition, thread_num))
+              .to_str().unwrap()
+          );
+          if verbose {
+            println!("partition_input: creating {}", path);
+          }
+          writers[partition] = Some(BufWriter::new(create_file(&path)?));
+          paths[partition] = Some(path);
+        }
+        writers[partition].as_mut().unwrap().write_all(&line)?;
+      }
+      for writer in writers.iter_mut().filter_map(|writer| writer.as_mut())
+      {
+        writer.flush()?;
+      }
+      /*return*/ Ok(paths)
+    }));
+  }
+
+  let mut partitions: Vec<Vec<String>> = vec![vec![]; num_partitions as usize];
+  let mut failures: Vec<PartitionError> = vec![];
+  for (thread_num, child) in children.into_iter().enumerate() {
+    let ranges = vec![thread_ranges[thread_num]];
+    if let Some(paths) = join_worker(child, thread_num, ranges, &mut failures)
+    {
+      for (partition, path) in paths.into_iter().enumerate()
+      {
+        if let Some(path) = path
+        {
+          partitions[partition].push(path);
+        }
+      }
+    }
+  }
+  check_workers(failures)?;
+  /*return*/ Ok(partitions)
+}
+
+// Executes a parallel partitioned (grace) hash join, for when none of the
+// files fits in memory
+//
+// 1. Split the lines of both files in `num_partitions` partitions by the hash
+//    of their key, writing them in temporary files inside `tmp_path`. Each
+//    file is divided in N ranges (one per job/thread) to do so
+// 2. spawn N threads. Each thread joins some partitions, one after the other:
+// 2.1. load the smaller side of the partition in a hash table
+// 2.2. look up the key of each line of the other side in the hash table,
+//      writing the matches and unpaired lines
+//
+// The partitions must be small enough to fit in memory. The temporary files
+// are removed afterwards, even if a thread panics.
+//
+// Like execute_hash_join, the files don't need to be sorted, keys are compared
+// bytewise, so `collation` must be Bytewise, and the output is the same as
+// execute_parallel_join's but not sorted, in one numbered file per thread,
+// published once all the threads have succeeded and returned in order, with
+// their digests if `sha256` is true.
+pub fn execute_grace_hash_join<S: ToSource>(
+  file1_str_list: &Vec<S>,
+  file2_str_list: &Vec<S>,
+  separator: &String,
+  csv: Option<CsvDialect>,
+  field1: &Vec<usize>,
+  field2: &Vec<usize>,
+  collation: Collation,
+  output_fields_str_list: &Vec<String>,
+  join_type: JoinType,
+  empty: &String,
+  output_path: &String,
+  sha256: bool,
+  tmp_path: &String,
+  num_partitions: u32,
+  verbose: bool,
+  njobs: i32,
+  buffer_size: u32
+) -> Result<Vec<OutputStats>>
+{
+  let start = Instant::now();
+  check_jobs(njobs)?;
+  check_key_fields(field1, field2)?;
+  check_hash_collation(collation)?;
+  if num_partitions == 0
+  {
+    return Err(Error::InvalidArgument(String::from("0 grace partitions")))
+  }
+  let format = FieldFormat::new(separator, csv)?;
+  // removed when dropped, also when unwinding from a panic
+  let tmp_dir = TempDir::new_in(tmp_path, "pjoin_grace")
+    .map_err(|error| Error::Open(tmp_path.clone(), error))?;
+
+  let partitions1 = partition_input(
+    file1_str_list,
+    &format,
+    field1,
+    num_partitions,
+    tmp_dir.path(),
+    "1",
+    verbose,
+    njobs,
+    buffer_size
+  )?;
+  if verbose {
+    println!("{} secs \tbase_thread: partitioned file1", start.elapsed().as_secs());
+  }
+  let partitions2 = partition_input(
+    file2_str_list,
+    &format,
+    field2,
+    num_partitions,
+    tmp_dir.path(),
+    "2",
+    verbose,
+    njobs,
+    buffer_size
+  )?;
+  if verbose {
+    println!("{} secs \tbase_thread: partitioned file2", start.elapsed().as_secs());
+  }
+
+  let mut children = vec![];
+  for thread_num in 0..(njobs as usize).min(num_partitions as usize)
+  {
+    let format = format.clone();
+    let field1 = field1.clone();
+    let field2 = field2.clone();
+    let output_fields_str_list = output_fields_str_list.clone();
+    let empty = empty.clone();
+    let output_path = output_path.clone();
+    let partition_pairs: Vec<(Vec<String>, Vec<String>)> = partitions1.iter()
+      .zip(partitions2.iter())
+      .skip(thread_num)
+      .step_by(njobs as usize)
+      .map(|(partition1, partition2)| (partition1.clone(), partition2.clone()))
+      .collect();
+
+    children.push( thread::spawn(move || -> Result<OutputStats>
+    {
+      let path = Path::new(&output_path).join(thread_num.to_string());
+      if verbose {
+        println!("thread {}: output path: {:?}", thread_num, path);
+      }
+      let mut out = create_partial_file(path)?;
+      out.set_csv(format.csv());
+      out.set_sha256(sha256);
+
+      for (partition1, partition2) in partition_pairs.into_iter()
+      {
+        let build_is_file1 = MultiFileReader::len(&partition1)? <
+          MultiFileReader::len(&partition2)?;
+        let (build_str_list, build_fields, probe_str_list, probe_fields) =
+          match build_is_file1
+          {
+            true => (partition1, &field1, partition2, &field2),
+            false => (partition2, &field2, partition1, &field1)
+          };
+        let joiner = HashJoiner::new(
+          format.clone(),
+          &output_fields_str_list,
+          join_type,
+          empty.clone(),
+          build_is_file1,
+          verbose
+        )?;
+        let table = HashTable::build(
+          &build_str_list, &format, build_fields, verbose, buffer_size
+        )?;
+
+        let matched = match probe_str_list.is_empty()
+        {
+          true => vec![false; table.len()],
+          false =>
+          {
+            let probe_len = MultiFileReader::len(&probe_str_list)?;
+            let mut probe = LineReader::new(
+              ByteRangeLineReader::open_range(
+                format.sources(&probe_str_list), 0, probe_len, verbose, buffer_size
+              )?,
+              format.clone(),
+              probe_fields.clone(),
+              verbose
+            );
+            joiner.probe(&table, &mut probe, &mut out)?
+          }
+        };
+        joiner.write_build_lines(&table, &matched, &mut out)?;
+      }
+      sync_file(&mut out)
+    }));
+  }
+
+  let mut outputs: Vec<OutputStats> = vec![];
+  let mut failures: Vec<PartitionError> = vec![];
+  let mut i: usize = 0;
+  for child in children {
+    // Wait for the thread to finish. Returns a result.
+    if verbose {
+      println!("{} secs \tbase_thread: finishing thread {}", start.elapsed().as_secs(), i);
+    }
+
+    // each thread joins multiple hash partitions, which have no byte ranges
+    if let Some(output) = join_worker(child, i, vec![], &mut failures)
+    {
+      outputs.push(output);
+    }
+
+    if verbose {
+      println!("{} secs \tbase_thread: ... finished thread {}", start.elapsed().as_secs(), i);
+    }
+    i = i + 1;
+  }
+  check_workers(failures)?;
+  publish_outputs(outputs)
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::fs;
+  use std::fs::File;
+  use std::io::prelude::*;
+  use tempdir::TempDir;
+
+  use test_helpers::_write_files;
+
+  use execute_grace_hash_join;
+  use JoinType;
+  use Error;
+  use Collation;
+
+  // returns all the lines of all the files in a directory, sorted
+  fn _read_sorted_lines(dir_path: &String) -> Vec<String>
+  {
+    let mut lines: Vec<String> = vec![];
+    for entry in fs::read_dir(dir_path).unwrap()
+    {
+      let mut contents = String::new();
+      File::open(entry.unwrap().path()).unwrap()
+        .read_to_string(&mut contents).unwrap();
+      lines.extend(contents.lines().map(String::from));
+    }
+    lines.sort();
+    /*return*/ lines
+  }
+
+  #[test]
+  fn test_grace_hash_join()
+  {
+    struct Data {
+      file1_str: &'static str,
+      file2_str: &'static str,
+      output_fields_str_list: &'static str,
+      join_type: JoinType,
+      output_lines: Vec<&'static str>,
+      num_partitions: u32,
+      njobs: i32
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        file1_str: "4;d,1;a,3;c",
+        file2_str: "3;C,5;E,1;A,1;AA,2;B",
+        output_fields_str_list: "1.0,1.1,2.1",
+        join_type: JoinType::Inner,
+        output_lines: vec!["1;a;A", "1;a;AA", "3;c;C"],
+        num_partitions: 1,
+        njobs: 1
+      },
+      Data {
+        file1_str: "4;d,1;a|3;c,7;g,8;h",
+        file2_str: "3;C,5;E|1;A,1;AA,2;B,8;H",
+        output_fields_str_list: "1.0,1.1,2.1",
+        join_type: JoinType::Inner,
+        output_lines: vec!["1;a;A", "1;a;AA", "3;c;C", "8;h;H"],
+        num_partitions: 4,
+        njobs: 2
+      },
+      Data {
+        file1_str: "4;d,1;a|3;c,7;g,8;h",
+        file2_str: "3;C,5;E|1;A,1;AA,2;B,8;H",
+        output_fields_str_list: "1.0,1.1,2.0,2.1",
+        join_type: JoinType::FullOuter,
+        output_lines: vec![
+          "1;a;1;A", "1;a;1;AA", "3;c;3;C", "4;d;;", "7;g;;", "8;h;8;H",
+          ";;2;B", ";;5;E"
+        ],
+        num_partitions: 5,
+        njobs: 3
+      },
+      Data {
+        file1_str: "4;d,1;a|3;c,7;g,8;h",
+        file2_str: "3;C,5;E|1;A,1;AA,2;B,8;H",
+        output_fields_str_list: "1.0",
+        join_type: JoinType::Semi,
+        output_lines: vec!["1;a", "3;c", "8;h"],
+        num_partitions: 3,
+        njobs: 2
+      },
+      Data {
+        file1_str: "4;d,1;a|3;c,7;g,8;h",
+        file2_str: "3;C,5;E|1;A,1;AA,2;B,8;H",
+        output_fields_str_list: "1.0",
+        join_type: JoinType::FullAnti,
+        output_lines: vec!["2;B", "4;d", "5;E", "7;g"],
+        num_partitions: 3,
+        njobs: 4
+      },
+    ];
+    for ref s in l.iter()
+    {
+      let tmp_dir1 = TempDir::new("grace_hash_join1").expect("create temp dir");
+      let file1_str_list = _write_files(s.file1_str, &tmp_dir1);
+      let tmp_dir2 = TempDir::new("grace_hash_join2").expect("create temp dir");
+      let file2_str_list = _write_files(s.file2_str, &tmp_dir2);
+      let tmp_dir_out = TempDir::new("grace_hash_join_out").expect("create temp dir");
+      let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+      let tmp_dir_spill = TempDir::new("grace_hash_join_tmp").expect("create temp dir");
+      let tmp_dir_spill_path = String::from(tmp_dir_spill.path().to_str().unwrap());
+
+      execute_grace_hash_join(
+        &file1_str_list,
+        &file2_str_list,
+        &String::from(";"),
+        None,
+        &vec![0],
+        &vec![0],
+        Collation::Bytewise,
+        &s.output_fields_str_list.split(",").map(String::from).collect(),
+        s.join_type,
+        &String::new(),
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        &tmp_dir_spill_path,
+        s.num_partitions,
+        false,
+        s.njobs,
+        /*buffer_size*/ 16384
+      ).unwrap();
+      assert_eq!(_read_sorted_lines(&tmp_dir_out_path), s.output_lines);
+      // the temporary partition files are removed
+      assert_eq!(fs::read_dir(&tmp_dir_spill_path).unwrap().count(), 0);
+    }
+  }
+
+  #[test]
+  fn test_grace_hash_join_no_partitions()
+  {
+    let tmp_dir = TempDir::new("grace_hash_join").expect("create temp dir");
+    let file_str_list = _write_files("1;a", &tmp_dir);
+    let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+
+    let result = execute_grace_hash_join(
+      &file_str_list,
+      &file_str_list,
+      &String::from(";"),
+      None,
+      &vec![0],
+      &vec![0],
+      Collation::Bytewise,
+      &vec![String::from("1.0")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_path,
+      /*sha256*/ false,
+      &tmp_dir_path,
+      /*num_partitions*/ 0,
+      false,
+      1,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::InvalidArgument(_)) => (),
+      result => panic!("expected an invalid argument error, got {:?}", result)
+    }
+  }
+
+  #[test]
+  fn test_grace_hash_join_no_jobs()
+  {
+    let tmp_dir = TempDir::new("grace_hash_join").expect("create temp dir");
+    let file_str_list = _write_files("1;a", &tmp_dir);
+    let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+
+    let result = execute_grace_hash_join(
+      &file_str_list,
+      &file_str_list,
+      &String::from(";"),
+      None,
+      &vec![0],
+      &vec![0],
+      Collation::Bytewise,
+      &vec![String::from("1.0")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_path,
+      /*sha256*/ false,
+      &tmp_dir_path,
+      /*num_partitions*/ 2,
+      false,
+      /*njobs*/ 0,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::InvalidArgument(_)) => (),
+      result => panic!("expected an invalid argument error, got {:?}", result)
+    }
+  }
+
+  #[test]
+  fn test_grace_hash_join_collation()
+  {
+    let tmp_dir = TempDir::new("grace_hash_join").expect("create temp dir");
+    let file_str_list = _write_files("1;a", &tmp_dir);
+    let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+
+    let result = execute_grace_hash_join(
+      &file_str_list,
+      &file_str_list,
+      &String::from(";"),
+      None,
+      &vec![0],
+      &vec![0],
+      Collation::IgnoreCase,
+      &vec![String::from("1.0")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_path,
+      /*sha256*/ false,
+      &tmp_dir_path,
+      /*num_partitions*/ 2,
+      false,
+      /*njobs*/ 1,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::InvalidArgument(_)) => (),
+      result => panic!("expected an invalid argument error, got {:?}", result)
+    }
+  }
+}
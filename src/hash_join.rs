@@ -0,0 +1,770 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::thread;
+use std::time::Instant;
+use std::path::Path;
+use std::sync::Arc;
+use std::collections::HashMap;
+
+use LineReader;
+use ReadLiner;
+use MultiFileReader;
+use ByteRangeLineReader;
+use OutputFile;
+use JoinType;
+use Error;
+use Result;
+use PartitionError;
+use error::join_worker;
+use error::check_workers;
+use error::check_jobs;
+use parallel_join::check_key_fields;
+use source::ToSource;
+use FieldFormat;
+use Fields;
+use CsvDialect;
+use Collation;
+use publish::OutputWriter;
+use publish::OutputStats;
+use publish::create_partial_file;
+use publish::sync_file;
+use publish::publish_outputs;
+
+/// In memory hash table with the lines of the build side of a hash join,
+/// grouped by key.
+pub struct HashTable
+{
+  /// Maps each key, encoded with `encode_key`, to its index in `runs`
+  index: HashMap<Vec<u8>, usize>,
+  /// The fields of all the lines with each key, in input order
+  runs: Vec<Vec<Fields>>
+}
+
+impl HashTable
+{
+  /// Creates an empty HashTable
+  pub fn new() -> HashTable
+  {
+    HashTable
+    {
+      index: HashMap::new(),
+      runs: Vec::new()
+    }
+  }
+
+  /// Reads all the lines of the given files into a new HashTable
+  pub fn build<S: ToSource>(
+    file_str_list: &Vec<S>,
+    format: &FieldFormat,
+    key_fields: &Vec<usize>,
+    verbose: bool,
+    buffer_size: u32
+  ) -> Result<HashTable>
+  {
+    let mut table = HashTable::new();
+    if file_str_list.is_empty()
+    {
+      return Ok(table)
+    }
+    let mut reader = LineReader::new(
+      ByteRangeLineReader::open_range(
+        format.sources(file_str_list),
+        0,
+        MultiFileReader::len(file_str_list)?,
+        verbose,
+        buffer_size
+      )?,
+      format.clone(),
+      key_fields.clone(),
+      verbose
+    );
+    let mut key: Vec<u8> = vec![];
+    reader.read_next()?;
+    while reader.has_current()
+    {
+      encode_key(&reader, &mut key)?;
+      let next_index = table.runs.len();
+      let run_index = match table.index.get(&key[..])
+      {
+        Some(&run_index) => run_index,
+        None =>
+        {
+          table.index.insert(key.clone(), next_index);
+          next_index
+        }
+      };
+      if run_index == next_index
+      {
+        table.runs.push(Vec::new());
+      }
+      table.runs[run_index].push(reader.fields().clone());
+      reader.read_next()?;
+    }
+    /*return*/ Ok(table)
+  }
+
+  /// Returns the number of different keys
+  pub fn len(&self) -> usize
+  {
+    self.runs.len()
+  }
+}
+
+/// Returns an InvalidArgument error unless the collation is Bytewise: keys are
+/// hashed as they are, so the keys that another collation takes as equal, like
+/// "1" and "01" as integers, would not be joined.
+pub fn check_hash_collation(collation: Collation) -> Result<()>
+{
+  if collation != Collation::Bytewise
+  {
+    return Err(Error::InvalidArgument(format!(
+      "a hash join compares keys bytewise, it can't use the {:?} collation",
+      collation
+    )))
+  }
+  Ok(())
+}
+
+/// Encodes the key of the current line of `reader` in `key`, as each key value
+/// preceded by its length, so that different keys are encoded differently
+fn encode_key<T: ReadLiner>(reader: &LineReader<T>, key: &mut Vec<u8>)
+  -> Result<()>
+{
+  key.clear();
+  for &i in reader.key_fields().iter()
+  {
+    let value = reader.field(i)?;
+    key.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    key.extend_from_slice(value);
+  }
+  Ok(())
+}
+
+/// Writes the output of a hash join between a HashTable, the build side, and
+/// the lines of the other file, the probe side.
+#[derive(Clone)]
+pub struct HashJoiner
+{
+  format: FieldFormat,
+  empty: String,
+  output_fields: Vec<(bool, usize)>,
+  join_type: JoinType,
+  build_is_file1: bool,
+  verbose: bool
+}
+
+impl HashJoiner
+{
+  /// Creates a HashJoiner. The `output_fields_str_list`, `join_type` and
+  /// `empty` have the same meaning as in OutputFile::new. `build_is_file1`
+  /// tells whether the build side is file1 or file2.
+  pub fn new(
+    format: FieldFormat,
+    output_fields_str_list: &Vec<String>,
+    join_type: JoinType,
+    empty: String,
+    build_is_file1: bool,
+    verbose: bool
+  ) -> Result<HashJoiner>
+  {
+    Ok(HashJoiner
+    {
+      format: format,
+      empty: empty,
+      output_fields: output_fields_str_list.iter()
+        .map(|s| OutputFile::pair_split(s))
+        .collect::<Result<Vec<(bool, usize)>>>()?,
+      join_type: join_type,
+      build_is_file1: build_is_file1,
+      verbose: verbose
+    })
+  }
+
+  /// Returns whether the unpaired lines of the probe side are written
+  fn probe_unpaired(&self) -> bool
+  {
+    match self.build_is_file1
+    {
+      true => self.join_type.unpaired2(),
+      false => self.join_type.unpaired1()
+    }
+  }
+
+  /// Returns whether the build lines with no match are written, by
+  /// `write_build_lines`
+  fn build_unpaired(&self) -> bool
+  {
+    match self.build_is_file1
+    {
+      true => self.join_type.unpaired1(),
+      false => self.join_type.unpaired2()
+    }
+  }
+
+  /// Returns whether the build lines with a match are written, by
+  /// `write_build_lines`
+  fn build_matched(&self) -> bool
+  {
+    self.join_type == JoinType::Semi && self.build_is_file1
+  }
+
+  /// Returns whether any build line is written by `write_build_lines`, which
+  /// then needs to know which keys had a match
+  pub fn writes_build_lines(&self) -> bool
+  {
+    self.build_unpaired() || self.build_matched()
+  }
+
+  /// Looks up the key of each probe line in the table, writing the matches
+  /// and the unpaired probe lines.
+  ///
+  /// If `writes_build_lines()`, returns for each key of the table whether it
+  /// had any match. Otherwise returns an empty list.
+  pub fn probe<T: ReadLiner>(
+    &self,
+    table: &HashTable,
+    probe: &mut LineReader<T>,
+    out: &mut OutputWriter
+  ) -> Result<Vec<bool>>
+  {
+    let probe_matched = self.join_type == JoinType::Semi && !self.build_is_file1;
+    let mut matched: Vec<bool> = match self.writes_build_lines()
+    {
+      true => vec![false; table.len()],
+      false => vec![]
+    };
+
+    let mut key: Vec<u8> = vec![];
+    let mut line: Vec<u8> = vec![];
+    probe.read_next()?;
+    while probe.has_current()
+    {
+      encode_key(probe, &mut key)?;
+      match table.index.get(&key[..])
+      {
+        Some(&run_index) =>
+        {
+          if !matched.is_empty() {
+            matched[run_index] = true;
+          }
+          if self.join_type.matches()
+          {
+            for build_line in table.runs[run_index].iter()
+            {
+              self.write_match(
+                out, &mut line, Some(build_line), Some(probe.fields())
+              )?;
+            }
+          }
+          else if probe_matched
+          {
+            self.write_line(out, &mut line, probe.fields())?;
+          }
+        },
+        None =>
+        {
+          if self.probe_unpaired() && self.join_type.whole_lines()
+          {
+            self.write_line(out, &mut line, probe.fields())?;
+          }
+          else if self.probe_unpaired()
+          {
+            self.write_match(out, &mut line, None, Some(probe.fields()))?;
+          }
+        }
+      }
+      probe.read_next()?;
+    }
+    if self.verbose {
+      println!("HashJoiner::probe finished");
+    }
+    /*return*/ Ok(matched)
+  }
+
+  /// Writes the build lines that need to be written after probing: the ones
+  /// without a match for outer and anti-joins, or the ones with a match for
+  /// semi-joins when file1 is the build side.
+  ///
+  /// `matched` tells for each key of the table whether it had any match.
+  pub fn write_build_lines(
+    &self,
+    table: &HashTable,
+    matched: &Vec<bool>,
+    out: &mut OutputWriter
+  ) -> Result<()>
+  {
+    if !self.writes_build_lines()
+    {
+      return Ok(())
+    }
+    let build_matched = self.build_matched();
+    let mut line: Vec<u8> = vec![];
+    for (run, &run_matched) in table.runs.iter().zip(matched.iter())
+    {
+      if run_matched != build_matched
+      {
+        continue;
+      }
+      for build_line in run.iter()
+      {
+        if build_matched || self.join_type.whole_lines()
+        {
+          self.write_line(out, &mut line, build_line)?;
+        }
+        else
+        {
+          self.write_match(out, &mut line, Some(build_line), None)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes a line with the output fields of a match between a build line and
+  /// a probe line, or of an unpaired line if one of them is None. The line is
+  /// joined in `line`, a buffer reused between calls.
+  fn write_match(
+    &self,
+    out: &mut OutputWriter,
+    line: &mut Vec<u8>,
+    build_fields: Option<&Fields>,
+    probe_fields: Option<&Fields>
+  ) -> Result<()>
+  {
+    let (file1_fields, file2_fields) = match self.build_is_file1
+    {
+      true => (build_fields, probe_fields),
+      false => (probe_fields, build_fields)
+    };
+    OutputFile::write_fields(
+      out,
+      line,
+      &self.output_fields,
+      &self.format,
+      &self.empty,
+      file1_fields,
+      file2_fields
+    )
+  }
+
+  /// Writes a whole line, joining it in `line`
+  fn write_line(&self, out: &mut OutputWriter, line: &mut Vec<u8>, fields: &Fields)
+    -> Result<()>
+  {
+    OutputFile::write_line(out, line, &self.format, fields)
+  }
+}
+
+// Executes a parallel build/probe hash join
+//
+// 1. Load the smaller file (the build side) in a hash table, by key
+// 2. divide the larger file (the probe side) in N ranges (one per job/thread)
+// 3. for each range:
+// 3.1. spawn a thread
+// 3.2. look up the key of each line of the range in the hash table, writing
+//      the matches and the unpaired probe lines
+// 4. if needed, write the build lines with and without matches in an extra
+//    output file
+//
+// The files don't need to be sorted, and the output is not sorted either. Keys
+// are equal only if they are bytewise equal, so `collation` must be Bytewise,
+// see check_hash_collation. The
+// fields are separated as in execute_parallel_join, also in CSV files.
+//
+// It writes the same output as execute_parallel_join for any `join_type`,
+// `output_fields_str_list` and `empty`, only in a different order: one
+// numbered file per thread, plus a last one with the unpaired build lines of
+// outer and anti-joins, or the file1 lines of semi-joins when file1 is the
+// build side. Like in execute_parallel_join, the output files are only
+// published once all the threads have succeeded, and what was written to each
+// of them is returned in order, with their digests if `sha256` is true.
+pub fn execute_hash_join<S: ToSource>(
+  file1_str_list: &Vec<S>,
+  file2_str_list: &Vec<S>,
+  separator: &String,
+  csv: Option<CsvDialect>,
+  field1: &Vec<usize>,
+  field2: &Vec<usize>,
+  collation: Collation,
+  output_fields_str_list: &Vec<String>,
+  join_type: JoinType,
+  empty: &String,
+  output_path: &String,
+  sha256: bool,
+  verbose: bool,
+  njobs: i32,
+  buffer_size: u32
+) -> Result<Vec<OutputStats>>
+{
+  let start = Instant::now();
+  check_jobs(njobs)?;
+  check_key_fields(field1, field2)?;
+  check_hash_collation(collation)?;
+  let format = FieldFormat::new(separator, csv)?;
+  let file1_str_list = &format.sources(file1_str_list);
+  let file2_str_list = &format.sources(file2_str_list);
+
+  let build_is_file1 = MultiFileReader::len(file1_str_list)? <
+    MultiFileReader::len(file2_str_list)?;
+  let (build_str_list, build_fields, probe_str_list, probe_fields) =
+    match build_is_file1
+    {
+      true => (file1_str_list, field1, file2_str_list, field2),
+      false => (file2_str_list, field2, file1_str_list, field1)
+    };
+  let joiner = HashJoiner::new(
+    format.clone(),
+    output_fields_str_list,
+    join_type,
+    empty.clone(),
+    build_is_file1,
+    verbose
+  )?;
+
+  let table = Arc::new(HashTable::build(
+    build_str_list, &format, build_fields, verbose, buffer_size
+  )?);
+  if verbose {
+    println!(
+      "{} secs \texecute_hash_join: build_is_file1={} keys={}",
+      start.elapsed().as_secs(),
+      build_is_file1,
+      table.len()
+    );
+  }
+
+  let probe_ranges = ByteRangeLineReader::open(
+    probe_str_list, njobs as u64, verbose, buffer_size
+  )?;
+  let num_ranges = probe_ranges.len();
+
+  let mut children = vec![];
+  let mut thread_ranges: Vec<(u64, u64)> = vec![];
+  for (thread_num, probe_range) in probe_ranges.into_iter().enumerate()
+  {
+    thread_ranges.push((probe_range.pos(), probe_range.end()));
+    let table = table.clone();
+    let joiner = joiner.clone();
+    let format = format.clone();
+    let probe_fields = probe_fields.clone();
+    let output_path = output_path.clone();
+
+    children.push( thread::spawn(move || -> Result<(Vec<bool>, OutputStats)>
+    {
+      let path = Path::new(&output_path).join(thread_num.to_string());
+      if verbose {
+        println!("thread {}: output path: {:?}", thread_num, path);
+      }
+      let mut out = create_partial_file(path)?;
+      out.set_csv(format.csv());
+      out.set_sha256(sha256);
+      let mut probe = LineReader::new(
+        probe_range, format, probe_fields, verbose
+      );
+      let matched = joiner.probe(&table, &mut probe, &mut out)?;
+      /*return*/ Ok((matched, sync_file(&mut out)?))
+    }));
+  }
+
+  let mut matched: Vec<bool> = match joiner.writes_build_lines()
+  {
+    true => vec![false; table.len()],
+    false => vec![]
+  };
+  let mut outputs: Vec<OutputStats> = vec![];
+  let mut failures: Vec<PartitionError> = vec![];
+  let mut i: usize = 0;
+  for child in children {
+    // Wait for the thread to finish. Returns a result.
+    if verbose {
+      println!("{} secs \tbase_thread: finishing thread {}", start.elapsed().as_secs(), i);
+    }
+
+    // the range of the probe side, which is file1 if the build side is file2
+    let ranges = vec![thread_ranges[i]];
+    if let Some((thread_matched, output)) = join_worker(child, i, ranges, &mut failures)
+    {
+      outputs.push(output);
+      for (run_matched, thread_run_matched) in matched.iter_mut().zip(thread_matched)
+      {
+        *run_matched = *run_matched || thread_run_matched;
+      }
+    }
+
+    if verbose {
+      println!("{} secs \tbase_thread: ... finished thread {}", start.elapsed().as_secs(), i);
+    }
+    i = i + 1;
+  }
+
+  check_workers(failures)?;
+  if joiner.writes_build_lines()
+  {
+    let path = Path::new(&output_path).join(num_ranges.to_string());
+    if verbose {
+      println!("base_thread: build side output path: {:?}", path);
+    }
+    let mut out = create_partial_file(&path)?;
+    out.set_csv(format.csv());
+    out.set_sha256(sha256);
+    joiner.write_build_lines(&table, &matched, &mut out)?;
+    outputs.push(sync_file(&mut out)?);
+  }
+  publish_outputs(outputs)
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::sync::Arc;
+  use tempdir::TempDir;
+
+  use test_helpers::_write_files;
+  use test_helpers::_assert_files_eq;
+
+  use execute_hash_join;
+  use JoinType;
+  use MemoryFile;
+  use source::Source;
+  use Error;
+  use Collation;
+
+  #[test]
+  fn test_hash_join()
+  {
+    struct Data {
+      file1_str: &'static str,
+      file2_str: &'static str,
+      field1: Vec<usize>,
+      field2: Vec<usize>,
+      output_fields_str_list: &'static str,
+      join_type: JoinType,
+      output_str: &'static str,
+      njobs: i32
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        file1_str: "4;d,1;a,3;c",
+        file2_str: "3;C,5;E,1;A,1;AA,2;B",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0,1.1,2.1",
+        join_type: JoinType::Inner,
+        output_str: "3;c;C\n1;a;A\n1;a;AA\n",
+        njobs: 1
+      },
+      Data {
+        // file2 is the build side
+        file1_str: "3;C,5;E,1;A,1;AA,2;B",
+        file2_str: "4;d,1;a,3;c",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0,1.1,2.1",
+        join_type: JoinType::Inner,
+        output_str: "3;C;c\n1;A;a\n1;AA;a\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "1;a,2;b|3;c,4;d",
+        file2_str: "0;z,5;y,4;x,2;w,2;v,1;u",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0,2.1",
+        join_type: JoinType::Inner,
+        output_str: "4;x\n2;w\n|2;v\n1;u\n",
+        njobs: 2
+      },
+      Data {
+        file1_str: "4;d,1;a,3;c",
+        file2_str: "3;C,5;E,1;A,2;B",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0,1.1,2.0,2.1",
+        join_type: JoinType::FullOuter,
+        output_str: "3;c;3;C\n;;5;E\n1;a;1;A\n;;2;B\n|4;d;;\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "4;d,1;a,3;c",
+        file2_str: "3;C,5;E,1;A,2;B",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0",
+        join_type: JoinType::LeftAnti,
+        output_str: "|4;d\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "4;d,1;a,3;c",
+        file2_str: "3;C,5;E,1;A,2;B,1;AA",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0",
+        join_type: JoinType::Semi,
+        output_str: "|1;a\n3;c\n",
+        njobs: 1
+      },
+      Data {
+        // file1 is the probe side
+        file1_str: "3;C,5;E,1;A,2;B,1;AA",
+        file2_str: "4;d,1;a,3;c,1;b",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0",
+        join_type: JoinType::Semi,
+        output_str: "3;C\n1;A\n1;AA\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "a;1;x,b;1;y",
+        file2_str: "1;a;X,1;b;Y,2;a;Z",
+        field1: vec![0, 1],
+        field2: vec![1, 0],
+        output_fields_str_list: "1.2,2.2",
+        join_type: JoinType::Inner,
+        output_str: "x;X\ny;Y\n",
+        njobs: 1
+      },
+    ];
+    for ref s in l.iter()
+    {
+      let tmp_dir1 = TempDir::new("hash_join1").expect("create temp dir");
+      let file1_str_list = _write_files(s.file1_str, &tmp_dir1);
+      let tmp_dir2 = TempDir::new("hash_join2").expect("create temp dir");
+      let file2_str_list = _write_files(s.file2_str, &tmp_dir2);
+      let tmp_dir_out = TempDir::new("hash_join_out").expect("create temp dir");
+      let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+
+      execute_hash_join(
+        &file1_str_list,
+        &file2_str_list,
+        &String::from(";"),
+        None,
+        &s.field1,
+        &s.field2,
+        Collation::Bytewise,
+        &s.output_fields_str_list.split(",").map(String::from).collect(),
+        s.join_type,
+        &String::new(),
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        false,
+        s.njobs,
+        /*buffer_size*/ 16384
+      ).unwrap();
+      _assert_files_eq(&tmp_dir_out_path, s.output_str);
+    }
+  }
+
+  #[test]
+  fn test_hash_join_last_line_without_new_line()
+  {
+    // the last line of a file might not end with a new line, also in the
+    // middle of an input made of several files
+    let memory_file = |name: &str, data: &[u8]| -> Arc<dyn Source>
+    {
+      Arc::new(MemoryFile::new(name, data.to_vec()))
+    };
+    let files_1 = vec![memory_file("0", b"2;b\n1;a"), memory_file("1", b"3;c")];
+    let files_2 = vec![memory_file("0", b"3;C\n1;A\n2;B")];
+    let tmp_dir_out = TempDir::new("hash_join_out").expect("create temp dir");
+    let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+
+    execute_hash_join(
+      &files_1,
+      &files_2,
+      &String::from(";"),
+      None,
+      &vec![0],
+      &vec![0],
+      Collation::Bytewise,
+      &vec![String::from("1.0"), String::from("1.1"), String::from("2.1")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      false,
+      /*njobs*/ 1,
+      /*buffer_size*/ 16384
+    ).unwrap();
+    _assert_files_eq(&tmp_dir_out_path, "3;c;C\n1;a;A\n2;b;B\n");
+  }
+
+  #[test]
+  fn test_hash_join_no_jobs()
+  {
+    let tmp_dir = TempDir::new("hash_join").expect("create temp dir");
+    let file_str_list = _write_files("1;a", &tmp_dir);
+    let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+
+    let result = execute_hash_join(
+      &file_str_list,
+      &file_str_list,
+      &String::from(";"),
+      None,
+      &vec![0],
+      &vec![0],
+      Collation::Bytewise,
+      &vec![String::from("1.0")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_path,
+      /*sha256*/ false,
+      false,
+      /*njobs*/ 0,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::InvalidArgument(_)) => (),
+      result => panic!("expected an invalid argument error, got {:?}", result)
+    }
+  }
+
+  #[test]
+  fn test_hash_join_collation()
+  {
+    let tmp_dir = TempDir::new("hash_join").expect("create temp dir");
+    let file_str_list = _write_files("1;a", &tmp_dir);
+    let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+
+    // "1" and "01" would be equal integers, but their hashes are not
+    let result = execute_hash_join(
+      &file_str_list,
+      &file_str_list,
+      &String::from(";"),
+      None,
+      &vec![0],
+      &vec![0],
+      Collation::Integer,
+      &vec![String::from("1.0")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_path,
+      /*sha256*/ false,
+      false,
+      /*njobs*/ 1,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::InvalidArgument(_)) => (),
+      result => panic!("expected an invalid argument error, got {:?}", result)
+    }
+  }
+}
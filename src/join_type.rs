@@ -0,0 +1,164 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+/// The kind of join to execute, which defines which lines are written to the
+/// output besides the matches between file1 and file2.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinType
+{
+  /// Only the matches are written.
+  Inner,
+  /// The matches and the unpaired lines of file1 are written, like
+  /// `join -a 1`.
+  LeftOuter,
+  /// The matches and the unpaired lines of file2 are written, like
+  /// `join -a 2`.
+  RightOuter,
+  /// The matches and the unpaired lines of both files are written, like
+  /// `join -a 1 -a 2`.
+  FullOuter,
+  /// Only the unpaired lines of file1 are written, like `join -v 1`.
+  LeftAnti,
+  /// Only the unpaired lines of file2 are written, like `join -v 2`.
+  RightAnti,
+  /// Only the unpaired lines of both files are written, like
+  /// `join -v 1 -v 2`.
+  FullAnti,
+  /// Only the lines of file1 that have a match in file2 are written, each of
+  /// them once.
+  Semi
+}
+
+impl JoinType
+{
+  /// Returns the JoinType that writes the unpaired lines of the files whose
+  /// number is set to true.
+  pub fn from_unpaired(unpaired1: bool, unpaired2: bool) -> JoinType
+  {
+    match (unpaired1, unpaired2)
+    {
+      (false, false) => JoinType::Inner,
+      (true, false) => JoinType::LeftOuter,
+      (false, true) => JoinType::RightOuter,
+      (true, true) => JoinType::FullOuter
+    }
+  }
+
+  /// Returns the JoinType that writes only the unpaired lines of the files
+  /// whose number is set to true, or an inner join if none is.
+  pub fn from_only_unpaired(unpaired1: bool, unpaired2: bool) -> JoinType
+  {
+    match (unpaired1, unpaired2)
+    {
+      (false, false) => JoinType::Inner,
+      (true, false) => JoinType::LeftAnti,
+      (false, true) => JoinType::RightAnti,
+      (true, true) => JoinType::FullAnti
+    }
+  }
+
+  /// Returns whether the matches are written, using the output fields
+  pub fn matches(&self) -> bool
+  {
+    match *self
+    {
+      JoinType::Inner |
+      JoinType::LeftOuter |
+      JoinType::RightOuter |
+      JoinType::FullOuter => true,
+      _ => false
+    }
+  }
+
+  /// Returns whether the unpaired lines of file1 are written
+  pub fn unpaired1(&self) -> bool
+  {
+    match *self
+    {
+      JoinType::LeftOuter |
+      JoinType::FullOuter |
+      JoinType::LeftAnti |
+      JoinType::FullAnti => true,
+      _ => false
+    }
+  }
+
+  /// Returns whether the unpaired lines of file2 are written
+  pub fn unpaired2(&self) -> bool
+  {
+    match *self
+    {
+      JoinType::RightOuter |
+      JoinType::FullOuter |
+      JoinType::RightAnti |
+      JoinType::FullAnti => true,
+      _ => false
+    }
+  }
+
+  /// Returns whether the lines are written whole instead of using the output
+  /// fields, which is the case when lines of only one file are written.
+  pub fn whole_lines(&self) -> bool
+  {
+    !self.matches()
+  }
+}
+
+#[cfg(test)]
+mod test
+{
+  use JoinType;
+
+  #[test]
+  fn test_from_unpaired()
+  {
+    assert_eq!(JoinType::from_unpaired(false, false), JoinType::Inner);
+    assert_eq!(JoinType::from_unpaired(true, false), JoinType::LeftOuter);
+    assert_eq!(JoinType::from_unpaired(false, true), JoinType::RightOuter);
+    assert_eq!(JoinType::from_unpaired(true, true), JoinType::FullOuter);
+
+    assert_eq!(JoinType::Inner.unpaired1(), false);
+    assert_eq!(JoinType::Inner.unpaired2(), false);
+    assert_eq!(JoinType::LeftOuter.unpaired1(), true);
+    assert_eq!(JoinType::LeftOuter.unpaired2(), false);
+    assert_eq!(JoinType::RightOuter.unpaired1(), false);
+    assert_eq!(JoinType::RightOuter.unpaired2(), true);
+    assert_eq!(JoinType::FullOuter.unpaired1(), true);
+    assert_eq!(JoinType::FullOuter.unpaired2(), true);
+  }
+
+  #[test]
+  fn test_from_only_unpaired()
+  {
+    assert_eq!(JoinType::from_only_unpaired(false, false), JoinType::Inner);
+    assert_eq!(JoinType::from_only_unpaired(true, false), JoinType::LeftAnti);
+    assert_eq!(JoinType::from_only_unpaired(false, true), JoinType::RightAnti);
+    assert_eq!(JoinType::from_only_unpaired(true, true), JoinType::FullAnti);
+
+    assert_eq!(JoinType::LeftAnti.matches(), false);
+    assert_eq!(JoinType::LeftAnti.unpaired1(), true);
+    assert_eq!(JoinType::LeftAnti.unpaired2(), false);
+    assert_eq!(JoinType::RightAnti.unpaired1(), false);
+    assert_eq!(JoinType::RightAnti.unpaired2(), true);
+    assert_eq!(JoinType::FullAnti.unpaired1(), true);
+    assert_eq!(JoinType::FullAnti.unpaired2(), true);
+    assert_eq!(JoinType::Semi.matches(), false);
+    assert_eq!(JoinType::Semi.unpaired1(), false);
+    assert_eq!(JoinType::Semi.unpaired2(), false);
+    assert_eq!(JoinType::Semi.whole_lines(), true);
+    assert_eq!(JoinType::FullOuter.whole_lines(), false);
+  }
+}
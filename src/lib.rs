@@ -21,17 +21,85 @@
 **/
 
 extern crate tempdir;
+extern crate memmap;
+extern crate sha2;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate memchr;
 
+mod error;
+mod source;
+mod csv;
+mod field_format;
 mod multi_file_reader;
 mod line_reader;
 mod output_file;
 mod byte_range_line_reader;
 mod parallel_join;
+mod parallel_merge;
+mod parallel_sort;
+mod hash_join;
+mod grace_hash_join;
+mod join_type;
+mod collation;
+mod failure_policy;
+mod publish;
+mod manifest;
+mod spool;
 mod test_helpers;
 
+pub use error::Error;
+pub use error::Result;
+pub use error::PartitionError;
+pub use source::Source;
+pub use source::SeekRead;
+pub use source::ToSource;
+pub use source::LocalFile;
+pub use source::MemoryFile;
+pub use source::to_sources;
+pub use source::map_files;
+pub use source::file_sources;
+pub use csv::CsvDialect;
+pub use csv::CsvFile;
+pub use csv::QuoteState;
+pub use field_format::FieldFormat;
+pub use field_format::Fields;
 pub use multi_file_reader::MultiFileReader;
 pub use multi_file_reader::ReadLiner;
+pub use multi_file_reader::FindKeyPosition;
 pub use line_reader::LineReader;
 pub use byte_range_line_reader::ByteRangeLineReader;
 pub use output_file::OutputFile;
-pub use parallel_join::execute_parallel_join;
\ No newline at end of file
+pub use parallel_join::execute_parallel_join;
+pub use parallel_merge::execute_parallel_merge;
+pub use parallel_sort::execute_parallel_sort;
+pub use hash_join::execute_hash_join;
+pub use grace_hash_join::execute_grace_hash_join;
+pub use join_type::JoinType;
+pub use collation::Collation;
+pub use failure_policy::FailurePolicy;
+pub use publish::OutputStats;
+pub use publish::OutputWriter;
+pub use publish::partial_path;
+pub use publish::parts_path;
+pub use publish::create_partial_file;
+pub use publish::sync_file;
+pub use publish::publish_files;
+pub use publish::publish_outputs;
+pub use publish::concatenate_outputs;
+pub use publish::stdout_writer;
+pub use publish::stream_writer;
+pub use publish::STDOUT_PATH;
+pub use publish::print_output_stats;
+pub use publish::write_success_marker;
+pub use publish::hex_digest;
+pub use publish::combined_digest;
+pub use sha2::Sha256;
+pub use manifest::Manifest;
+pub use manifest::PartitionSummary;
+pub use manifest::numbered_file_list;
+pub use spool::SpooledInput;
+pub use spool::is_seekable;
+pub use spool::STDIN_PATH;
@@ -14,16 +14,29 @@
  * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
 **/
 
+use std::cmp::Ordering;
+
 use ReadLiner;
+use FieldFormat;
+use Fields;
+use Collation;
+use Error;
+use Result;
+use multi_file_reader::remove_new_line;
 
 /// Helps to iterative line parsing by reading and storing lines for a given
 /// ReadLiner object.
+///
+/// The same line buffer and Fields are reused for every line, and field values
+/// are handed out as slices borrowed from them, so that reading lines doesn't
+/// allocate memory for each of them.
 pub struct LineReader<T>
 {
   reader: T,
-  separator: String,
-  key_field: usize,
-  last_parsed_line: Vec<String>,
+  format: FieldFormat,
+  key_fields: Vec<usize>,
+  line: Vec<u8>,
+  last_parsed_line: Fields,
   finished: bool,
   verbose: bool,
 }
@@ -31,14 +44,16 @@ pub struct LineReader<T>
 impl<T: ReadLiner> LineReader<T>
 {
   /// Creates a LineReader
-  pub fn new(reader: T, separator: String, key_field: u32, verbose: bool) -> LineReader<T>
+  pub fn new(reader: T, format: FieldFormat, key_fields: Vec<usize>, verbose: bool)
+    -> LineReader<T>
   {
     LineReader
     {
       reader: reader,
-      separator: separator,
-      key_field: key_field as usize,
-      last_parsed_line: vec![String::new()],
+      format: format,
+      key_fields: key_fields,
+      line: vec![],
+      last_parsed_line: Fields::new(),
       finished: false,
       verbose: verbose
     }
@@ -51,33 +66,65 @@ impl<T: ReadLiner> LineReader<T>
   }
 
   /// reads the next line, storing it internally
-  pub fn read_next(&mut self)
+  pub fn read_next(&mut self) -> Result<()>
   {
-    let mut line1 = String::new();
-    self.finished = self.reader.read_line(&mut line1, self.verbose).unwrap() == 0;
-    line1.pop();
-    self.last_parsed_line = line1.split(self.separator.as_str()).map(String::from).collect();
+    self.line.clear();
+    self.finished = self.reader.read_line(&mut self.line, self.verbose)? == 0;
+    remove_new_line(&mut self.line);
+    self.format.split_into(&self.line, &mut self.last_parsed_line);
     if self.verbose {
-      println!("LineReader::read_next, line_read={}", line1);
+      println!(
+        "LineReader::read_next, line_read={}",
+        String::from_utf8_lossy(&self.line)
+      );
     }
+    Ok(())
+  }
+
+  /// returns the indexes of the key field values
+  pub fn key_fields(&self) -> &Vec<usize>
+  {
+    &self.key_fields
   }
 
-  /// returns the index of the key field value
-  pub fn key_field(&self) -> usize
+  /// returns the key field values
+  pub fn key(&self) -> Result<Vec<&[u8]>>
   {
-    self.key_field
+    self.key_fields.iter().map(|&i| self.field(i)).collect()
   }
 
-  /// returns the key field value
-  pub fn key(&self) -> String
+  /// returns the value by index of the last line, or a MissingField error if
+  /// the line doesn't have it
+  pub fn field(&self, i: usize) -> Result<&[u8]>
   {
-    self.last_parsed_line[self.key_field].clone()
+    field(&self.last_parsed_line, i, &self.format)
   }
 
-  /// returns the value by index of the last line
-  pub fn field(&self, i: usize) -> String
+  /// returns all the field values of the last line
+  pub fn fields(&self) -> &Fields
   {
-    self.last_parsed_line[i].clone()
+    &self.last_parsed_line
+  }
+
+  /// Compares the key of the last line with the key of `other`, some fields
+  /// split with the same format whose key is in `other_key_fields`, without
+  /// allocating memory
+  pub fn compare_key(
+    &self,
+    other: &Fields,
+    other_key_fields: &Vec<usize>,
+    collation: Collation
+  ) -> Result<Ordering>
+  {
+    for (&i, &j) in self.key_fields.iter().zip(other_key_fields.iter())
+    {
+      match collation.compare(self.field(i)?, field(other, j, &self.format)?)
+      {
+        Ordering::Equal => continue,
+        ordering => return Ok(ordering)
+      }
+    }
+    Ok(self.key_fields.len().cmp(&other_key_fields.len()))
   }
 
   /// Acces to the internal reader as a reference
@@ -87,23 +134,41 @@ impl<T: ReadLiner> LineReader<T>
   }
 }
 
+/// Returns the value by index of some fields, or a MissingField error if
+/// there is no such field
+fn field<'a>(fields: &'a Fields, i: usize, format: &FieldFormat)
+  -> Result<&'a [u8]>
+{
+  match fields.get(i)
+  {
+    Some(value) => Ok(value),
+    None => Err(Error::MissingField(
+      String::from_utf8_lossy(&format.join(&fields.to_vec())).into_owned(),
+      i
+    ))
+  }
+}
+
 #[cfg(test)]
 mod test {
   use std;
   use std::slice::Iter;
   use ReadLiner;
   use LineReader;
+  use Error;
+  use test_helpers::_format;
 
   impl<'a> ReadLiner for Iter<'a, String>
   {
-    fn read_line<'b>(&mut self, buf: &'b mut String, _: bool)
+    fn read_line<'b>(&mut self, buf: &'b mut Vec<u8>, _: bool)
       -> std::io::Result<usize>
     {
       match self.next()
       {
         Some(val) =>
         {
-          buf.clone_from(val);
+          buf.clear();
+          buf.extend_from_slice(val.as_bytes());
           Ok(buf.len())
         },
         _ => Ok(0)
@@ -118,28 +183,60 @@ mod test {
       String::from("a,b\n"),
       String::from("c,d\n"),
     ];
-    let mut reader = LineReader::new(values.iter(), String::from(","), 0, false);
+    let mut reader = LineReader::new(values.iter(), _format(","), vec![0], false);
     assert_eq!(reader.has_current(), true);
-    reader.read_next();
+    reader.read_next().unwrap();
     assert_eq!(reader.has_current(), true);
 
-    assert_eq!(reader.key(), String::from("a"));
-    assert_eq!(reader.field(0), String::from("a"));
+    assert_eq!(reader.key().unwrap(), vec![b"a".to_vec()]);
+    assert_eq!(reader.field(0).unwrap(), b"a".to_vec());
     assert_eq!(reader.has_current(), true);
-    assert_eq!(reader.field(0), String::from("a"));
-    assert_eq!(reader.field(1), String::from("b"));
+    assert_eq!(reader.field(0).unwrap(), b"a".to_vec());
+    assert_eq!(reader.field(1).unwrap(), b"b".to_vec());
 
-    reader.read_next();
+    reader.read_next().unwrap();
     assert_eq!(reader.has_current(), true);
-    assert_eq!(reader.key_field(), 0);
-    assert_eq!(reader.field(0), String::from("c"));
-    assert_eq!(reader.key(), String::from("c"));
-    assert_eq!(reader.field(1), String::from("d"));
+    assert_eq!(reader.key_fields(), &vec![0]);
+    assert_eq!(reader.field(0).unwrap(), b"c".to_vec());
+    assert_eq!(reader.key().unwrap(), vec![b"c".to_vec()]);
+    assert_eq!(reader.field(1).unwrap(), b"d".to_vec());
+    assert_eq!(reader.fields().to_vec(), vec![&b"c"[..], &b"d"[..]]);
 
-    reader.read_next();
+    reader.read_next().unwrap();
     reader.reader();
     assert_eq!(reader.has_current(), false);
-    assert_eq!(reader.field(0), String::from(""));
-    assert_eq!(reader.key(), String::from(""));
+    assert_eq!(reader.field(0).unwrap(), b"".to_vec());
+    assert_eq!(reader.key().unwrap(), vec![b"".to_vec()]);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_multiple_key_fields()
+  {
+    let values = vec![
+      String::from("a,b,c\n"),
+    ];
+    let mut reader = LineReader::new(
+      values.iter(), _format(","), vec![2, 0], false
+    );
+    reader.read_next().unwrap();
+    assert_eq!(reader.key_fields(), &vec![2, 0]);
+    assert_eq!(reader.key().unwrap(), vec![b"c".to_vec(), b"a".to_vec()]);
+  }
+
+  #[test]
+  fn test_missing_field()
+  {
+    let values = vec![
+      String::from("a,b\n"),
+    ];
+    let mut reader = LineReader::new(
+      values.iter(), _format(","), vec![2], false
+    );
+    reader.read_next().unwrap();
+    match reader.key()
+    {
+      Err(Error::MissingField(line, 2)) => assert_eq!(line, "a,b"),
+      result => panic!("expected a missing field error, got {:?}", result)
+    }
+  }
+}
@@ -0,0 +1,378 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::fs;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::SeekFrom;
+use std::io::prelude::*;
+use std::path::Path;
+use std::path::PathBuf;
+use serde_json;
+
+use Error;
+use Result;
+use multi_file_reader::get_key;
+use multi_file_reader::key_to_strings;
+use FieldFormat;
+use CsvDialect;
+use csv::QuoteState;
+use publish::OutputStats;
+use publish::hex_digest;
+use publish::combined_digest;
+use publish::create_partial_file;
+use publish::sync_file;
+use publish::publish_files;
+
+/// Name of the manifest file written in the output directory
+pub const MANIFEST_NAME: &'static str = "_manifest.json";
+
+/// Description of an output file, one of the partitions of a Manifest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartitionSummary
+{
+  /// Path of the file, relative to the directory of the manifest
+  pub path: String,
+  /// Number of lines
+  pub lines: u64,
+  /// Size in bytes
+  pub bytes: u64,
+  /// Key of the first line, or None if the file is empty or there are no key
+  /// fields
+  pub first_key: Option<Vec<String>>,
+  /// Key of the last line, or None if the file is empty or there are no key
+  /// fields
+  pub last_key: Option<Vec<String>>,
+  /// SHA-256 digest of the contents, in hexadecimal
+  pub sha256: String
+}
+
+/// Description of the numbered output files of a run, so that they can be
+/// read back in the right order and checked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest
+{
+  /// The command line of the run
+  pub command: Vec<String>,
+  /// The output files, in order
+  pub partitions: Vec<PartitionSummary>,
+  /// Combined SHA-256 digest of the output files in order, in hexadecimal,
+  /// see combined_digest
+  pub combined_sha256: String
+}
+
+/// Returns the numbered files inside a directory, like the output of pjoin,
+/// psort or ppdump, ordered by their number so that "10" goes after "2".
+pub fn numbered_file_list(dir_path: &str) -> Result<Vec<String>>
+{
+  let entries = fs::read_dir(dir_path)
+    .map_err(|error| Error::Open(String::from(dir_path), error))?;
+  let mut numbered_files: Vec<(u64, String)> = vec![];
+  for entry in entries
+  {
+    let path = entry?.path();
+    let num: Option<u64> = path.file_name()
+      .and_then(|name| name.to_str())
+      .and_then(|name| name.parse().ok());
+    if let Some(num) = num
+    {
+      numbered_files.push((num, path.to_string_lossy().into_owned()));
+    }
+  }
+  numbered_files.sort();
+  /*return*/ Ok(numbered_files.into_iter().map(|x| x.1).collect())
+}
+
+/// Describes an output file from what was written to it. Only its first and
+/// last lines (or CSV records) are read, to get their keys. The path written
+/// in the summary is only the file name.
+fn summarize_output(output: &OutputStats, format: &FieldFormat, key_fields: &Vec<usize>)
+  -> Result<PartitionSummary>
+{
+  let path = output.path.to_string_lossy().into_owned();
+  let sha256 = output.sha256.as_ref().ok_or(Error::InvalidArgument(
+    format!("the digest of '{}' was not computed", path)
+  ))?;
+  let mut summary = PartitionSummary
+  {
+    path: output.path.file_name()
+      .map_or(path.clone(), |name| name.to_string_lossy().into_owned()),
+    lines: output.lines,
+    bytes: output.bytes,
+    first_key: None,
+    last_key: None,
+    sha256: hex_digest(sha256)
+  };
+  if key_fields.is_empty() || output.lines == 0
+  {
+    return Ok(summary)
+  }
+
+  let file = File::open(&path).map_err(|error| Error::Open(path.clone(), error))?;
+  let mut reader = BufReader::new(file);
+  // a CSV record goes on until a new line outside quotes
+  let mut first_line: Vec<u8> = vec![];
+  let mut state = QuoteState::Unquoted;
+  loop
+  {
+    let start = first_line.len();
+    if reader.read_until(b'\n', &mut first_line)? == 0
+    {
+      break;
+    }
+    match format.csv()
+    {
+      Some(dialect) => state = dialect.scan(state, &first_line[start..]),
+      None => break
+    }
+    if state == QuoteState::Unquoted
+    {
+      break;
+    }
+  }
+  let mut last_line: Vec<u8> = vec![];
+  reader.seek(SeekFrom::Start(output.last_line_pos))?;
+  reader.read_to_end(&mut last_line)?;
+  // JSON strings can only have text, so keys that are not valid UTF-8 are
+  // written with replacement chars
+  let line_key = |line: &[u8]| -> Result<Vec<String>>
+  {
+    let line = match line.last()
+    {
+      Some(&b'\n') => &line[..line.len() - 1],
+      _ => line
+    };
+    Ok(key_to_strings(&get_key(line, format, key_fields)?))
+  };
+  summary.first_key = Some(line_key(&first_line)?);
+  summary.last_key = Some(line_key(&last_line)?);
+  /*return*/ Ok(summary)
+}
+
+impl Manifest
+{
+  /// Describes the published output files of a run from what was written to
+  /// them, as returned by the execute_* functions. The keys of the first and
+  /// last lines are made of the given `key_fields` of the output lines, split
+  /// with `separator` and the `csv` quoting rules, and are not written if
+  /// there are none.
+  pub fn from_output(
+    outputs: &Vec<OutputStats>,
+    separator: &String,
+    csv: Option<CsvDialect>,
+    key_fields: &Vec<usize>,
+    command: Vec<String>
+  ) -> Result<Manifest>
+  {
+    let format = FieldFormat::new(separator, csv)?;
+    let partitions = outputs.iter()
+      .map(|output| summarize_output(output, &format, key_fields))
+      .collect::<Result<Vec<PartitionSummary>>>()?;
+    Ok(Manifest
+    {
+      command: command,
+      partitions: partitions,
+      // the digest of every output file was computed to summarize it
+      combined_sha256: combined_digest(outputs).expect("digests of the outputs")
+    })
+  }
+
+  /// Returns the manifest as JSON
+  pub fn to_json(&self) -> String
+  {
+    // it only has strings, numbers and lists, which can always be written
+    /*return*/ serde_json::to_string_pretty(self).expect("write manifest as JSON") + "\n"
+  }
+
+  /// Parses a manifest from its JSON text, returning an error message if it
+  /// is not valid
+  pub fn from_json(json: &str) -> ::std::result::Result<Manifest, String>
+  {
+    serde_json::from_str(json).map_err(|error| error.to_string())
+  }
+
+  /// Writes the manifest as MANIFEST_NAME inside `output_path`, publishing it
+  /// atomically like the output files
+  pub fn write(&self, output_path: &String) -> Result<()>
+  {
+    let path = Path::new(output_path).join(MANIFEST_NAME);
+    let mut out = create_partial_file(&path)?;
+    out.write_all(self.to_json().as_bytes())?;
+    sync_file(&mut out)?;
+    publish_files(&vec![path])
+  }
+
+  /// Reads a manifest file
+  pub fn read(manifest_path: &str) -> Result<Manifest>
+  {
+    let mut json = String::new();
+    File::open(manifest_path)
+      .map_err(|error| Error::Open(String::from(manifest_path), error))?
+      .read_to_string(&mut json)?;
+    Manifest::from_json(&json)
+      .map_err(|message| Error::InvalidManifest(String::from(manifest_path), message))
+  }
+
+  /// Reads a manifest file and returns the paths of its files in order, to
+  /// use them as an input file list. Checks that every file exists and has the
+  /// size written in the manifest, so that a truncated file is not read.
+  pub fn read_file_list(manifest_path: &str) -> Result<Vec<String>>
+  {
+    let manifest = Manifest::read(manifest_path)?;
+    let dir_path: PathBuf = match Path::new(manifest_path).parent()
+    {
+      Some(dir_path) => dir_path.to_path_buf(),
+      None => PathBuf::new()
+    };
+    let mut file_list: Vec<String> = vec![];
+    for partition in manifest.partitions.iter()
+    {
+      let path = dir_path.join(&partition.path).to_string_lossy().into_owned();
+      let bytes = fs::metadata(&path)
+        .map_err(|error| Error::Open(path.clone(), error))?
+        .len();
+      if bytes != partition.bytes
+      {
+        return Err(Error::InvalidManifest(
+          String::from(manifest_path),
+          format!("'{}' has {} bytes instead of {}", path, bytes, partition.bytes)
+        ))
+      }
+      file_list.push(path);
+    }
+    /*return*/ Ok(file_list)
+  }
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::fs::File;
+  use std::fs::OpenOptions;
+  use std::io::prelude::*;
+  use tempdir::TempDir;
+
+  use test_helpers::_write_files;
+
+  use Error;
+  use CsvDialect;
+  use publish::create_partial_file;
+  use publish::sync_file;
+  use publish::publish_outputs;
+  use manifest::Manifest;
+  use manifest::PartitionSummary;
+  use manifest::MANIFEST_NAME;
+  use manifest::numbered_file_list;
+
+  #[test]
+  fn test_numbered_file_list()
+  {
+    let tmp_dir = TempDir::new("manifest").expect("create temp dir");
+    let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+    // 11 files, so that "10" goes after "2"
+    let files = _write_files("a|b|c|d|e|f|g|h|i|j|k", &tmp_dir);
+    File::create(tmp_dir.path().join("_SUCCESS")).expect("create temp file");
+
+    assert_eq!(numbered_file_list(&tmp_dir_path).unwrap(), files);
+  }
+
+  #[test]
+  fn test_manifest()
+  {
+    let tmp_dir = TempDir::new("manifest").expect("create temp dir");
+    let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+    let mut outputs = vec![];
+    for (i, content) in ["1;b\n2;a\n", "3;c\n", ""].iter().enumerate()
+    {
+      let mut out = create_partial_file(tmp_dir.path().join(i.to_string())).unwrap();
+      out.set_sha256(true);
+      out.write_all(content.as_bytes()).unwrap();
+      outputs.push(sync_file(&mut out).unwrap());
+    }
+    let outputs = publish_outputs(outputs).unwrap();
+    let files: Vec<String> = outputs.iter()
+      .map(|output| output.path.to_string_lossy().into_owned())
+      .collect();
+
+    let manifest = Manifest::from_output(
+      &outputs,
+      &String::from(";"),
+      None,
+      &vec![0],
+      vec![String::from("pjoin"), String::from("-s"), String::from(";")]
+    ).unwrap();
+    assert_eq!(manifest.partitions[0], PartitionSummary
+    {
+      path: String::from("0"),
+      lines: 2,
+      bytes: 8,
+      first_key: Some(vec![String::from("1")]),
+      last_key: Some(vec![String::from("2")]),
+      sha256: String::from(
+        "e9961a9f2b2b1683470dce2a7d381557198752ae10e1af3b6d7aeae88107c5d8"
+      )
+    });
+    assert_eq!(manifest.partitions.len(), 3);
+    assert_eq!(manifest.partitions[1].last_key, Some(vec![String::from("3")]));
+    assert_eq!(manifest.partitions[2].lines, 0);
+    assert_eq!(manifest.partitions[2].first_key, None);
+    assert_eq!(
+      manifest.combined_sha256,
+      "c8c0af38f7d3a9d3e80a72325908243f13779105923f49aaabd33065cea76ef2"
+    );
+
+    manifest.write(&tmp_dir_path).unwrap();
+    let manifest_path = format!("{}/{}", tmp_dir_path, MANIFEST_NAME);
+    assert_eq!(Manifest::read(&manifest_path).unwrap(), manifest);
+    assert!(Manifest::from_json("{\"command\": [\"pjoin\"]}").is_err());
+    assert!(Manifest::from_json("{\"command\": 1, \"partitions\": []}").is_err());
+    assert_eq!(Manifest::read_file_list(&manifest_path).unwrap(), files);
+
+    // a file modified after writing the manifest is detected
+    OpenOptions::new().append(true).open(&files[2]).unwrap()
+      .write_all(b"4;d\n").unwrap();
+    match Manifest::read_file_list(&manifest_path)
+    {
+      Err(Error::InvalidManifest(path, _)) => assert_eq!(path, manifest_path),
+      result => panic!("expected an invalid manifest error, got {:?}", result)
+    }
+  }
+  #[test]
+  fn test_manifest_csv()
+  {
+    let tmp_dir = TempDir::new("manifest").expect("create temp dir");
+    let mut out = create_partial_file(tmp_dir.path().join("0")).unwrap();
+    out.set_csv(Some(CsvDialect::rfc4180()));
+    out.set_sha256(true);
+    // new lines inside quotes don't end the first or the last record
+    out.write_all(b"\"1\n1\";b\n2;a\n\"3;3\";\"c\nc\"\n").unwrap();
+    let outputs = publish_outputs(vec![sync_file(&mut out).unwrap()]).unwrap();
+    assert_eq!(outputs[0].lines, 3);
+    assert_eq!(outputs[0].last_line_pos, 12);
+
+    let manifest = Manifest::from_output(
+      &outputs,
+      &String::from(";"),
+      Some(CsvDialect::rfc4180()),
+      &vec![0],
+      vec![String::from("psort"), String::from("--csv")]
+    ).unwrap();
+    assert_eq!(manifest.partitions[0].lines, 3);
+    assert_eq!(manifest.partitions[0].first_key, Some(vec![String::from("1\n1")]));
+    assert_eq!(manifest.partitions[0].last_key, Some(vec![String::from("3;3")]));
+  }
+}
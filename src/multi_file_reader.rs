@@ -15,15 +15,25 @@
 **/
 
 use std;
-use std::fs;
-use std::fs::File;
+use std::fmt;
 use std::io::SeekFrom;
 use std::io::BufReader;
 use std::io::prelude::*;
 use std::cmp;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use Collation;
+use Error;
+use Result;
+use FieldFormat;
+use source::Source;
+use source::SeekRead;
+use source::ToSource;
+use csv::QuoteState;
 
 // A FileInfo is used to indicate the position at which a file with a given
-// path starts and ends, with `start` and `end` being multi-file references of
+// source starts and ends, with `start` and `end` being multi-file references of
 // positions in a MultiFileReader (or a vector of files).
 //
 // For example, if you have a vector of 2 files:
@@ -31,38 +41,56 @@ use std::cmp;
 // - file2, length = 2048 bytes
 //
 // For that vector of files, the FileInfos could be something like:
-// FileInfo { path: "/tmp/file1", start: 0, end: 1024 }
-// FileInfo { path: "/tmp/file2", start: 1024, end: 3092 }
+// FileInfo { source: "/tmp/file1", start: 0, end: 1024 }
+// FileInfo { source: "/tmp/file2", start: 1024, end: 3092 }
 //
 // FileInfo's are useful for example to be able to seek to a specific
 // multi-file position in a MultiFileReader without having to scann through
 // the files.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct FileInfo
 {
-  path: String,
+  source: Arc<dyn Source>,
   start: u64,
   end: u64
 }
 
+impl fmt::Debug for FileInfo
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+  {
+    write!(
+      f,
+      "FileInfo {{ source: {:?}, start: {}, end: {} }}",
+      self.source.name(),
+      self.start,
+      self.end
+    )
+  }
+}
+
 /// Multi file reader allows to read line by line a vector of files just
 /// like it was only one file.
 ///
+/// The files can be given as a list of paths of local files, or of any other
+/// Source, see ToSource.
+///
 /// File positions managed in the context of a MultiFileReader are always
 /// "multi-file positions", as if all the files were only one, unless specified
 /// otherwise.
 pub struct MultiFileReader
 {
   files_info: Vec<FileInfo>,
-  current_file_buffer: BufReader<File>,
+  current_file_buffer: BufReader<Box<dyn SeekRead>>,
   current_file_index: usize,
   current_file_pos: u64
 }
 
-/// Trait to read a line to a string, allowing verbose debug output
+/// Trait to read a line to a buffer of bytes, allowing verbose debug output.
+/// Lines can have any bytes, not only UTF-8 text.
 pub trait ReadLiner
 {
-  fn read_line(&mut self, buf: &mut String, verbose: bool)
+  fn read_line(&mut self, buf: &mut Vec<u8>, verbose: bool)
     -> std::io::Result<usize>;
 }
 
@@ -71,7 +99,7 @@ impl MultiFileReader
   /// Clones a MultiFileReader, replicating the same state as `self`, and thus
   /// reopening the current file and seeking to the current seek position, and
   /// of course also cloning the other fields in the struct.
-  pub fn clone(&self) -> MultiFileReader
+  pub fn clone(&self) -> Result<MultiFileReader>
   {
     // at the end of the file self.current_file_index is too big, so check for
     // that
@@ -85,48 +113,48 @@ impl MultiFileReader
         self.current_file_index
       }
     };
-    let mut f: File = File::open(
-      self.files_info[file_index].path.clone()
-    ).unwrap();
-    f.seek(SeekFrom::Start(self.current_file_pos)).unwrap();
-    return MultiFileReader
+    let f = open_source(&self.files_info[file_index].source, self.current_file_pos)?;
+    return Ok(MultiFileReader
     {
       current_file_buffer: BufReader::new(f),
       files_info: self.files_info.iter().cloned().collect(),
       current_file_index: self.current_file_index,
       current_file_pos: self.current_file_pos
-    }
+    })
   }
 
   /// Returns the sum of the lengths of all the files in the reader
-  pub fn len(file_list: &Vec<String>) -> u64
+  pub fn len<S: ToSource>(file_list: &Vec<S>) -> Result<u64>
   {
-    file_list.iter().fold(
-      0,
-      |accumulator, path| accumulator + fs::metadata(path).unwrap().len()
-    )
+    let mut len: u64 = 0;
+    for file in file_list.iter()
+    {
+      len += source_len(&file.to_source())?;
+    }
+    Ok(len)
   }
 
-  /// Returns the vector of file infos for a given vector of file paths.
-  pub fn get_files_info(path_list: &Vec<String>) -> Vec<FileInfo>
+  /// Returns the vector of file infos for a given vector of files.
+  pub fn get_files_info<S: ToSource>(path_list: &Vec<S>) -> Result<Vec<FileInfo>>
   {
     // TODO: maybe convert this in a fold
     let mut ret: Vec<FileInfo> = Vec::with_capacity(path_list.len());
     let mut last_end: u64 = 0;
-    for path in path_list.iter()
+    for file in path_list.iter()
     {
-      let fsize = fs::metadata(path).unwrap().len();
+      let source = file.to_source();
+      let fsize = source_len(&source)?;
       ret.push(
         FileInfo
         {
-          path: path.clone(),
+          source: source,
           start: last_end,
           end: last_end + fsize
         }
       );
       last_end += fsize;
     }
-    return ret
+    return Ok(ret)
   }
 
   /// Returns the index of the FileInfo from which to read if the caller wants
@@ -147,7 +175,7 @@ impl MultiFileReader
   ///
   /// Seeking might involve closing the currently opened file and opening
   /// another one if the multi-file seek position lies in another file.
-  pub fn seek(&mut self, pos: u64)
+  pub fn seek(&mut self, pos: u64) -> Result<()>
   {
     // get a valid file_index inside a self.files_info. Usually it's just
     // self.current_file_index, but we use last self.file_info if
@@ -174,10 +202,11 @@ impl MultiFileReader
     // seek
     if pos >= start && pos <= end
     {
+      self.current_file_index = file_index;
       self.current_file_pos = pos - start;
       self.current_file_buffer.seek(
         SeekFrom::Start(self.current_file_pos)
-      ).unwrap();
+      )?;
     }
     // else, we open the appropiate file and seek it
     else
@@ -187,40 +216,43 @@ impl MultiFileReader
       );
       let file = {
         let ref file_info = self.files_info[self.current_file_index];
-        let mut file = File::open(file_info.path.clone()).unwrap();
         self.current_file_pos = pos - file_info.start;
-        file.seek(SeekFrom::Start(self.current_file_pos)).unwrap();
-        file
+        open_source(&file_info.source, self.current_file_pos)?
       };
       self.current_file_buffer = BufReader::new(file);
     }
+    Ok(())
   }
 
-  /// Returns a MultiFileReader for a list of paths. The returned
+  /// Returns a MultiFileReader for a list of files. The returned
   /// MultiFileReader will be at the requested multi-file seek position.
-  pub fn open(path_list: &Vec<String>, pos: u64) -> MultiFileReader
+  ///
+  /// The list of files must not be empty.
+  pub fn open<S: ToSource>(path_list: &Vec<S>, pos: u64) -> Result<MultiFileReader>
   {
-    let files_info: Vec<FileInfo> = MultiFileReader::get_files_info(path_list);
+    if path_list.is_empty()
+    {
+      return Err(Error::InvalidArgument(String::from("empty list of files")))
+    }
+    let files_info: Vec<FileInfo> = MultiFileReader::get_files_info(path_list)?;
     let file_index = MultiFileReader::find_file_info(&files_info, pos);
     let current_file_pos: u64;
     let file = {
       let ref file_info = files_info[file_index];
-      let mut file = File::open(file_info.path.clone()).unwrap();
       current_file_pos = pos - file_info.start;
-      file.seek(SeekFrom::Start(current_file_pos)).unwrap();
-      file
+      open_source(&file_info.source, current_file_pos)?
     };
-    return MultiFileReader
+    return Ok(MultiFileReader
     {
       current_file_buffer: BufReader::new(file),
       files_info: files_info,
       current_file_index: file_index,
       current_file_pos: current_file_pos
-    }
+    })
   }
 
   /// Returns the internal mutable reference to the current file buffer
-  pub fn get_file_buffer(&mut self) -> &mut BufReader<File>
+  pub fn get_file_buffer(&mut self) -> &mut BufReader<Box<dyn SeekRead>>
   {
     return &mut (self.current_file_buffer)
   }
@@ -234,7 +266,7 @@ impl MultiFileReader
   /// Returns the size of the MultiFileReader
   pub fn own_len(&self) -> u64
   {
-    return self.files_info.last().unwrap().end
+    return self.files_info.last().map_or(0, |file_info| file_info.end)
   }
 
   /// Tries to read sequentially to the supplied buffer from the
@@ -249,7 +281,7 @@ impl MultiFileReader
     let buf_len = buf.len();
     while pos < buf_len
     {
-      let len = self.current_file_buffer.read(&mut buf[pos..buf_len]).unwrap();
+      let len = self.current_file_buffer.read(&mut buf[pos..buf_len])?;
       self.current_file_pos += len as u64;
       if len == 0 {
         self.current_file_index += 1;
@@ -257,9 +289,8 @@ impl MultiFileReader
         {
           return Ok(())
         } else {
-          let current_file = File::open(
-            self.files_info[self.current_file_index].path.clone()
-          ).unwrap();
+          let current_file = self.files_info[self.current_file_index].source
+            .open_at(0)?;
           self.current_file_buffer = BufReader::new(current_file);
           self.current_file_pos = 0;
         }
@@ -268,6 +299,85 @@ impl MultiFileReader
     }
     return Ok(())
   }
+
+  /// Returns the multi-file position of the first line that starts after the
+  /// multi-file position `pos`, or the length of the files if there is none.
+  /// The lines of CSV files are their records.
+  pub fn next_line_pos(&mut self, pos: u64) -> Result<u64>
+  {
+    if pos >= self.own_len()
+    {
+      return Ok(self.own_len())
+    }
+    let file_index = MultiFileReader::find_file_info(&self.files_info, pos);
+    let file_start = self.files_info[file_index].start;
+    let csv_next_pos = match self.files_info[file_index].source.csv()
+    {
+      Some(csv_file) => Some(
+        csv_file.record_at(pos - file_start)
+          .map_err(|error| Error::Open(csv_file.name(), error))?.1
+      ),
+      None => None
+    };
+    if let Some(next_pos) = csv_next_pos
+    {
+      return Ok(file_start + next_pos)
+    }
+    // skip the rest of the line containing pos
+    let mut discard_line: Vec<u8> = vec![];
+    self.seek(pos)?;
+    self.read_line(&mut discard_line, false)?;
+    /*return*/ Ok(pos + discard_line.len() as u64)
+  }
+
+  /// Returns the multi-file position of the line containing the byte at the
+  /// multi-file position `pos`. The lines of CSV files are their records.
+  ///
+  /// The bytes before `pos` are read backwards in blocks, the first of
+  /// `buffer_size` bytes and each of the next twice as big as the previous
+  /// one, until a new line is found, so lines can have any length.
+  pub fn line_pos(&mut self, pos: u64, buffer_size: u32) -> Result<u64>
+  {
+    let file_index = MultiFileReader::find_file_info(&self.files_info, pos);
+    let file_start = self.files_info[file_index].start;
+    let csv_line_pos = match self.files_info[file_index].source.csv()
+    {
+      Some(csv_file) => Some(
+        csv_file.record_at(pos - file_start)
+          .map_err(|error| Error::Open(csv_file.name(), error))?.0
+      ),
+      None => None
+    };
+    if let Some(line_pos) = csv_line_pos
+    {
+      return Ok(file_start + line_pos)
+    }
+
+    // read the bytes before pos, and find the start of the line that contains
+    // it as the position after the last \n in them. If there is none, continue
+    // with the bytes before, in a bigger block
+    let mut end_pos: u64 = pos;
+    let mut block_size: u64 = cmp::max(buffer_size, 1) as u64;
+    let mut buf: Vec<u8> = vec![];
+    loop
+    {
+      let seek_pos: u64 = end_pos.saturating_sub(block_size);
+      buf.resize((end_pos - seek_pos) as usize, 0);
+      self.seek(seek_pos)?;
+      self.read(&mut buf)?;
+
+      if let Some(i) = buf.iter().rposition(|&c| c == b'\n')
+      {
+        return Ok(seek_pos + i as u64 + 1)
+      }
+      if seek_pos == 0
+      {
+        return Ok(0)
+      }
+      end_pos = seek_pos;
+      block_size *= 2;
+    }
+  }
 }
 
 impl ReadLiner for MultiFileReader
@@ -277,23 +387,50 @@ impl ReadLiner for MultiFileReader
   ///
   /// If the current opened file has no more lines, then it tries to read the
   /// line from the next file recursively.
-  fn read_line(&mut self, buf: &mut String, verbose: bool)
+  fn read_line(&mut self, buf: &mut Vec<u8>, verbose: bool)
     -> std::io::Result<usize>
   {
-    match self.current_file_buffer.read_line(buf)
+    let line_start = buf.len();
+    match self.current_file_buffer.read_until(b'\n', buf)
     {
       Ok(bytes) =>
       {
         self.current_file_pos += bytes as u64;
         match bytes
         {
-          bytes if bytes > 0 => Ok(bytes),
+          bytes if bytes > 0 =>
+          {
+            // a record of a CSV file continues after new lines inside quotes
+            let csv = self.files_info[self.current_file_index].source.csv()
+              .map(|csv_file| csv_file.dialect());
+            if let Some(dialect) = csv
+            {
+              let mut record_bytes = bytes;
+              let mut state = dialect.scan(
+                QuoteState::Unquoted, &buf[line_start..]
+              );
+              while state != QuoteState::Unquoted
+              {
+                let scan_start = buf.len();
+                let bytes = self.current_file_buffer.read_until(b'\n', buf)?;
+                if bytes == 0
+                {
+                  break;
+                }
+                self.current_file_pos += bytes as u64;
+                record_bytes += bytes;
+                state = dialect.scan(state, &buf[scan_start..]);
+              }
+              return Ok(record_bytes)
+            }
+            Ok(bytes)
+          },
           bytes =>
           {
             if verbose {
               println!(
                 "MultiFileReader::read_line: {:p} read empty line({} bytes): '{}'",
-                self, bytes, buf
+                self, bytes, String::from_utf8_lossy(buf)
               );
             }
             self.current_file_index += 1;
@@ -303,11 +440,10 @@ impl ReadLiner for MultiFileReader
             } else
             {
               if verbose {
-                println!("MultiFileReader::read_line: opening file '{}'", self.files_info[self.current_file_index].path.clone());
+                println!("MultiFileReader::read_line: opening file '{}'", self.files_info[self.current_file_index].source.name());
               }
-              let current_file = File::open(
-                self.files_info[self.current_file_index].path.clone()
-              );
+              let current_file = self.files_info[self.current_file_index].source
+                .open_at(0);
               match current_file
               {
                 Ok(file) =>
@@ -335,241 +471,192 @@ impl ReadLiner for MultiFileReader
 /// underlying struct.
 pub trait FindKeyPosition
 {
-  /// Given a list of path to files that should contain lines with potentially
-  /// multiple values per line separated by the given separator and whose lines
-  /// are sorted by the value that is always at the given key_field position
-  /// of the line, this function returns the position of the line which
-  /// contains the given key value.
-  fn find_key_pos(
-    key: String,
-    path_list: &Vec<String>,
-    separator: char,
-    key_field: usize,
-    verbose: bool,
-    buffer_size: u32
-  ) -> Option<u64>;
+  /// Given a list of paths to files whose lines have multiple values
+  /// separated as defined by `format`, and are sorted by the values at the
+  /// `key_fields` positions in the order defined by `collation`, returns the
+  /// multi-file position of the first line whose key is equal or bigger than
+  /// the given key value, or the length of the files if there is none.
+  fn find_key_lower_bound<S: ToSource>(
+    key: Vec<Vec<u8>>,
+    path_list: &Vec<S>,
+    format: &FieldFormat,
+    key_fields: &Vec<usize>,
+    collation: Collation,
+    verbose: bool
+  ) -> Result<u64>;
+
+  /// Given the same kind of sorted list of files as `find_key_lower_bound`,
+  /// returns the multi-file position of the first line whose key is bigger
+  /// than the given key value, or the length of the files if there is none.
+  fn find_key_upper_bound<S: ToSource>(
+    key: Vec<Vec<u8>>,
+    path_list: &Vec<S>,
+    format: &FieldFormat,
+    key_fields: &Vec<usize>,
+    collation: Collation,
+    verbose: bool
+  ) -> Result<u64>;
 }
 
-/// Returns the last line of the file at the supplied file.
-///
-/// Note: It only works if the last line of the file is shorter than
-/// `buffer_size` in bytes.
-pub fn read_file_last_line(path: &String, buffer_size: u32) -> String
+/// Opens a source at the given position, returning an Open error with its
+/// name if it fails
+fn open_source(source: &Arc<dyn Source>, pos: u64) -> Result<Box<dyn SeekRead>>
 {
-  let file = File::open(path.as_str()).unwrap();
-  let mut file_buf = BufReader::new(file);
-  let file_size = fs::metadata(path).unwrap().len();
-
-  // ensure because that the buffer we need to seek is not be bigger
-  // than the content of the last file.
-  let seek_pos: u64 = cmp::max(
-    0,
-    file_size as i64 - (buffer_size as i64)
-  ) as u64;
-
-  let mut buf = vec![0; (file_size - seek_pos) as usize];
-  file_buf.seek(SeekFrom::Start(seek_pos)).unwrap();
-  file_buf.read_exact(&mut buf[0..((file_size - seek_pos) as usize)]).unwrap();
-
-  let lines = String::from_utf8(buf).unwrap();
-  let split: Vec<&str> = lines.split('\n').collect();
-  let last_line: String = split[split.len()-2].to_string();
-  return last_line
+  source.open_at(pos).map_err(|error| Error::Open(source.name(), error))
 }
 
-/// Given a line of text, splits it and gets the value at the given
-/// `key_field` index.
-pub fn get_key(line: &String, separator: char, key_field: usize) -> &str
+/// Returns the length of a source, or an Open error with its name
+fn source_len(source: &Arc<dyn Source>) -> Result<u64>
 {
-  let values: Vec<&str> = line.split(separator).collect();
-  return values[key_field]
+  source.len().map_err(|error| Error::Open(source.name(), error))
 }
 
-impl FindKeyPosition for MultiFileReader
+/// Removes the new line that ends a line, if it has one: the last line of a
+/// file might not end with a new line
+pub fn remove_new_line(line: &mut Vec<u8>)
 {
-  /// Find the seek position of the key in multiple files.
-  ///
-  /// Asumptions:
-  /// - The files are in order.
-  /// - The content of the files is one element per line.
-  /// - Each element has multiple values, separated by the "separator".
-  /// - The key of an element is in the value with the position "key_field".
-  /// - The key is a positive integer (0 or more).
-  /// - This function should return either the seek position of the key if it is
-  ///   found, or the position of the highest value that is lower than the key
-  ///   otherwise.
-  /// - The last element in the last file must not be bigger than buffer_size
-  ///   bytes
-  /// - files are new-line terminated and contain at least one line
-  fn find_key_pos(
-      key: String,
-      path_list: &Vec<String>,
-      separator: char,
-      key_field: usize,
-      verbose: bool,
-      buffer_size: u32
-  ) -> Option<u64>
+  if line.last() == Some(&b'\n')
   {
-    // contains:
-    // - a key (integer)
-    // - the position in bytes of the line containing it (in a MultiFileReader)
-    // - the size of the line in bytes
-    struct Coordinate {
-      key: String,
-      pos: u64,
-      len: u64
-    }
-    let mut reader = MultiFileReader::open(path_list, 0);
+    line.pop();
+  }
+}
 
-    // "bottom" and "up" are the limit the search range. we will use a binary
-    // search algorithm, and here we set the initial state where bottom is the
-    // first line of the first file, and top the last line of the last file
-    let mut bottom: Coordinate =
+/// Given a line, splits it and gets the values at the given `key_fields`
+/// indexes, which together are the key of the line.
+///
+/// Keys are compared lexicographically, first by the first key field value,
+/// then by the second, and so on.
+///
+/// Returns a MissingField error if the line doesn't have any of the fields.
+pub fn get_key(line: &[u8], format: &FieldFormat, key_fields: &Vec<usize>)
+  -> Result<Vec<Vec<u8>>>
+{
+  let values: Vec<Vec<u8>> = format.split(line);
+  return key_fields.iter().map(
+    |&i| match values.get(i)
     {
+      Some(value) => Ok(value.clone()),
+      None => Err(Error::MissingField(String::from_utf8_lossy(line).into_owned(), i))
+    }
+  ).collect()
+}
 
-      let mut first_line: String = String::new();
-      reader.read_line(&mut first_line, false).unwrap();
-      first_line.pop(); // remove \n
+/// Returns the values of a key as text, replacing the bytes that are not
+/// valid UTF-8, to print it or write it in a manifest
+pub fn key_to_strings<T: AsRef<[u8]>>(key: &[T]) -> Vec<String>
+{
+  key.iter()
+    .map(|value| String::from_utf8_lossy(value.as_ref()).into_owned())
+    .collect()
+}
 
-      /*return*/ Coordinate
-      {
-        key: get_key(&first_line, separator, key_field).to_string(),
-        pos: 0,
-        len: first_line.len() as u64 + 1 /* \n */
-      }
-    };
-    let mut top: Coordinate =
-    {
-      // getting the last line of the last file is a bit tricky. We basically
-      // read the last part of the file into a big enough buffer, so that the
-      // buffer contains at least one \n character & split the buffer by that
-      // character to get the last line.
-      let last_file_path = path_list.last().unwrap().clone();
-      let last_str: String = read_file_last_line(&last_file_path, buffer_size);
-      let last_key: &str = get_key(&last_str, separator, key_field);
-
-      /*return*/Coordinate
-      {
-        key: last_key.trim().to_string(),
-        pos: reader.own_len() - (last_str.len() as u64) - 1 /* \n */,
-        len: last_str.len() as u64 + 1 /* \n */
-      }
-    };
+/// Binary search used to implement `find_key_lower_bound` and
+/// `find_key_upper_bound`. Returns the multi-file position of the first line
+/// whose key is bigger than the given key (if `strict`) or equal or bigger
+/// (otherwise), or the length of the files if there is none.
+///
+/// The search keeps a [low, high) range of positions where the line we look
+/// for starts. `low` is always the start of a line, and so is `high` unless it
+/// is the end of the files.
+fn find_key_bound<S: ToSource>(
+  key: Vec<Vec<u8>>,
+  path_list: &Vec<S>,
+  format: &FieldFormat,
+  key_fields: &Vec<usize>,
+  collation: Collation,
+  strict: bool,
+  verbose: bool
+) -> Result<u64>
+{
+  let path_list = format.sources(path_list);
+  let mut reader = MultiFileReader::open(&path_list, 0)?;
+  let mut low: u64 = 0;
+  let mut high: u64 = reader.own_len();
 
-    // CASE A: if we found the key, return it
-    if bottom.key == key
+  while low < high
+  {
+    // find the first line starting at or after the middle position. If there
+    // is none before high, then use the line at low.
+    let middle_pos: u64 = low + (high - low) / 2;
+    let mut line_pos: u64 = middle_pos;
+    if middle_pos > 0
     {
-      if verbose {
-        println!("MultiFileReader::find_key_pos Case A: bottom.key={} key={}", bottom.key, key);
-      }
-      return Some(bottom.pos)
+      line_pos = reader.next_line_pos(middle_pos - 1)?;
     }
-    // CASE B: if we found the key, return it
-    else if top.key == key
+    if line_pos >= high
     {
-      if verbose {
-        println!("MultiFileReader::find_key_pos Case B: top.key={} key={}", top.key, key);
-      }
-      return Some(top.pos)
-    }
-    // CASE C
-    else if bottom.pos + bottom.len == top.pos
-    {
-      if top.key > key
-      {
-        if verbose {
-          println!("MultiFileReader::find_key_pos Case C.1: bottom.key={} key={}", bottom.key, key);
-        }
-        return Some(bottom.pos)
-      }
-      else {
-        if verbose {
-          println!("MultiFileReader::find_key_pos Case C.2: top.key={} key={}", top.key, key);
-        }
-        return Some(top.pos)
-      }
+      line_pos = low;
     }
 
-    loop
-    {
-      // CASE D: if we didn't found the key but top and bottom are next to each other,
-      // return the bottom, because it is the highest number that is equal or
-      // lower than the key
-      if bottom.pos + bottom.len == top.pos
+    let mut line: Vec<u8> = vec![];
+    reader.seek(line_pos)?;
+    reader.read_line(&mut line, false)?;
+    let line_len = line.len() as u64;
+    remove_new_line(&mut line);
+
+    let found = {
+      let line_key: Vec<Vec<u8>> = get_key(&line, format, key_fields)?;
+      let ordering = collation.compare_keys(&line_key, &key);
+      if strict
       {
-        if verbose {
-          println!("MultiFileReader::find_key_pos Case D: bottom.pos({}) + bottom.len({}) == top.pos({})", bottom.pos, bottom.len, top.pos);
-        }
-        return Some(bottom.pos)
+        ordering == Ordering::Greater
       }
-      // CASE E: we didn't find the key and still have space to find it, so find an
-      // element in the middle of that space and iterate
       else
       {
-        // our range includes the bottom line (so we use "bottom.pos" instead of
-        // "bottom.pos + bottom.len") because the first line as it might be cut
-        // it is going to be discarded anyway, so there's no fear of ending up
-        // with the bottom line again
-        let middle_pos: u64 = bottom.pos + (top.pos - bottom.pos) / 2;
-        reader.seek(middle_pos);
-
-        // discard first line
-        let mut cut_pos: u64 = middle_pos;
-        {
-          let mut discard_line = String::new();
-          reader.read_line(&mut discard_line, false).unwrap();
-          cut_pos += discard_line.len() as u64;
-        }
+        ordering != Ordering::Less
+      }
+    };
+    if verbose {
+      println!(
+        "MultiFileReader::find_key_bound: low={} high={} line_pos={} found={}",
+        low, high, line_pos, found
+      );
+    }
 
-        // CASE E.1: if we are ending up in the top position, it means that the
-        // middle is too close to the top, so we should just use as a cut_pos
-        // a line closer to bottom. The easiest way to do it is to use the line
-        // next to the bottom line, so that's what we do here.
-        //
-        // NOTE that at CASE D we know for a fact that there's something in
-        // the middle between bottom and pos because otherwise we would be at
-        // either case A, B or C but not D.
-        if cut_pos == top.pos
-        {
-          cut_pos = bottom.pos + bottom.len;
-          reader.seek(cut_pos);
-        }
+    if found
+    {
+      high = line_pos;
+    }
+    else
+    {
+      low = line_pos + line_len;
+    }
+  }
+  return Ok(low)
+}
 
-        let mut cut_line = String::new();
-        reader.read_line(&mut cut_line, false).unwrap();
-        cut_line.pop(); // remove \n
-        let cut_line_key: String = get_key(&cut_line, separator, key_field).to_string();
+impl FindKeyPosition for MultiFileReader
+{
+  /// Find the position of the first line whose key is equal or bigger than
+  /// `key`, in files sorted by key.
+  fn find_key_lower_bound<S: ToSource>(
+    key: Vec<Vec<u8>>,
+    path_list: &Vec<S>,
+    format: &FieldFormat,
+    key_fields: &Vec<usize>,
+    collation: Collation,
+    verbose: bool
+  ) -> Result<u64>
+  {
+    find_key_bound(
+      key, path_list, format, key_fields, collation, false, verbose
+    )
+  }
 
-        // Case E.2
-        if cut_line_key == key
-        {
-          if verbose {
-            println!("MultiFileReader::find_key_pos Case E.2: cut_line_key({}) == key({})", cut_line_key, key);
-          }
-          return Some(cut_pos);
-        }
-        // Case E.3
-        else if cut_line_key > key
-        {
-          if verbose {
-            println!("MultiFileReader::find_key_pos Case E.3: cut_line_key({}) > key({})", cut_line_key, key);
-          }
-          top.pos = cut_pos;
-          top.key = cut_line_key.clone();
-          top.len = cut_line.len() as u64 + 1;
-        }
-        // Case E.4
-        else if cut_line_key < key
-        {
-          if verbose {
-            println!("MultiFileReader::find_key_pos Case E.4: cut_line_key({}) > key({})", cut_line_key, key);
-          }
-          bottom.pos = cut_pos;
-          bottom.key = cut_line_key.clone();
-          bottom.len = cut_line.len() as u64 + 1;
-        }
-      }
-    }
+  /// Find the position of the first line whose key is bigger than `key`, in
+  /// files sorted by key.
+  fn find_key_upper_bound<S: ToSource>(
+    key: Vec<Vec<u8>>,
+    path_list: &Vec<S>,
+    format: &FieldFormat,
+    key_fields: &Vec<usize>,
+    collation: Collation,
+    verbose: bool
+  ) -> Result<u64>
+  {
+    find_key_bound(
+      key, path_list, format, key_fields, collation, true, verbose
+    )
   }
 }
 
@@ -578,12 +665,13 @@ mod test {
   use tempdir::TempDir;
 
   use test_helpers::_write_files;
+  use test_helpers::_format;
 
   use MultiFileReader;
   use ReadLiner;
+  use Collation;
   use multi_file_reader::FindKeyPosition;
   use multi_file_reader::get_key;
-  use multi_file_reader::read_file_last_line;
 
   #[test]
   fn test_multifile_get_files_info()
@@ -592,7 +680,7 @@ mod test {
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
 
-    let files_info = MultiFileReader::get_files_info(&files);
+    let files_info = MultiFileReader::get_files_info(&files).unwrap();
     assert_eq!(files_info[0].start, 0);
     assert_eq!(files_info[0].end, 6);
     assert_eq!(files_info[1].start, 6);
@@ -606,7 +694,7 @@ mod test {
     let data = "0,1,2|3|4,5,6|7,8,9,10|11,12,13,14,15,16";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let files_info = MultiFileReader::get_files_info(&files);
+    let files_info = MultiFileReader::get_files_info(&files).unwrap();
 
     assert_eq!(MultiFileReader::find_file_info(&files_info, 0), 0);
     assert_eq!(MultiFileReader::find_file_info(&files_info, 1), 0);
@@ -622,43 +710,43 @@ mod test {
     let data = "0,1,2|3|4,5,6|7";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let mut reader = MultiFileReader::open(&files, 0);
+    let mut reader = MultiFileReader::open(&files, 0).unwrap();
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "0\n");
+    assert_eq!(s, b"0\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "1\n");
+    assert_eq!(s, b"1\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "2\n");
+    assert_eq!(s, b"2\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "3\n");
+    assert_eq!(s, b"3\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "4\n");
+    assert_eq!(s, b"4\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "5\n");
+    assert_eq!(s, b"5\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "6\n");
+    assert_eq!(s, b"6\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "7\n");
+    assert_eq!(s, b"7\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "");
+    assert_eq!(s, b"");
   }
 
   #[test]
@@ -667,15 +755,15 @@ mod test {
     let data = "0,1,2|3|4,5,6|7";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let mut reader = MultiFileReader::open(&files, 8);
+    let mut reader = MultiFileReader::open(&files, 8).unwrap();
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "4\n");
+    assert_eq!(s, b"4\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "5\n");
+    assert_eq!(s, b"5\n");
   }
 
   #[test]
@@ -684,15 +772,15 @@ mod test {
     let data = "0,1,2|3|4,5,6|7";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let mut reader = MultiFileReader::open(&files, 9);
+    let mut reader = MultiFileReader::open(&files, 9).unwrap();
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "\n");
+    assert_eq!(s, b"\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "5\n");
+    assert_eq!(s, b"5\n");
   }
 
   #[test]
@@ -701,15 +789,15 @@ mod test {
     let data = "0,1,2|3|4,5,6|7";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let mut reader = MultiFileReader::open(&files, 7);
+    let mut reader = MultiFileReader::open(&files, 7).unwrap();
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "\n");
+    assert_eq!(s, b"\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "4\n");
+    assert_eq!(s, b"4\n");
   }
 
   #[test]
@@ -718,182 +806,152 @@ mod test {
     let data = "0,1,2|3|4,5,6|7,8,9,10|11,12,13,14,15,16";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let mut reader = MultiFileReader::open(&files, 0);
+    let mut reader = MultiFileReader::open(&files, 0).unwrap();
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "0\n");
+    assert_eq!(s, b"0\n");
 
-    reader.seek(8);
+    reader.seek(8).unwrap();
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "4\n");
+    assert_eq!(s, b"4\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "5\n");
+    assert_eq!(s, b"5\n");
 
-    reader.seek(9);
+    reader.seek(9).unwrap();
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "\n");
+    assert_eq!(s, b"\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "5\n");
+    assert_eq!(s, b"5\n");
 
-    reader.seek(7);
+    reader.seek(7).unwrap();
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "\n");
+    assert_eq!(s, b"\n");
 
-    let mut s = String::new();
+    let mut s: Vec<u8> = vec![];
     reader.read_line(&mut s, false).expect("reading a line");
-    assert_eq!(s.as_str(), "4\n");
+    assert_eq!(s, b"4\n");
   }
 
   #[test]
   fn test_get_key()
   {
-    let line = String::from("1,2;3,4");
-    let key = get_key(&line, ';', 0);
-    assert_eq!(key, "1,2");
-
-    let line = String::from("1,bb;3,4");
-    let key = get_key(&line, ',', 1);
-    assert_eq!(key, "bb;3");
-  }
+    let line = b"1,2;3,4";
+    let key = get_key(line, &_format(";"), &vec![0]).unwrap();
+    assert_eq!(key, vec![b"1,2".to_vec()]);
 
-  #[test]
-  fn test_read_file_last_line()
-  {
-    let data = "aaaaaaaaaaa,b,ccc,dddddddddddddddddddd,erergerg";
-    let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
-    let files = _write_files(data, &tmp_dir);
+    let line = b"1,bb;3,4";
+    let key = get_key(line, &_format(","), &vec![1]).unwrap();
+    assert_eq!(key, vec![b"bb;3".to_vec()]);
 
-    let last_line = read_file_last_line(files.first().unwrap(), 16384);
-    assert_eq!(last_line, "erergerg");
+    let line = b"1,bb;3,4";
+    let key = get_key(line, &_format(","), &vec![2, 0]).unwrap();
+    assert_eq!(key, vec![b"4".to_vec(), b"1".to_vec()]);
   }
 
   #[test]
-  fn test_find_key()
+  fn test_line_pos()
   {
-    let data = "0,1,2,3,4,5,6,7,8,9,10";
+    let data = "aaaaaaaaaaaaaaaaaa,b,cccccccc|dddddddddddddddddddddddd|e,ffffffffffff";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let buffer_size = 16384;
-
-    let pos = MultiFileReader::find_key_pos(
-      "0".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(0));
-
-    let pos = MultiFileReader::find_key_pos(
-      "10".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(20));
-
-    let pos = MultiFileReader::find_key_pos(
-      "1".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(2));
-
-    let pos = MultiFileReader::find_key_pos(
-      "2".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(4));
-
-    let pos = MultiFileReader::find_key_pos(
-      "3".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(6));
-
-    let pos = MultiFileReader::find_key_pos(
-      "4".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(8));
-
-    let pos = MultiFileReader::find_key_pos(
-      "5".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(10));
-
-    let pos = MultiFileReader::find_key_pos(
-      "6".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(12));
-
-    let pos = MultiFileReader::find_key_pos(
-      "9".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(18));
-
-    let pos = MultiFileReader::find_key_pos(
-      "8".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(16));
-
-    let pos = MultiFileReader::find_key_pos(
-      "7".to_string(), &files, ',', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(14));
-  }
+    let contents: Vec<u8> = data.replace(",", "\n").replace("|", "\n").into_bytes();
+    let contents: Vec<u8> = [&contents[..], b"\n"].concat();
 
-  #[test]
-  fn test_find_key_2chars()
-  {
-    let data = "0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17";
-    let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
-    let files = _write_files(data, &tmp_dir);
-    let buffer_size = 16384;
-
-    let pos = MultiFileReader::find_key_pos(
-      "16".to_string(), &files, '|', 0, false, buffer_size);
-    assert_eq!(pos, Some(38));
+    let mut reader = MultiFileReader::open(&files, 0).unwrap();
+    for &buffer_size in [1, 2, 7, 16384].iter()
+    {
+      for pos in 0..contents.len()
+      {
+        let expected = match contents[..pos].iter().rposition(|&c| c == b'\n')
+        {
+          Some(i) => i + 1,
+          None => 0
+        };
+        assert_eq!(
+          reader.line_pos(pos as u64, buffer_size).unwrap(), expected as u64
+        );
+      }
+    }
   }
 
   #[test]
-  fn test_find_key_letters()
+  fn test_find_key_bounds()
   {
-    let data = "aaa,aab,aac,abb,ccc,ddde,eeeee,ffff,g";
+    let data = "0,1,1|1,1,3|3,5";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let buffer_size = 16384;
 
-    let pos = MultiFileReader::find_key_pos(
-      "ffff".to_string(), &files, '|', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(31));
+    let bounds = |key: &str| -> (u64, u64)
+    {
+      (
+        MultiFileReader::find_key_lower_bound(
+          vec![key.as_bytes().to_vec()], &files, &_format(","), &vec![0], Collation::Bytewise, false
+        ).unwrap(),
+        MultiFileReader::find_key_upper_bound(
+          vec![key.as_bytes().to_vec()], &files, &_format(","), &vec![0], Collation::Bytewise, false
+        ).unwrap()
+      )
+    };
+    assert_eq!(bounds("0"), (0, 2));
+    assert_eq!(bounds("1"), (2, 10));
+    assert_eq!(bounds("2"), (10, 10));
+    assert_eq!(bounds("3"), (10, 14));
+    assert_eq!(bounds("5"), (14, 16));
+    assert_eq!(bounds("6"), (16, 16));
+    assert_eq!(bounds("/"), (0, 0));
   }
 
   #[test]
-  fn test_find_key_non_existant()
+  fn test_find_key_bounds_multiple_fields()
   {
-    let data = "aaa,aab,aac,abb,ccc,ddde,eeeee,ffff,g";
+    let data = "1;a;0,1;b;0,1;b;1,2;a;0|2;a;1,2;c;0";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let buffer_size = 16384;
 
-    let pos = MultiFileReader::find_key_pos(
-      "fggg".to_string(), &files, '|', 0, false, buffer_size
-    );
-    assert_eq!(pos, Some(31));
+    let bounds = |key: Vec<&str>| -> (u64, u64)
+    {
+      let key: Vec<Vec<u8>> = key.iter().map(|k| k.as_bytes().to_vec()).collect();
+      (
+        MultiFileReader::find_key_lower_bound(
+          key.clone(), &files, &_format(";"), &vec![0, 1], Collation::Bytewise, false
+        ).unwrap(),
+        MultiFileReader::find_key_upper_bound(
+          key, &files, &_format(";"), &vec![0, 1], Collation::Bytewise, false
+        ).unwrap()
+      )
+    };
+    assert_eq!(bounds(vec!["1", "a"]), (0, 6));
+    assert_eq!(bounds(vec!["1", "b"]), (6, 18));
+    assert_eq!(bounds(vec!["1", "c"]), (18, 18));
+    assert_eq!(bounds(vec!["2", "a"]), (18, 30));
+    assert_eq!(bounds(vec!["2", "c"]), (30, 36));
   }
 
   #[test]
-  fn test_find_key_non_existant_second_column()
+  fn test_find_key_collation()
   {
-    let data = "whatever#aaa,whatever2#aab,whatever33#aac,whatever55#abb,whatever66#ccc,whatever___#ddde,what__ever#eeeee,a#ffff,whate#g";
+    let data = "1,2,9,10,11,100";
     let tmp_dir = TempDir::new("multi_file_reader").expect("create temp dir");
     let files = _write_files(data, &tmp_dir);
-    let buffer_size = 16384;
 
-    let pos = MultiFileReader::find_key_pos(
-      "fggg".to_string(), &files, '#', 1, false, buffer_size
-    );
-    assert_eq!(pos, Some(106));
+    let pos = MultiFileReader::find_key_lower_bound(
+      vec![b"10".to_vec()], &files, &_format(","), &vec![0], Collation::Integer, false
+    ).unwrap();
+    assert_eq!(pos, 6);
+    let pos = MultiFileReader::find_key_upper_bound(
+      vec![b"010".to_vec()], &files, &_format(","), &vec![0], Collation::Integer, false
+    ).unwrap();
+    assert_eq!(pos, 9);
   }
 }
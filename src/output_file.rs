@@ -14,8 +14,9 @@
  * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
 **/
 
+use std::cmp::Ordering;
 use std::fs::File;
-use std::io::BufWriter;
+use std::path::Path;
 use std::io::prelude::*;
 
 use LineReader;
@@ -23,6 +24,26 @@ use MultiFileReader;
 use ByteRangeLineReader;
 use multi_file_reader::FindKeyPosition;
 use multi_file_reader::get_key;
+use multi_file_reader::key_to_strings;
+use source::ToSource;
+use FieldFormat;
+use Fields;
+use JoinType;
+use Collation;
+use Error;
+use Result;
+use publish::OutputWriter;
+use publish::OutputStats;
+use publish::sync_file;
+
+/// Creates a file at the given path to write output to it, returning an Open
+/// error with the path if it fails.
+pub fn create_file<P: AsRef<Path>>(path: P) -> Result<File>
+{
+  File::create(path.as_ref()).map_err(
+    |error| Error::Open(path.as_ref().to_string_lossy().into_owned(), error)
+  )
+}
 
 /// Struct used to read sequentially from two ByteRangeLineReaders sorted
 /// lines, ending in the same key from both readers, writing matches to a third
@@ -31,13 +52,24 @@ use multi_file_reader::get_key;
 /// Note that a single ByteRangeLineReader can read from multiple files and has
 /// itself an end position - that's why it's a range.
 ///
-/// Each line read and written can have multiples fields, separated by the
-/// specified separator string.
+/// Each line read and written can have multiples fields, separated as
+/// specified by a FieldFormat.
 pub struct OutputFile {
-  separator: String,
+  format: FieldFormat,
   verbose: bool,
-  output_file: BufWriter<File>,
+  output_file: OutputWriter,
   output_fields: Vec<(bool, usize)>,
+  join_type: JoinType,
+  empty: String,
+  collation: Collation,
+  // the first file2 line of the current run of lines with equal keys
+  run_line: Fields,
+  // the lines of the run, of which only the first file2_run_len are used, so
+  // that their memory is reused for the next runs
+  file2_run: Vec<Fields>,
+  file2_run_len: usize,
+  // buffer in which each output line is joined before writing it
+  line: Vec<u8>,
   pub file1: LineReader<ByteRangeLineReader>,
   pub file2: LineReader<ByteRangeLineReader>
 }
@@ -51,21 +83,31 @@ impl OutputFile {
   /// ByteRangeLineReader.
   ///
   /// The file2 is not yet split, but it receives the list of multiple files
-  /// that compose it and a start position. With that and then end position of
-  /// the file1, the last line in the file1 range is read, the last key of that
-  /// line in file1 is obtained, and then the file2 files are scanned using a
-  /// binary search to find the line containing corresponding to the file1 last
-  /// key, and the position of that line in file2 is used to define the end
-  /// of a ByteRangeLineReader for file2.
+  /// that compose it and a start position, which is the end of the file2 range
+  /// of the previous OutputFile. The last line in the file1 range is read and
+  /// its key obtained, and then the file2 files are scanned using a binary
+  /// search to find the first line after the file1 last key, which is the end
+  /// of a ByteRangeLineReader for file2 that starts at the start position.
+  /// The file1 range must come from ByteRangeLineReader::open_by_key, which
+  /// never splits a run of lines with equal keys between two ranges, so that
+  /// no file2 line with the file1 first key is before the start position.
+  ///
+  /// The range of the last file1 range always extends until the end of file2,
+  /// so that together all the file2 ranges cover all the lines of file2.
   ///
   /// If verbose is set to true, some debug output will be shown when operating
   /// with this OutputFile.
   ///
-  /// The separator is used to separate the multiple fields when reading from
-  /// file1 and file2, and also to separate the output values when writing the
-  /// matches in the output file.
+  /// Both files must be sorted by their key fields in the order defined by the
+  /// `collation`, which is used to compare the keys.
+  ///
+  /// The format is used to split the multiple fields when reading from file1
+  /// and file2, and also to join the output values when writing the matches in
+  /// the output file, quoting them if the files are CSV files.
   ///
-  /// The output file path is specified by the `output_file_str`.
+  /// The output is written with `output_file`, usually the partial file of an
+  /// output file created with create_partial_file, which has to be published
+  /// with publish_files after calling `flush`.
   ///
   /// When a match is added, a line is written in the outputfile, containing
   /// the fields specified in the output_fields_str_list.
@@ -73,62 +115,95 @@ impl OutputFile {
   /// The format of the output_fields_str_list elements is of format
   /// "file_num.field_num", for example "1.0" would specify the first element
   /// of file1, and "2.3" would specify the 4th element of file2.
-  pub fn new(
-      separator: String,
+  ///
+  /// The `join_type` defines how the unpaired lines are written. If it only
+  /// writes lines of one of the files, unpaired lines are written whole.
+  /// Otherwise, the output fields of the other file, and any field missing in
+  /// the line, are written as the `empty` string.
+  pub fn new<S: ToSource>(
+      format: FieldFormat,
       verbose: bool,
-      output_file_str: String,
+      output_file: OutputWriter,
       output_fields_str_list: Vec<String>,
-      field1: u32,
-      file2_str_list: Vec<String>,
-      field2: u32,
+      join_type: JoinType,
+      empty: String,
+      field1: Vec<usize>,
+      file2_str_list: Vec<S>,
+      field2: Vec<usize>,
+      collation: Collation,
       file1_range: ByteRangeLineReader,
       start_pos: u64,
       buffer_size: u32
-  ) -> OutputFile
+  ) -> Result<OutputFile>
   {
-    let separator_char = separator.chars().next().unwrap();
+    let file2_str_list = format.sources(&file2_str_list);
+    let end_pos: u64 = match file1_range.first_line()?
+    {
+      Some(first_line) =>
+      {
+        let last_key: Vec<Vec<u8>> = get_key(
+          &file1_range.last_line()?, &format, &field1
+        )?;
+        if verbose {
+          println!(
+            "OutputFile::new: out={} start_pos={} first_key={:?} last_key={:?}",
+            output_file.path().display(),
+            start_pos,
+            key_to_strings(&get_key(&first_line, &format, &field1)?),
+            key_to_strings(&last_key)
+          );
+        }
 
-    let last_key: String = get_key(
-      &file1_range.last_line(), separator_char, field1 as usize
-    ).to_string();
-    if verbose {
-      println!(
-        "OutputFile::new: out={} start_pos={} last_key={}",
-        output_file_str,
-        start_pos,
-        last_key
-      );
+        MultiFileReader::find_key_upper_bound(
+          last_key,
+          &file2_str_list,
+          &format,
+          &field2,
+          collation,
+          verbose
+        )?
+      },
+      // an empty file1 range has nothing to join with
+      None => start_pos
+    };
+    let end_pos: u64 = if file1_range.end() >= file1_range.own_len()
+    {
+      MultiFileReader::len(&file2_str_list)?
     }
-
-    let end_pos: u64 = MultiFileReader::find_key_pos(
-      last_key,
-      &file2_str_list,
-      separator_char,
-      field2 as usize,
-      verbose,
-      buffer_size
-    ).unwrap();
+    else
+    {
+      end_pos
+    };
 
     if verbose {
       println!(
-        "OutputFile::new: calculated_end_pos={}",
-        end_pos,
+        "OutputFile::new: calculated_start_pos={} calculated_end_pos={}",
+        start_pos,
+        end_pos
       );
     }
 
-    return OutputFile
+    return Ok(OutputFile
     {
-      separator:      separator.clone(),
+      format:         format.clone(),
       verbose:        verbose,
-      output_file:    BufWriter::new(File::create(output_file_str).unwrap()),
+      output_file:    output_file,
 
       output_fields:  output_fields_str_list.iter().map(
         |s| OutputFile::pair_split(s)
-      ).collect(),
+      ).collect::<Result<Vec<(bool, usize)>>>()?,
+
+      join_type:      join_type,
+      empty:          empty,
+      collation:      collation,
+      run_line:       Fields::new(),
+      file2_run:      Vec::new(),
+      file2_run_len:  0,
+      line:           Vec::new(),
 
       file1:          LineReader::new(
         file1_range,
-        separator.clone(),
+        format.clone(),
         field1,
         verbose
       ),
@@ -140,12 +215,12 @@ impl OutputFile {
           end_pos,
           verbose,
           buffer_size
-        ),
-        separator.clone(),
+        )?,
+        format,
         field2,
         verbose
       )
-    }
+    })
   }
 
   /// This is used to process the format in which the output_fields_str_list is
@@ -153,12 +228,19 @@ impl OutputFile {
   ///
   /// Processes a split, converting it to a pair of (bool, usize) that means
   /// (is_file1, index of the field in split values of the line).
-  pub fn pair_split(s: &String) -> (bool, usize)
+  ///
+  /// Returns an InvalidArgument error if it is not of the format
+  /// "file_num.field_num".
+  pub fn pair_split(s: &String) -> Result<(bool, usize)>
   {
+    let invalid = || Error::InvalidArgument(format!("output field '{}'", s));
     let vals: Vec<&str> = (*s).split(".").collect();
-    assert!(vals.len() == 2);
-    let value: u32 = vals[1].parse().unwrap();
-    (vals[0] == "1", value as usize)
+    if vals.len() != 2 || (vals[0] != "1" && vals[0] != "2")
+    {
+      return Err(invalid())
+    }
+    let value: usize = vals[1].parse().map_err(|_| invalid())?;
+    Ok((vals[0] == "1", value))
   }
 
   /// Returns the multi-file calculated end position of the file2
@@ -171,35 +253,232 @@ impl OutputFile {
   /// required values from both lines according to the configuration given in
   /// the contructor (the input var `output_fields_str_list`) and writing them
   /// into a line in the output file.
-  pub fn add_match(&mut self)
+  pub fn add_match(&mut self) -> Result<()>
   {
     if self.verbose {
-      println!("OutputFile::add_match file1_key={}", self.file1_key());
+      println!("OutputFile::add_match file1_key={:?}", self.file1_key().map(|key| key_to_strings(&key)));
     }
-    let line: Vec<String> = self.output_fields.iter()
+    OutputFile::write_fields(
+      &mut self.output_file,
+      &mut self.line,
+      &self.output_fields,
+      &self.format,
+      &self.empty,
+      Some(self.file1.fields()),
+      Some(self.file2.fields())
+    )
+  }
+
+  /// Compares the keys of the current lines of file1 and file2
+  pub fn compare_keys(&self) -> Result<Ordering>
+  {
+    self.file1.compare_key(
+      self.file2.fields(), self.file2.key_fields(), self.collation
+    )
+  }
+
+  /// Returns whether the current file1 line has the key of the last file2 run,
+  /// buffered or skipped with `buffer_file2_run` or `skip_file2_run`
+  pub fn file1_in_run(&self) -> Result<bool>
+  {
+    let ordering = self.file1.compare_key(
+      &self.run_line, self.file2.key_fields(), self.collation
+    )?;
+    Ok(ordering == Ordering::Equal)
+  }
+
+  /// Returns whether the current file2 line has the key of the last file2 run
+  fn file2_in_run(&self) -> Result<bool>
+  {
+    let ordering = self.file2.compare_key(
+      &self.run_line, self.file2.key_fields(), self.collation
+    )?;
+    Ok(ordering == Ordering::Equal)
+  }
+
+  /// Reads all the consecutive file2 lines that have the same key as the
+  /// current file2 line, keeping them so that they can be matched with every
+  /// file1 line with that key using `add_run_matches`.
+  ///
+  /// After this, the current file2 line is the first one with a different key.
+  pub fn buffer_file2_run(&mut self) -> Result<()>
+  {
+    if self.verbose {
+      println!("OutputFile::buffer_file2_run file2_key={:?}", self.file2_key().map(|key| key_to_strings(&key)));
+    }
+    self.run_line.clone_from(self.file2.fields());
+    self.file2_run_len = 0;
+    while self.file2.has_current() && self.file2_in_run()?
+    {
+      if self.file2_run_len == self.file2_run.len()
+      {
+        self.file2_run.push(Fields::new());
+      }
+      self.file2_run[self.file2_run_len].clone_from(self.file2.fields());
+      self.file2_run_len += 1;
+      self.file2.read_next()?;
+    }
+    if self.verbose {
+      println!(
+        "OutputFile::buffer_file2_run lines={}", self.file2_run_len
+      );
+    }
+    Ok(())
+  }
+
+  /// Skips all the consecutive file2 lines that have the same key as the
+  /// current file2 line, without keeping them.
+  ///
+  /// After this, the current file2 line is the first one with a different key.
+  pub fn skip_file2_run(&mut self) -> Result<()>
+  {
+    self.run_line.clone_from(self.file2.fields());
+    while self.file2.has_current() && self.file2_in_run()?
+    {
+      self.file2.read_next()?;
+    }
+    Ok(())
+  }
+
+  /// Adds a match for the current line of file1 with each of the file2 lines
+  /// buffered by the last call to `buffer_file2_run`.
+  pub fn add_run_matches(&mut self) -> Result<()>
+  {
+    if self.verbose {
+      println!("OutputFile::add_run_matches file1_key={:?}", self.file1_key().map(|key| key_to_strings(&key)));
+    }
+    for file2_fields in self.file2_run[..self.file2_run_len].iter()
+    {
+      OutputFile::write_fields(
+        &mut self.output_file,
+        &mut self.line,
+        &self.output_fields,
+        &self.format,
+        &self.empty,
+        Some(self.file1.fields()),
+        Some(file2_fields)
+      )?;
+    }
+    Ok(())
+  }
+
+  /// Adds the current line of file1 as an unpaired line. It is written whole
+  /// if the join type only writes lines of one file, or otherwise using the
+  /// empty string for the file2 output fields.
+  pub fn add_unpaired1(&mut self) -> Result<()>
+  {
+    if self.verbose {
+      println!("OutputFile::add_unpaired1 file1_key={:?}", self.file1_key().map(|key| key_to_strings(&key)));
+    }
+    if self.join_type.whole_lines()
+    {
+      return self.add_file1_line()
+    }
+    OutputFile::write_fields(
+      &mut self.output_file,
+      &mut self.line,
+      &self.output_fields,
+      &self.format,
+      &self.empty,
+      Some(self.file1.fields()),
+      None
+    )
+  }
+
+  /// Adds the current line of file2 as an unpaired line. It is written whole
+  /// if the join type only writes lines of one file, or otherwise using the
+  /// empty string for the file1 output fields.
+  pub fn add_unpaired2(&mut self) -> Result<()>
+  {
+    if self.verbose {
+      println!("OutputFile::add_unpaired2 file2_key={:?}", self.file2_key().map(|key| key_to_strings(&key)));
+    }
+    if self.join_type.whole_lines()
+    {
+      return self.add_file2_line()
+    }
+    OutputFile::write_fields(
+      &mut self.output_file,
+      &mut self.line,
+      &self.output_fields,
+      &self.format,
+      &self.empty,
+      None,
+      Some(self.file2.fields())
+    )
+  }
+
+  /// Writes the current line of file1 whole into the output file
+  pub fn add_file1_line(&mut self) -> Result<()>
+  {
+    OutputFile::write_line(
+      &mut self.output_file, &mut self.line, &self.format, self.file1.fields()
+    )
+  }
+
+  /// Writes the current line of file2 whole into the output file
+  pub fn add_file2_line(&mut self) -> Result<()>
+  {
+    OutputFile::write_line(
+      &mut self.output_file, &mut self.line, &self.format, self.file2.fields()
+    )
+  }
+
+  /// Writes to the output file any buffered output and syncs it to disk,
+  /// returning what was written to it
+  pub fn flush(&mut self) -> Result<OutputStats>
+  {
+    sync_file(&mut self.output_file)
+  }
+
+  /// Writes into the output file a line with the output fields extracted from
+  /// the given file1 and file2 line values. The fields of a missing line, or
+  /// missing in a line, are written as the `empty` string.
+  ///
+  /// The line is joined in `line`, a buffer reused between calls.
+  pub fn write_fields(
+    output_file: &mut OutputWriter,
+    line: &mut Vec<u8>,
+    output_fields: &Vec<(bool, usize)>,
+    format: &FieldFormat,
+    empty: &String,
+    file1_fields: Option<&Fields>,
+    file2_fields: Option<&Fields>
+  ) -> Result<()>
+  {
+    let values = output_fields.iter()
       .map(
-        |&(file_num, field_num)| -> String
+        |&(file_num, field_num)| -> &[u8]
         {
-          if file_num
+          let fields = if file_num { file1_fields } else { file2_fields };
+          match fields.and_then(|fields| fields.get(field_num))
           {
-            let v = self.file1.field(field_num);
-            if self.verbose {
-              println!("OutputFile::add_match file_num={} [1], field_num={}, v={}", file_num, field_num, v);
-            }
-            return v
-          }
-          else
-          {
-            let v = self.file2.field(field_num);
-            if self.verbose {
-              println!("OutputFile::add_match file_num={} [2], field_num={}, v={}", file_num, field_num, v);
-            }
-            return v
+            Some(value) => value,
+            None => empty.as_bytes()
           }
         }
-      ).collect();
-    self.output_file.write(line.join(self.separator.as_str()).as_bytes()).unwrap();
-    self.output_file.write(b"\n").unwrap();
+      );
+    line.clear();
+    format.join_into(values, line);
+    line.push(b'\n');
+    output_file.write_all(line)?;
+    Ok(())
+  }
+
+  /// Writes into the output file a whole line with the given values, joining
+  /// it in `line`, a buffer reused between calls.
+  pub fn write_line(
+    output_file: &mut OutputWriter,
+    line: &mut Vec<u8>,
+    format: &FieldFormat,
+    fields: &Fields
+  ) -> Result<()>
+  {
+    line.clear();
+    format.join_into(fields.iter(), line);
+    line.push(b'\n');
+    output_file.write_all(line)?;
+    Ok(())
   }
 
   /// Returns whether there is still a line to be processed in file1 or not
@@ -222,7 +501,7 @@ impl OutputFile {
 
   /// Tries to read the next line in file1, changing the current line in the
   /// file1 ByteRangeLineReader to the next.
-  pub fn file1_read_next(&mut self)
+  pub fn file1_read_next(&mut self) -> Result<()>
   {
     if self.verbose {
       println!("OutputFile::file1_read_next()");
@@ -232,7 +511,7 @@ impl OutputFile {
 
   /// Tries to read the next line in file2, changing the current line in the
   /// file2 ByteRangeLineReader to the next.
-  pub fn file2_read_next(&mut self)
+  pub fn file2_read_next(&mut self) -> Result<()>
   {
     if self.verbose {
       println!("OutputFile::file2_read_next()");
@@ -240,31 +519,27 @@ impl OutputFile {
     self.file2.read_next()
   }
 
-  /// Returns a string corresponding with the key field value of the current
-  /// file1 line
-  pub fn file1_key(&self) -> String
+  /// Returns the key field values of the current file1 line
+  pub fn file1_key(&self) -> Result<Vec<&[u8]>>
   {
     self.file1.key()
   }
 
 
-  /// Returns a string corresponding with the key field value of the current
-  /// file2 line
-  pub fn file2_key(&self) -> String
+  /// Returns the key field values of the current file2 line
+  pub fn file2_key(&self) -> Result<Vec<&[u8]>>
   {
     self.file2.key()
   }
 
-  /// Returns a string corresponding with the specified field value of the
-  /// current file1 line
-  pub fn file1_field(&self, i: usize) -> String
+  /// Returns the bytes of the specified field value of the current file1 line
+  pub fn file1_field(&self, i: usize) -> Result<&[u8]>
   {
     self.file1.field(i)
   }
 
-  /// Returns a string corresponding with the specified field value of the
-  /// current file2 line
-  pub fn file2_field(&self, i: usize) -> String
+  /// Returns the bytes of the specified field value of the current file2 line
+  pub fn file2_field(&self, i: usize) -> Result<&[u8]>
   {
     self.file2.field(i)
   }
@@ -273,13 +548,19 @@ impl OutputFile {
 #[cfg(test)]
 mod test
 {
+  use std::path::PathBuf;
   use tempdir::TempDir;
 
   use test_helpers::_assert_file_eq;
   use test_helpers::_write_files;
+  use test_helpers::_format;
 
   use ByteRangeLineReader;
   use OutputFile;
+  use JoinType;
+  use Collation;
+  use create_partial_file;
+  use publish_files;
 
   #[test]
   fn test_files()
@@ -297,99 +578,110 @@ mod test
       /*num_readers*/ 1,
       /*verbose*/ true,
       /*buffer_size*/ 16384
-    );
+    ).unwrap();
 
     let file_2: &str = "1,3,4,";
     let files_2 = _write_files(file_2, &tmp_dir2);
 
     {
       let mut out = OutputFile::new(
-        /*separator*/ String::from(","),
+        /*format*/ _format(","),
         /*verbose*/ true,
-        /*output_file_str*/ output_file_str.clone(),
+        /*output_file*/ create_partial_file(&output_file_str).unwrap(),
         /*output_fields_str_list*/ vec![
           String::from("1.0"),
           String::from("2.0"),
           String::from("2.0")
         ],
-        /*field1*/ 0,
+        /*join_type*/ JoinType::Inner,
+        /*empty*/ String::new(),
+        /*field1*/ vec![0],
         /*file2_str_list*/ files_2,
-        /*field2*/ 0,
-        /*file1_range*/ file_1_ranges[0].clone(),
+        /*field2*/ vec![0],
+        /*collation*/ Collation::Bytewise,
+        /*file1_range*/ file_1_ranges[0].clone().unwrap(),
         /*start_pos*/ 0,
         /*buffer_size*/ 16384
-      );
+      ).unwrap();
 
       assert_eq!(out.file1_has_current(), true);
       assert_eq!(out.file2_has_current(), true);
 
-      assert_eq!(out.file1_key(), String::from(""));
-      assert_eq!(out.file2_key(), String::from(""));
+      assert_eq!(out.file1_key().unwrap(), vec![b"".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec![b"".to_vec()]);
 
-      assert_eq!(out.file1_field(0), String::from(""));
-      assert_eq!(out.file2_field(0), String::from(""));
-      out.file2_read_next();
+      assert_eq!(out.file1_field(0).unwrap(), b"".to_vec());
+      assert_eq!(out.file2_field(0).unwrap(), b"".to_vec());
+      out.file2_read_next().unwrap();
 
-      assert_eq!(out.file1_key(), String::from(""));
-      assert_eq!(out.file2_key(), String::from("1"));
-      assert_eq!(out.file1_field(0), String::from(""));
-      assert_eq!(out.file2_field(0), String::from("1"));
+      assert_eq!(out.file1_key().unwrap(), vec![b"".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec![b"1".to_vec()]);
+      assert_eq!(out.file1_field(0).unwrap(), b"".to_vec());
+      assert_eq!(out.file2_field(0).unwrap(), b"1".to_vec());
 
-      out.add_match(); // adds ",1,1" to output file
+      out.add_match().unwrap(); // adds ",1,1" to output file
 
-      out.file1_read_next();
-      out.file2_read_next();
+      out.file1_read_next().unwrap();
+      out.file2_read_next().unwrap();
 
-      assert_eq!(out.file1_key(), String::from("0"));
-      assert_eq!(out.file2_key(), String::from("3"));
-      assert_eq!(out.file1_field(0), String::from("0"));
-      assert_eq!(out.file2_field(0), String::from("3"));
+      assert_eq!(out.file1_key().unwrap(), vec![b"0".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec![b"3".to_vec()]);
+      assert_eq!(out.file1_field(0).unwrap(), b"0".to_vec());
+      assert_eq!(out.file2_field(0).unwrap(), b"3".to_vec());
 
-      out.add_match(); // adds "0,3,3" to output file
-      out.file1_read_next();
+      out.add_match().unwrap(); // adds "0,3,3" to output file
+      out.file1_read_next().unwrap();
 
-      assert_eq!(out.file1_key(), String::from("4"));
-      assert_eq!(out.file2_key(), String::from("3"));
-      assert_eq!(out.file1_field(0), String::from("4"));
-      assert_eq!(out.file2_field(0), String::from("3"));
+      assert_eq!(out.file1_key().unwrap(), vec![b"4".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec![b"3".to_vec()]);
+      assert_eq!(out.file1_field(0).unwrap(), b"4".to_vec());
+      assert_eq!(out.file2_field(0).unwrap(), b"3".to_vec());
 
-      out.add_match(); // adds "4,3,3" to output file
+      out.add_match().unwrap(); // adds "4,3,3" to output file
 
-      out.file2_read_next();
+      out.file2_read_next().unwrap();
 
-      assert_eq!(out.file1_key(), String::from("4"));
-      assert_eq!(out.file2_key(), String::from("4"));
-      assert_eq!(out.file1_field(0), String::from("4"));
-      assert_eq!(out.file2_field(0), String::from("4"));
+      assert_eq!(out.file1_key().unwrap(), vec![b"4".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec![b"4".to_vec()]);
+      assert_eq!(out.file1_field(0).unwrap(), b"4".to_vec());
+      assert_eq!(out.file2_field(0).unwrap(), b"4".to_vec());
 
-      out.file1_read_next();
+      out.file1_read_next().unwrap();
       assert_eq!(out.file1_has_current(), true);
       assert_eq!(out.file2_has_current(), true);
 
-      assert_eq!(out.file1_key(), String::from("5"));
-      assert_eq!(out.file2_key(), String::from("4"));
-      assert_eq!(out.file1_field(0), String::from("5"));
-      assert_eq!(out.file2_field(0), String::from("4"));
+      assert_eq!(out.file1_key().unwrap(), vec![b"5".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec![b"4".to_vec()]);
+      assert_eq!(out.file1_field(0).unwrap(), b"5".to_vec());
+      assert_eq!(out.file2_field(0).unwrap(), b"4".to_vec());
 
-      out.file1_read_next();
+      out.file1_read_next().unwrap();
       assert_eq!(out.file1_has_current(), false);
       assert_eq!(out.file2_has_current(), true);
 
-      assert_eq!(out.file1_key(), String::from(""));
-      assert_eq!(out.file2_key(), String::from("4"));
-      assert_eq!(out.file1_field(0), String::from(""));
-      assert_eq!(out.file2_field(0), String::from("4"));
+      assert_eq!(out.file1_key().unwrap(), vec![b"".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec![b"4".to_vec()]);
+      assert_eq!(out.file1_field(0).unwrap(), b"".to_vec());
+      assert_eq!(out.file2_field(0).unwrap(), b"4".to_vec());
 
-      out.file2_read_next();
+      // the empty line at the end of file2 is read too, as the last file1
+      // range extends until the end of file2
+      out.file2_read_next().unwrap();
+      assert_eq!(out.file1_has_current(), false);
+      assert_eq!(out.file2_has_current(), true);
+      assert_eq!(out.file2_key().unwrap(), vec![b"".to_vec()]);
+
+      out.file2_read_next().unwrap();
       assert_eq!(out.file1_has_current(), false);
       assert_eq!(out.file2_has_current(), false);
 
-      assert_eq!(out.file1_key(), String::from(""));
-      assert_eq!(out.file2_key(), String::from(""));
-      assert_eq!(out.file1_field(0), String::from(""));
-      assert_eq!(out.file2_field(0), String::from(""));
+      assert_eq!(out.file1_key().unwrap(), vec![b"".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec![b"".to_vec()]);
+      assert_eq!(out.file1_field(0).unwrap(), b"".to_vec());
+      assert_eq!(out.file2_field(0).unwrap(), b"".to_vec());
     }
 
+    publish_files(&vec![PathBuf::from(&output_file_str)]).unwrap();
     _assert_file_eq(
       &output_file_str,
       ",1,1\n0,3,3\n4,3,3\n"
@@ -412,72 +704,75 @@ mod test
       /*num_readers*/ 1,
       /*verbose*/ true,
       /*buffer_size*/ 16384
-    );
+    ).unwrap();
 
     let file_2: &str = "1;aaa;!!!#↓,3;lol;4";
     let files_2 = _write_files(file_2, &tmp_dir2);
 
     {
       let mut out = OutputFile::new(
-        /*separator*/ String::from(";"),
+        /*format*/ _format(";"),
         /*verbose*/ true,
-        /*output_file_str*/ output_file_str.clone(),
+        /*output_file*/ create_partial_file(&output_file_str).unwrap(),
         /*output_fields_str_list*/ vec![
           String::from("1.2"),
           String::from("1.1"),
           String::from("2.0"),
         ],
-        /*field1*/ 1,
+        /*join_type*/ JoinType::Inner,
+        /*empty*/ String::new(),
+        /*field1*/ vec![1],
         /*file2_str_list*/ files_2,
-        /*field2*/ 2,
-        /*file1_range*/ file_1_ranges[0].clone(),
+        /*field2*/ vec![2],
+        /*collation*/ Collation::Bytewise,
+        /*file1_range*/ file_1_ranges[0].clone().unwrap(),
         /*start_pos*/ 0,
         /*buffer_size*/ 16384
-      );
+      ).unwrap();
 
-      out.file1_read_next();
-      out.file2_read_next();
+      out.file1_read_next().unwrap();
+      out.file2_read_next().unwrap();
       assert_eq!(out.file1_has_current(), true);
       assert_eq!(out.file2_has_current(), true);
 
-      assert_eq!(out.file1_key(), String::from("bbbbb"));
-      assert_eq!(out.file2_key(), String::from("!!!#↓"));
+      assert_eq!(out.file1_key().unwrap(), vec![b"bbbbb".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec!["!!!#↓".as_bytes().to_vec()]);
 
-      assert_eq!(out.file1_field(0), String::from("111"));
-      assert_eq!(out.file1_field(1), String::from("bbbbb"));
-      assert_eq!(out.file1_field(2), String::from("ccc"));
+      assert_eq!(out.file1_field(0).unwrap(), b"111".to_vec());
+      assert_eq!(out.file1_field(1).unwrap(), b"bbbbb".to_vec());
+      assert_eq!(out.file1_field(2).unwrap(), b"ccc".to_vec());
 
-      assert_eq!(out.file2_field(0), String::from("1"));
-      assert_eq!(out.file2_field(1), String::from("aaa"));
-      assert_eq!(out.file2_field(2), String::from("!!!#↓"));
+      assert_eq!(out.file2_field(0).unwrap(), b"1".to_vec());
+      assert_eq!(out.file2_field(1).unwrap(), b"aaa".to_vec());
+      assert_eq!(out.file2_field(2).unwrap(), "!!!#↓".as_bytes().to_vec());
 
-      out.file1_read_next();
+      out.file1_read_next().unwrap();
 
-      assert_eq!(out.file1_key(), String::from("5"));
-      assert_eq!(out.file2_key(), String::from("!!!#↓"));
+      assert_eq!(out.file1_key().unwrap(), vec![b"5".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec!["!!!#↓".as_bytes().to_vec()]);
 
-      assert_eq!(out.file1_field(0), String::from("2222222"));
-      assert_eq!(out.file1_field(1), String::from("5"));
-      assert_eq!(out.file1_field(2), String::from("767u"));
-      assert_eq!(out.file1_field(3), String::from("oo"));
+      assert_eq!(out.file1_field(0).unwrap(), b"2222222".to_vec());
+      assert_eq!(out.file1_field(1).unwrap(), b"5".to_vec());
+      assert_eq!(out.file1_field(2).unwrap(), b"767u".to_vec());
+      assert_eq!(out.file1_field(3).unwrap(), b"oo".to_vec());
 
-      assert_eq!(out.file2_field(0), String::from("1"));
-      assert_eq!(out.file2_field(1), String::from("aaa"));
-      assert_eq!(out.file2_field(2), String::from("!!!#↓"));
+      assert_eq!(out.file2_field(0).unwrap(), b"1".to_vec());
+      assert_eq!(out.file2_field(1).unwrap(), b"aaa".to_vec());
+      assert_eq!(out.file2_field(2).unwrap(), "!!!#↓".as_bytes().to_vec());
 
-      out.file2_read_next();
+      out.file2_read_next().unwrap();
 
-      assert_eq!(out.file1_key(), String::from("5"));
-      assert_eq!(out.file2_key(), String::from("4"));
+      assert_eq!(out.file1_key().unwrap(), vec![b"5".to_vec()]);
+      assert_eq!(out.file2_key().unwrap(), vec![b"4".to_vec()]);
 
-      assert_eq!(out.file1_field(0), String::from("2222222"));
-      assert_eq!(out.file1_field(1), String::from("5"));
-      assert_eq!(out.file1_field(2), String::from("767u"));
-      assert_eq!(out.file1_field(3), String::from("oo"));
+      assert_eq!(out.file1_field(0).unwrap(), b"2222222".to_vec());
+      assert_eq!(out.file1_field(1).unwrap(), b"5".to_vec());
+      assert_eq!(out.file1_field(2).unwrap(), b"767u".to_vec());
+      assert_eq!(out.file1_field(3).unwrap(), b"oo".to_vec());
 
-      assert_eq!(out.file2_field(0), String::from("3"));
-      assert_eq!(out.file2_field(1), String::from("lol"));
-      assert_eq!(out.file2_field(2), String::from("4"));
+      assert_eq!(out.file2_field(0).unwrap(), b"3".to_vec());
+      assert_eq!(out.file2_field(1).unwrap(), b"lol".to_vec());
+      assert_eq!(out.file2_field(2).unwrap(), b"4".to_vec());
     }
   }
 
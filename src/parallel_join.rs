@@ -14,63 +14,172 @@
  * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
 **/
 
+use std::cmp::Ordering;
 use std::thread;
 use std::time::Instant;
 use std::path::Path;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
+use std::io::prelude::*;
 
 use OutputFile;
 use ByteRangeLineReader;
+use MultiFileReader;
+use JoinType;
+use Collation;
+use Error;
+use Result;
+use PartitionError;
+use error::join_worker;
+use error::check_workers;
+use error::check_jobs;
+use publish::OutputStats;
+use publish::STDOUT_PATH;
+use publish::create_partial_file;
+use publish::sync_file;
+use publish::stdout_writer;
+use publish::stream_writer;
+use publish::publish_outputs;
+use source::ToSource;
+use FieldFormat;
+use CsvDialect;
+
+/// Returns an InvalidArgument error unless both files have the same number of
+/// key fields, and at least one: keys with a different number of values are
+/// never equal, so nothing would be joined.
+pub fn check_key_fields(field1: &Vec<usize>, field2: &Vec<usize>) -> Result<()>
+{
+  if field1.is_empty() || field1.len() != field2.len()
+  {
+    return Err(Error::InvalidArgument(format!(
+      "the key fields {:?} and {:?} of both files must have the same length",
+      field1, field2
+    )))
+  }
+  Ok(())
+}
 
 // Executes a skew partition parallel join algorithm
 //
-// 1. Divide the A file in N ranges (one per job/thread)
+// 1. Divide the A file in N ranges (one per job/thread), so that all the lines
+//    with the same key are in the same range
 // 2. for each range:
 // 2.1. find the range in B
 // 2.2. spawn a thread with the batch
 // 2.3. each thread merge joins
-pub fn execute_parallel_join(
-  file1_str_list: &Vec<String>,
-  file2_str_list: &Vec<String>,
+//
+// Both files must be sorted by their join fields in the order defined by the
+// `collation`, which is used in all the key comparisons.
+//
+// The fields of each line are separated by `separator`. If given a `csv`
+// dialect, the files are CSV files: the lines are their records, which might
+// have quoted fields with separators and new lines, and the output fields are
+// quoted when needed.
+//
+// When a key is repeated in both files, all the combinations of the lines with
+// that key are written, like GNU join does. Depending on the `join_type`, the
+// unpaired lines of each file are also written, using `empty` for the missing
+// output fields. Anti-joins and semi-joins write whole lines of only one of
+// the files instead.
+//
+// If any thread fails, returns an Error::Workers with the byte ranges of file1
+// and file2 of each failed partition, once all the threads have finished.
+// Otherwise the output files, written to partial files until then, are
+// published all at once, and what was written to each of them is returned in
+// order.
+//
+// If `output_path` is STDOUT_PATH, the output is written to stdout in order
+// instead: each partition is written once all the partitions before it have
+// been, and the threads of the partitions after it block once they have
+// STREAM_BUFFER_CHUNKS chunks waiting. Then only what was written to stdout
+// is returned. After any failure nothing else is written to stdout, and the
+// threads still writing fail too.
+//
+// The SHA-256 digest of each output file, or of what was written to stdout,
+// is only computed if `sha256` is true, see OutputWriter::set_sha256.
+pub fn execute_parallel_join<S: ToSource>(
+  file1_str_list: &Vec<S>,
+  file2_str_list: &Vec<S>,
   separator: &String,
-  field1: u32,
-  field2: u32,
+  csv: Option<CsvDialect>,
+  field1: &Vec<usize>,
+  field2: &Vec<usize>,
+  collation: Collation,
   output_fields_str_list: &Vec<String>,
+  join_type: JoinType,
+  empty: &String,
   output_path: &String,
+  sha256: bool,
   verbose: bool,
   njobs: i32,
   buffer_size: u32
-)
+) -> Result<Vec<OutputStats>>
 {
-  let a_ranges = ByteRangeLineReader::open(&file1_str_list, njobs as u64, verbose, buffer_size);
+  check_jobs(njobs)?;
+  check_key_fields(field1, field2)?;
+  let format = FieldFormat::new(separator, csv)?;
+  let a_ranges = ByteRangeLineReader::open_by_key(
+    &format.sources(file1_str_list),
+    njobs as u64,
+    &format,
+    field1,
+    collation,
+    verbose,
+    buffer_size
+  )?;
+  let file2_str_list = format.sources(file2_str_list);
+  let file2_len = MultiFileReader::len(&file2_str_list)?;
 
   let mut children = vec![];
+  // byte range of file1 and start of file2 of each partition, to report them
+  // if the thread fails
+  let mut ranges1: Vec<(u64, u64)> = vec![];
+  let mut starts2: Vec<u64> = vec![];
+  // the receiving end of the output of each thread, when writing to stdout
+  let streaming = output_path == STDOUT_PATH;
+  let mut receivers: Vec<Option<Receiver<Vec<u8>>>> = vec![];
 
   let start = Instant::now();
 
-  let mut ends: Vec<(Sender<u64>, Receiver<u64>)> = Vec::with_capacity(njobs as usize + 1);
-  ends.push(mpsc::channel());
-  ends[0].0.send(0).unwrap();
+  // each thread sends the end of its file2 range to the next one, which uses
+  // it as its start. If a thread fails before that, no more threads are
+  // spawned
+  let (first_tx, mut start_rx): (Sender<u64>, Receiver<u64>) = mpsc::channel();
+  first_tx.send(0).unwrap();
 
-  for (thread_num, a_range) in a_ranges.iter().enumerate()
+  for (thread_num, a_range) in a_ranges.into_iter().enumerate()
   {
     let verbose = verbose.clone();
-    let separator = separator.clone();
+    let format = format.clone();
     let output_path = output_path.clone();
     let output_fields_str_list = output_fields_str_list.clone();
+    let empty = empty.clone();
     let field1 = field1.clone();
     let file2_str_list = file2_str_list.clone();
     let field2 = field2.clone();
     let start = start.clone();
 
-    ends.push(mpsc::channel());
-
-    let start_pos = ends[thread_num].1.recv().unwrap();
-    let next_tx = ends[thread_num+1].0.clone();
-    let a_range = a_range.clone();
+    let start_pos = match start_rx.recv()
+    {
+      Ok(start_pos) => start_pos,
+      Err(_) => break
+    };
+    let (next_tx, next_rx): (Sender<u64>, Receiver<u64>) = mpsc::channel();
+    start_rx = next_rx;
+    ranges1.push((a_range.pos(), a_range.end()));
+    starts2.push(start_pos);
+    let stream_out = match streaming
+    {
+      true =>
+      {
+        let (stream_out, receiver) = stream_writer();
+        receivers.push(Some(receiver));
+        Some(stream_out)
+      },
+      false => None
+    };
 
-    children.push( thread::spawn(move ||
+    children.push( thread::spawn(move || -> Result<OutputStats>
     {
       let path = String::from(
         Path::new(&output_path).join(thread_num.to_string()).to_str().unwrap()
@@ -78,22 +187,32 @@ pub fn execute_parallel_join(
       if verbose {
         println!("thread {}: output path: {}", thread_num, path);
       }
+      let mut output_file = match stream_out
+      {
+        Some(stream_out) => stream_out,
+        None => create_partial_file(&path)?
+      };
+      output_file.set_csv(format.csv());
+      output_file.set_sha256(sha256);
 
       let mut out = OutputFile::new(
-        separator,
+        format,
         verbose,
-        path,
+        output_file,
         output_fields_str_list,
+        join_type,
+        empty,
         field1,
         file2_str_list,
         field2,
+        collation,
         a_range,
         start_pos,
         buffer_size
-      );
-      next_tx.send(out.file2_end()).unwrap();
-      out.file1_read_next();
-      out.file2_read_next();
+      )?;
+      let _ = next_tx.send(out.file2_end());
+      out.file1_read_next()?;
+      out.file2_read_next()?;
 
       if thread_num as i32 == njobs -1 && verbose {
         println!("thread={} elapsed={}s {}ns", thread_num, start.elapsed().as_secs(), start.elapsed().subsec_nanos());
@@ -101,59 +220,142 @@ pub fn execute_parallel_join(
 
       while out.file1_has_current() && out.file2_has_current()
       {
-        let key1 = out.file1_key();
-        let key2 = out.file2_key();
         if verbose {
-          println!("thread {} key1: {} key2: {}", thread_num, key1, key2);
+          println!("thread {} key1: {:?} key2: {:?}", thread_num, out.file1_key()?, out.file2_key()?);
         }
-
-        match (Some(key1), Some(key2))
+        match out.compare_keys()?
         {
-          (Some(ref key1), Some(ref key2)) if key1 < key2 => {
-            out.file1_read_next();
+          Ordering::Less => {
+            if join_type.unpaired1() {
+              out.add_unpaired1()?;
+            }
+            out.file1_read_next()?;
           },
-          (Some(ref key1), Some(ref key2)) if key1 == key2 => {
-            out.add_match();
-            out.file1_read_next();
-            out.file2_read_next();
+          Ordering::Equal => {
+            // a key might be repeated in both files, so we match each of the
+            // file1 lines with that key with all the file2 lines with it
+            if join_type.matches() {
+              out.buffer_file2_run()?;
+            } else {
+              out.skip_file2_run()?;
+            }
+            while out.file1_has_current() && out.file1_in_run()?
+            {
+              if join_type.matches() {
+                out.add_run_matches()?;
+              } else if join_type == JoinType::Semi {
+                out.add_file1_line()?;
+              }
+              out.file1_read_next()?;
+            }
           },
-          (Some(ref key1), Some(ref key2)) if key1 > key2 => {
-            out.file2_read_next();
+          Ordering::Greater => {
+            if join_type.unpaired2() {
+              out.add_unpaired2()?;
+            }
+            out.file2_read_next()?;
           },
-          _=> { break },
         }
       }
+
+      // the lines left in any of the files have no match
+      while join_type.unpaired1() && out.file1_has_current()
+      {
+        out.add_unpaired1()?;
+        out.file1_read_next()?;
+      }
+      while join_type.unpaired2() && out.file2_has_current()
+      {
+        out.add_unpaired2()?;
+        out.file2_read_next()?;
+      }
+      let output = out.flush()?;
       if thread_num as i32 == njobs -1 && verbose {
         println!("thread={} END elapsed={}s {}ns", thread_num, start.elapsed().as_secs(), start.elapsed().subsec_nanos());
       }
+      Ok(output)
     }));
   }
 
-  let mut i: i32 = 0;
+  // the end of the file2 range of the last thread
+  if let Ok(end_pos) = start_rx.recv()
+  {
+    starts2.push(end_pos);
+  }
+
+  let mut outputs: Vec<OutputStats> = vec![];
+  let mut failures: Vec<PartitionError> = vec![];
+  let mut stdout = stdout_writer();
+  stdout.set_sha256(sha256);
+  let mut stdout_error: Option<Error> = None;
+  let mut i: usize = 0;
   for child in children {
     // Wait for the thread to finish. Returns a result.
     if verbose {
       println!("{} secs \tbase_thread: finishing thread {}", start.elapsed().as_secs(), i);
     }
 
-    let _ = child.join();
+    // the partition is written to stdout while the thread writes it, until the
+    // thread finishes. After a failure the receiver is just dropped, so that
+    // the thread fails on its next write instead of blocking
+    if let Some(receiver) = receivers.get_mut(i).and_then(|receiver| receiver.take())
+    {
+      if failures.is_empty() && stdout_error.is_none()
+      {
+        for chunk in receiver.iter()
+        {
+          if let Err(error) = stdout.write_all(&chunk)
+          {
+            stdout_error = Some(Error::from(error));
+            break;
+          }
+        }
+      }
+    }
+
+    let range2 = (starts2[i], *starts2.get(i + 1).unwrap_or(&file2_len));
+    if let Some(output) = join_worker(child, i, vec![ranges1[i], range2], &mut failures)
+    {
+      outputs.push(output);
+    }
 
     if verbose {
       println!("{} secs \tbase_thread: ... finished thread {}", start.elapsed().as_secs(), i);
     }
     i = i + 1;
   }
+  if let Some(error) = stdout_error
+  {
+    return Err(error)
+  }
+  check_workers(failures)?;
+  if streaming
+  {
+    return Ok(vec![sync_file(&mut stdout)?])
+  }
+  publish_outputs(outputs)
 }
 
 #[cfg(test)]
 mod test
 {
+  use std::fs;
+  use std::sync::Arc;
   use tempdir::TempDir;
 
   use test_helpers::_write_files;
+  use test_helpers::_memory_files;
   use test_helpers::_assert_files_eq;
 
   use execute_parallel_join;
+  use JoinType;
+  use Collation;
+  use CsvDialect;
+  use MemoryFile;
+  use source::Source;
+  use Error;
+  use Result;
+  use OutputStats;
 
   #[test]
   fn test_join1()
@@ -162,8 +364,8 @@ mod test
       file1_str: &'static str,
       file2_str: &'static str,
       separator: &'static str,
-      field1: u32,
-      field2: u32,
+      field1: Vec<usize>,
+      field2: Vec<usize>,
       output_fields_str_list: &'static str,
       output_str: &'static str,
       njobs: i32
@@ -174,8 +376,8 @@ mod test
         file1_str: "1,2,3,4",
         file2_str: "1,2,4,5,6",
         separator: ",",
-        field1: 0,
-        field2: 0,
+        field1: vec![0],
+        field2: vec![0],
         output_fields_str_list: "1.0,1.0",
         output_str: "1,1\n2,2\n4,4\n",
         njobs: 1
@@ -184,8 +386,8 @@ mod test
         file1_str: "1;aa,2;bb,3;cc,4;dd",
         file2_str: "1;AAAAAA,2;BBBBBBBB,4;CCCCC,5;DD,6;EEEEE",
         separator: ";",
-        field1: 0,
-        field2: 0,
+        field1: vec![0],
+        field2: vec![0],
         output_fields_str_list: "1.1,2.1",
         output_str: "aa;AAAAAA\nbb;BBBBBBBB\ndd;CCCCC\n",
         njobs: 1
@@ -194,8 +396,8 @@ mod test
         file1_str: "1;aa,2;bb,3;cc,4;dd",
         file2_str: "1;aa;AAAA,2;BBBBBBBB;42,4;cc;CCC,5;DD;ddd,6;EEEEE;",
         separator: ";",
-        field1: 1,
-        field2: 1,
+        field1: vec![1],
+        field2: vec![1],
         output_fields_str_list: "1.0,1.1,2.1,2.2",
         output_str: "1;aa;aa;AAAA\n3;cc;cc;CCC\n",
         njobs: 1
@@ -204,8 +406,8 @@ mod test
         file1_str: "1,2|3,4",
         file2_str: "1,2,4|5,6",
         separator: ",",
-        field1: 0,
-        field2: 0,
+        field1: vec![0],
+        field2: vec![0],
         output_fields_str_list: "1.0,1.0",
         output_str: "1,1\n2,2\n4,4\n",
         njobs: 1
@@ -214,8 +416,8 @@ mod test
         file1_str: "0,1,2,3,4,5,6,7,8,9",
         file2_str: "0,1,2,3,4,5,6,7,8,9",
         separator: ",",
-        field1: 0,
-        field2: 0,
+        field1: vec![0],
+        field2: vec![0],
         output_fields_str_list: "1.0",
         output_str: "0\n1\n2\n3\n4\n5\n|6\n7\n8\n9\n",
         njobs: 2
@@ -224,8 +426,8 @@ mod test
         file1_str: "0,1,2,3,4,5,6,7,8,9",
         file2_str: "0,1,2,3,4,5,6,7,9",
         separator: ",",
-        field1: 0,
-        field2: 0,
+        field1: vec![0],
+        field2: vec![0],
         output_fields_str_list: "1.0",
         output_str: "0\n1\n2\n3\n4\n5\n|6\n7\n9\n",
         njobs: 2
@@ -234,8 +436,8 @@ mod test
         file1_str: "0,1,2,3,4,5,6,7,8,9",
         file2_str: "5,6,7,8,9",
         separator: ",",
-        field1: 0,
-        field2: 0,
+        field1: vec![0],
+        field2: vec![0],
         output_fields_str_list: "1.0",
         output_str: "5\n|6\n7\n8\n9\n",
         njobs: 2
@@ -244,12 +446,64 @@ mod test
         file1_str: "0,1,2,3,4,5,6,7,8,9",
         file2_str: "6,7,8,9",
         separator: ",",
-        field1: 0,
-        field2: 0,
+        field1: vec![0],
+        field2: vec![0],
         output_fields_str_list: "1.0",
         output_str: "|6\n7\n8\n9\n",
         njobs: 2
       },
+      Data {
+        file1_str: "1;a;x,2;a;z,1;b;y,2;b;w",
+        file2_str: "a;1;X,a;2;Z,b;1;Y,b;2;W,b;2;V",
+        separator: ";",
+        field1: vec![1, 0],
+        field2: vec![0, 1],
+        output_fields_str_list: "1.2,2.2",
+        output_str: "x;X\nz;Z\ny;Y\nw;W\nw;V\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "1;a,1;b,2;c,3;d",
+        file2_str: "1;A,1;B,1;C,3;D",
+        separator: ";",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.1,2.1",
+        output_str: "a;A\na;B\na;C\nb;A\nb;B\nb;C\nd;D\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "0,1,1,1,1,1,2,3",
+        file2_str: "1,1,3",
+        separator: ",",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0,2.0",
+        output_str: "1,1\n1,1\n1,1\n1,1\n1,1\n1,1\n1,1\n1,1\n1,1\n1,1\n|3,3\n",
+        njobs: 2
+      },
+      Data {
+        // multi-byte separator, not | as _write_files splits files at it
+        file1_str: "1##a#b,2##c,3##d,4##e##f",
+        file2_str: "1##A,2##B#,4##C,5##D",
+        separator: "##",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0,1.1,2.1",
+        output_str: "1##a#b##A\n2##c##B#\n|4##e##C\n",
+        njobs: 2
+      },
+      Data {
+        // separated by runs of blanks
+        file1_str: "  1 a,2\t\tb  ,3 c,4  d d",
+        file2_str: "1\tA, 2 B,4 C,5 D",
+        separator: "",
+        field1: vec![0],
+        field2: vec![0],
+        output_fields_str_list: "1.0,1.1,2.1,1.2",
+        output_str: "1 a A \n2 b B \n|4 d C d\n",
+        njobs: 2
+      },
     ];
     for ref s in l.iter()
     {
@@ -269,15 +523,496 @@ mod test
         &files_1,
         &files_2,
         &separator,
-        s.field1,
-        s.field2,
+        None,
+        &s.field1,
+        &s.field2,
+        Collation::Bytewise,
         &output_fields_str_list,
+        JoinType::Inner,
+        &String::new(),
         &tmp_dir_out_path,
+        /*sha256*/ false,
         false,
         s.njobs,
         /*buffer_size*/ 16384
-      );
+      ).unwrap();
+      _assert_files_eq(&tmp_dir_out_path, s.output_str);
+    }
+  }
+
+  #[test]
+  fn test_join_memory_files()
+  {
+    let tmp_dir_out = TempDir::new("parallel_join_out").expect("create temp dir");
+    let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+    let files_1 = _memory_files("0;a,1;b,2;c|3;d,4;e,5;f");
+    let files_2 = _memory_files("1;B,2;C,3;D|5;F");
+
+    execute_parallel_join(
+      &files_1,
+      &files_2,
+      &String::from(";"),
+      None,
+      &vec![0],
+      &vec![0],
+      Collation::Bytewise,
+      &vec![String::from("1.1"), String::from("2.1")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      false,
+      /*njobs*/ 2,
+      /*buffer_size*/ 16384
+    ).unwrap();
+    _assert_files_eq(&tmp_dir_out_path, "b;B\nc;C\nd;D\n|f;F\n");
+  }
+
+  #[test]
+  fn test_join_last_line_without_new_line()
+  {
+    // the last line of a file might not end with a new line, also in the
+    // middle of an input made of several files
+    let memory_file = |name: &str, data: &[u8]| -> Arc<dyn Source>
+    {
+      Arc::new(MemoryFile::new(name, data.to_vec()))
+    };
+    let files_1 = vec![memory_file("0", b"1;a\n2;b"), memory_file("1", b"3;c")];
+    let files_2 = vec![memory_file("0", b"1;A\n2;B\n3;C")];
+    for njobs in 1..3
+    {
+      let tmp_dir_out = TempDir::new("parallel_join_out").expect("create temp dir");
+      let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+      let outputs = execute_parallel_join(
+        &files_1,
+        &files_2,
+        &String::from(";"),
+        None,
+        &vec![0],
+        &vec![0],
+        Collation::Bytewise,
+        &vec![String::from("1.0"), String::from("1.1"), String::from("2.1")],
+        JoinType::Inner,
+        &String::new(),
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        false,
+        njobs,
+        /*buffer_size*/ 16384
+      ).unwrap();
+      let mut output: Vec<u8> = vec![];
+      for output_stats in outputs.iter()
+      {
+        output.extend(fs::read(&output_stats.path).unwrap());
+      }
+      assert_eq!(String::from_utf8(output).unwrap(), "1;a;A\n2;b;B\n3;c;C\n");
+    }
+  }
+
+  #[test]
+  fn test_join_bytes()
+  {
+    let tmp_dir_out = TempDir::new("parallel_join_out").expect("create temp dir");
+    let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+    // Latin-1 text, which is not valid UTF-8, is joined and written verbatim
+    let files_1: Vec<Arc<dyn Source>> = vec![Arc::new(MemoryFile::new(
+      "0", b"caf\xe9;1\nna\xefve;2\n\xff;3\n".to_vec()
+    ))];
+    let files_2: Vec<Arc<dyn Source>> = vec![Arc::new(MemoryFile::new(
+      "0", b"caf\xe9;\xe0\nna\xefve;b\n".to_vec()
+    ))];
+
+    execute_parallel_join(
+      &files_1,
+      &files_2,
+      &String::from(";"),
+      None,
+      &vec![0],
+      &vec![0],
+      Collation::Bytewise,
+      &vec![String::from("1.1"), String::from("2.1"), String::from("1.0")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      false,
+      /*njobs*/ 2,
+      /*buffer_size*/ 16384
+    ).unwrap();
+    let mut output: Vec<u8> = vec![];
+    for i in 0..2
+    {
+      let path = tmp_dir_out.path().join(i.to_string());
+      output.extend(fs::read(path).unwrap());
+    }
+    assert_eq!(output, b"1;\xe0;caf\xe9\n2;b;na\xefve\n".to_vec());
+  }
+
+  #[test]
+  fn test_join_csv()
+  {
+    let tmp_dir_out = TempDir::new("parallel_join_out").expect("create temp dir");
+    let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+    // the byte ranges of the threads start inside quoted fields
+    let files_1 = _memory_files(
+      "1;\"a\n2;x\n3;y\",2;\"b;\"\"b\"\"\n\n\",3;\"c\nc\nc\nc\nc\nc\",4;d"
+    );
+    let files_2 = _memory_files("\"1\";A,3;\"C\nC\"");
+
+    execute_parallel_join(
+      &files_1,
+      &files_2,
+      &String::from(";"),
+      Some(CsvDialect::rfc4180()),
+      &vec![0],
+      &vec![0],
+      Collation::Bytewise,
+      &vec![String::from("1.1"), String::from("2.1")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      false,
+      /*njobs*/ 3,
+      /*buffer_size*/ 16384
+    ).unwrap();
+    _assert_files_eq(&tmp_dir_out_path, "\"a\n2;x\n3;y\";A\n|\"c\nc\nc\nc\nc\nc\";\"C\nC\"\n|");
+  }
+
+  #[test]
+  fn test_outer_join()
+  {
+    struct Data {
+      file1_str: &'static str,
+      file2_str: &'static str,
+      join_type: JoinType,
+      empty: &'static str,
+      output_str: &'static str,
+      njobs: i32
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        file1_str: "1;a,2;b,4;d",
+        file2_str: "0;Z,2;B,3;C,4;D,5;E",
+        join_type: JoinType::LeftOuter,
+        empty: "-",
+        output_str: "1;a;-\n2;b;B\n4;d;D\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "1;a,2;b,4;d",
+        file2_str: "0;Z,2;B,3;C,4;D,5;E",
+        join_type: JoinType::RightOuter,
+        empty: "",
+        output_str: ";;Z\n2;b;B\n;;C\n4;d;D\n;;E\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "1;a,2;b,4;d,6;f",
+        file2_str: "0;Z,2;B,3;C,4;D,5;E",
+        join_type: JoinType::FullOuter,
+        empty: "-",
+        output_str: "-;-;Z\n1;a;-\n2;b;B\n-;-;C\n4;d;D\n-;-;E\n6;f;-\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "1;a,2;b,2;c,4;d,6;f,7;g",
+        file2_str: "0;Z,2;B,3;C,4;D,5;E,8;H,9;I",
+        join_type: JoinType::FullOuter,
+        empty: "-",
+        output_str: "-;-;Z\n1;a;-\n2;b;B\n2;c;B\n-;-;C\n4;d;D\n|-;-;E\n6;f;-\n7;g;-\n-;-;H\n-;-;I\n",
+        njobs: 2
+      },
+    ];
+    for ref s in l.iter()
+    {
+      let tmp_dir_1 = TempDir::new("parallel_join_1").expect("create temp dir");
+      let tmp_dir_2 = TempDir::new("parallel_join_2").expect("create temp dir");
+      let tmp_dir_out = TempDir::new("parallel_join_out").expect("create temp dir");
+
+      let files_1 = _write_files(s.file1_str, &tmp_dir_1);
+      let files_2 = _write_files(s.file2_str, &tmp_dir_2);
+      let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+      let output_fields_str_list: Vec<String> = vec![
+        String::from("1.0"),
+        String::from("1.1"),
+        String::from("2.1")
+      ];
+
+      execute_parallel_join(
+        &files_1,
+        &files_2,
+        &String::from(";"),
+        None,
+        &vec![0],
+        &vec![0],
+        Collation::Bytewise,
+        &output_fields_str_list,
+        s.join_type,
+        &String::from(s.empty),
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        false,
+        s.njobs,
+        /*buffer_size*/ 16384
+      ).unwrap();
+      _assert_files_eq(&tmp_dir_out_path, s.output_str);
+    }
+  }
+
+  #[test]
+  fn test_anti_semi_join()
+  {
+    struct Data {
+      file1_str: &'static str,
+      file2_str: &'static str,
+      join_type: JoinType,
+      output_str: &'static str,
+      njobs: i32
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        file1_str: "1;a,2;b,2;c,4;d",
+        file2_str: "0;Z,2;B,2;BB,3;C",
+        join_type: JoinType::LeftAnti,
+        output_str: "1;a\n4;d\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "1;a,2;b,2;c,4;d",
+        file2_str: "0;Z,2;B,2;BB,3;C",
+        join_type: JoinType::RightAnti,
+        output_str: "0;Z\n3;C\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "1;a,2;b,2;c,4;d",
+        file2_str: "0;Z,2;B,2;BB,3;C",
+        join_type: JoinType::FullAnti,
+        output_str: "0;Z\n1;a\n3;C\n4;d\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "1;a,2;b,2;c,4;d",
+        file2_str: "0;Z,2;B,2;BB,3;C,4;D",
+        join_type: JoinType::Semi,
+        output_str: "2;b\n2;c\n4;d\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "1;a,2;b,2;c,4;d,6;f,7;g",
+        file2_str: "0;Z,2;B,3;C,4;D,5;E,7;G,8;H",
+        join_type: JoinType::Semi,
+        output_str: "2;b\n2;c\n4;d\n|7;g\n",
+        njobs: 2
+      },
+      Data {
+        file1_str: "1;a,2;b,2;c,4;d,6;f,7;g",
+        file2_str: "0;Z,2;B,3;C,4;D,5;E,7;G,8;H",
+        join_type: JoinType::FullAnti,
+        output_str: "0;Z\n1;a\n3;C\n|5;E\n6;f\n8;H\n",
+        njobs: 2
+      },
+    ];
+    for ref s in l.iter()
+    {
+      let tmp_dir_1 = TempDir::new("parallel_join_1").expect("create temp dir");
+      let tmp_dir_2 = TempDir::new("parallel_join_2").expect("create temp dir");
+      let tmp_dir_out = TempDir::new("parallel_join_out").expect("create temp dir");
+
+      let files_1 = _write_files(s.file1_str, &tmp_dir_1);
+      let files_2 = _write_files(s.file2_str, &tmp_dir_2);
+      let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+
+      execute_parallel_join(
+        &files_1,
+        &files_2,
+        &String::from(";"),
+        None,
+        &vec![0],
+        &vec![0],
+        Collation::Bytewise,
+        &vec![String::from("1.0")],
+        s.join_type,
+        &String::new(),
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        false,
+        s.njobs,
+        /*buffer_size*/ 16384
+      ).unwrap();
       _assert_files_eq(&tmp_dir_out_path, s.output_str);
     }
   }
+
+  #[test]
+  fn test_join_collation()
+  {
+    struct Data {
+      file1_str: &'static str,
+      file2_str: &'static str,
+      collation: Collation,
+      output_str: &'static str,
+      njobs: i32
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        file1_str: "2;a,9;b,10;c,11;d,100;e",
+        file2_str: "1;A,9;B,11;D,100;E",
+        collation: Collation::Integer,
+        output_str: "b;B\nd;D\ne;E\n",
+        njobs: 1
+      },
+      Data {
+        file1_str: "2;a,9;b,10;c,11;d,100;e,101;f",
+        file2_str: "1;A,9;B,11;D,100;E,101;F",
+        collation: Collation::Integer,
+        output_str: "b;B\nd;D\n|e;E\nf;F\n",
+        njobs: 2
+      },
+      Data {
+        file1_str: "a;a,B;b,c;c",
+        file2_str: "A;A,b;B,C;C",
+        collation: Collation::IgnoreCase,
+        output_str: "a;A\nb;B\nc;C\n",
+        njobs: 1
+      },
+      Data {
+        // sorted like sort -f, which folds to uppercase
+        file1_str: "_;x",
+        file2_str: "a;A,_;U",
+        collation: Collation::IgnoreCase,
+        output_str: "x;U\n",
+        njobs: 1
+      },
+    ];
+    for ref s in l.iter()
+    {
+      let tmp_dir_1 = TempDir::new("parallel_join_1").expect("create temp dir");
+      let tmp_dir_2 = TempDir::new("parallel_join_2").expect("create temp dir");
+      let tmp_dir_out = TempDir::new("parallel_join_out").expect("create temp dir");
+
+      let files_1 = _write_files(s.file1_str, &tmp_dir_1);
+      let files_2 = _write_files(s.file2_str, &tmp_dir_2);
+      let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+
+      execute_parallel_join(
+        &files_1,
+        &files_2,
+        &String::from(";"),
+        None,
+        &vec![0],
+        &vec![0],
+        s.collation,
+        &vec![String::from("1.1"), String::from("2.1")],
+        JoinType::Inner,
+        &String::new(),
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        false,
+        s.njobs,
+        /*buffer_size*/ 16384
+      ).unwrap();
+      _assert_files_eq(&tmp_dir_out_path, s.output_str);
+    }
+  }
+
+  #[test]
+  fn test_join_errors()
+  {
+    let tmp_dir_1 = TempDir::new("parallel_join_1").expect("create temp dir");
+    let tmp_dir_2 = TempDir::new("parallel_join_2").expect("create temp dir");
+    let tmp_dir_out = TempDir::new("parallel_join_out").expect("create temp dir");
+    let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+
+    let join = |file1_str: &str, file2_str: &str| -> Result<Vec<OutputStats>>
+    {
+      execute_parallel_join(
+        &_write_files(file1_str, &tmp_dir_1),
+        &_write_files(file2_str, &tmp_dir_2),
+        &String::from(";"),
+        None,
+        &vec![1],
+        &vec![0],
+        Collation::Bytewise,
+        &vec![String::from("1.0"), String::from("2.1")],
+        JoinType::Inner,
+        &String::new(),
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        false,
+        1,
+        /*buffer_size*/ 16384
+      )
+    };
+
+    match join("a;1,b,c;3", "1;A,2;B,3;C")
+    {
+      Err(Error::Workers(ref failures)) if failures.len() == 1 =>
+      {
+        assert_eq!(failures[0].partition, 0);
+        assert_eq!(failures[0].ranges, vec![(0, 10), (0, 12)]);
+        match failures[0].error
+        {
+          Error::MissingField(ref line, 1) => assert_eq!(line, "b"),
+          ref error => panic!("expected a missing field error, got {:?}", error)
+        }
+      },
+      result => panic!("expected a worker error, got {:?}", result)
+    }
+
+    // keys with a different number of fields
+    let result = execute_parallel_join(
+      &_write_files("1;a", &tmp_dir_1),
+      &_write_files("1;A", &tmp_dir_2),
+      &String::from(";"),
+      None,
+      &vec![0, 1],
+      &vec![0],
+      Collation::Bytewise,
+      &vec![String::from("1.0")],
+      JoinType::Inner,
+      &String::new(),
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      false,
+      1,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::InvalidArgument(_)) => (),
+      result => panic!("expected an invalid argument error, got {:?}", result)
+    }
+
+    // no threads to write the output
+    for &njobs in [0, -1].iter()
+    {
+      let result = execute_parallel_join(
+        &_write_files("1;a", &tmp_dir_1),
+        &_write_files("1;A", &tmp_dir_2),
+        &String::from(";"),
+        None,
+        &vec![0],
+        &vec![0],
+        Collation::Bytewise,
+        &vec![String::from("1.0")],
+        JoinType::Inner,
+        &String::new(),
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        false,
+        njobs,
+        /*buffer_size*/ 16384
+      );
+      match result
+      {
+        Err(Error::InvalidArgument(_)) => (),
+        result => panic!("expected an invalid argument error, got {:?}", result)
+      }
+    }
+  }
 }
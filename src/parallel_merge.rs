@@ -0,0 +1,583 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::thread;
+use std::time::Instant;
+use std::path::Path;
+use std::io::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+use ReadLiner;
+use MultiFileReader;
+use ByteRangeLineReader;
+use Collation;
+use Error;
+use Result;
+use PartitionError;
+use error::join_worker;
+use error::check_workers;
+use error::check_jobs;
+use multi_file_reader::FindKeyPosition;
+use multi_file_reader::get_key;
+use multi_file_reader::key_to_strings;
+use FieldFormat;
+use CsvDialect;
+use source::Source;
+use source::ToSource;
+use publish::OutputStats;
+use publish::create_partial_file;
+use publish::sync_file;
+use publish::publish_outputs;
+
+/// A line read from one of the inputs of a k-way merge, kept in a BinaryHeap.
+///
+/// BinaryHeap is a max-heap, so the ordering is reversed to pop first the line
+/// with the lowest key, and the line of the first input for equal keys.
+struct MergeLine
+{
+  key: Vec<Vec<u8>>,
+  line: Vec<u8>,
+  input: usize,
+  collation: Collation
+}
+
+impl Ord for MergeLine
+{
+  fn cmp(&self, other: &MergeLine) -> Ordering
+  {
+    self.collation.compare_keys(&other.key, &self.key)
+      .then(other.input.cmp(&self.input))
+  }
+}
+
+impl PartialOrd for MergeLine
+{
+  fn partial_cmp(&self, other: &MergeLine) -> Option<Ordering>
+  {
+    Some(self.cmp(other))
+  }
+}
+
+impl PartialEq for MergeLine
+{
+  fn eq(&self, other: &MergeLine) -> bool
+  {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for MergeLine {}
+
+/// Reads the next line of the given input, returning it as a MergeLine or
+/// None if there are no more lines.
+///
+/// If given the key of the previous line of the input, returns an
+/// UnsortedInput error if the key of the line is lower.
+fn read_merge_line(
+  reader: &mut ByteRangeLineReader,
+  input: usize,
+  format: &FieldFormat,
+  key_fields: &Vec<usize>,
+  collation: Collation,
+  last_key: Option<&Vec<Vec<u8>>>,
+  verbose: bool
+) -> Result<Option<MergeLine>>
+{
+  let mut line: Vec<u8> = vec![];
+  if reader.read_line(&mut line, verbose)? == 0
+  {
+    return Ok(None)
+  }
+  // the last line of an input might not end in a new line, but it might not
+  // be the last once merged
+  if line.last() != Some(&b'\n')
+  {
+    line.push(b'\n');
+  }
+  let key = {
+    let trimmed_line = &line[..line.len() - 1];
+    let key = get_key(trimmed_line, format, key_fields)?;
+    if let Some(last_key) = last_key
+    {
+      if collation.compare_keys(&key, last_key) == Ordering::Less
+      {
+        return Err(Error::UnsortedInput(
+          String::from_utf8_lossy(trimmed_line).into_owned()
+        ))
+      }
+    }
+    key
+  };
+  Ok(Some(MergeLine
+  {
+    key: key,
+    line: line,
+    input: input,
+    collation: collation
+  }))
+}
+
+/// Finds the keys that split the key space of all the inputs in up to njobs
+/// slices of similar size. The first slice starts at the beginning, and each
+/// of the returned keys is the start of the next slice.
+///
+/// Each input is divided in njobs ranges aligned to key changes, and the first
+/// key of each range is taken as a sample of the key space, weighted by the
+/// size of the range. The splitters are the weighted quantiles of the samples.
+fn find_splitters<S: ToSource>(
+  input_list: &Vec<Vec<S>>,
+  format: &FieldFormat,
+  key_fields: &Vec<usize>,
+  collation: Collation,
+  verbose: bool,
+  njobs: i32,
+  buffer_size: u32
+) -> Result<Vec<Vec<Vec<u8>>>>
+{
+  let mut samples: Vec<(Vec<Vec<u8>>, u64)> = vec![];
+  for file_list in input_list.iter()
+  {
+    let ranges = ByteRangeLineReader::open_by_key(
+      file_list,
+      njobs as u64,
+      format,
+      key_fields,
+      collation,
+      verbose,
+      buffer_size
+    )?;
+    for range in ranges.iter()
+    {
+      if let Some(line) = range.first_line()?
+      {
+        let weight = range.end() - range.pos();
+        samples.push((get_key(&line, format, key_fields)?, weight));
+      }
+    }
+  }
+  samples.sort_by(|a, b| collation.compare_keys(&a.0, &b.0));
+
+  let total: u64 = samples.iter().map(|sample| sample.1).sum();
+  let mut splitters: Vec<Vec<Vec<u8>>> = vec![];
+  let mut accumulated: u64 = 0;
+  let mut prev_key: Option<Vec<Vec<u8>>> = None;
+  for (key, weight) in samples.into_iter()
+  {
+    // a slice can only start where the key changes
+    let is_new_key = match prev_key
+    {
+      Some(ref prev_key) => collation.compare_keys(prev_key, &key) != Ordering::Equal,
+      None => false
+    };
+    let next_slice = splitters.len() as u64 + 1;
+    if next_slice < njobs as u64 &&
+      accumulated >= total * next_slice / njobs as u64 &&
+      is_new_key
+    {
+      splitters.push(key.clone());
+    }
+    accumulated += weight;
+    prev_key = Some(key);
+  }
+  Ok(splitters)
+}
+
+// Executes a parallel k-way merge of multiple inputs, each of them a list of
+// files sorted by the key fields in the order defined by the collation. The
+// fields are separated as in execute_parallel_join, also in CSV files, whose
+// records are written verbatim.
+//
+// 1. Split the key space in up to N slices (one per job/thread) of similar
+//    size, see find_splitters
+// 2. for each slice of the key space:
+// 2.1. find the slice in every input
+// 2.2. spawn a thread
+// 2.3. merge the slices of all the inputs into an output file
+//
+// The output files are numbered and ordered, so that their concatenation is
+// the sorted merge of all the inputs. Lines with equal keys are written in the
+// order of the inputs. If any thread fails, returns an Error::Workers with the
+// byte ranges of each input of each failed slice. Otherwise the output files,
+// written to partial files until then, are published all at once, and what
+// was written to each of them is returned in order, with their digests if
+// `sha256` is true.
+pub fn execute_parallel_merge<S: ToSource>(
+  input_list: &Vec<Vec<S>>,
+  separator: &String,
+  csv: Option<CsvDialect>,
+  key_fields: &Vec<usize>,
+  collation: Collation,
+  output_path: &String,
+  sha256: bool,
+  verbose: bool,
+  njobs: i32,
+  buffer_size: u32
+) -> Result<Vec<OutputStats>>
+{
+  check_jobs(njobs)?;
+  let format = FieldFormat::new(separator, csv)?;
+  let start = Instant::now();
+
+  // empty inputs have nothing to merge
+  let mut non_empty_input_list: Vec<Vec<Arc<dyn Source>>> = vec![];
+  for file_list in input_list.iter()
+  {
+    if MultiFileReader::len(file_list)? > 0
+    {
+      non_empty_input_list.push(format.sources(file_list));
+    }
+  }
+  let input_list = non_empty_input_list;
+  if input_list.is_empty()
+  {
+    let path = Path::new(output_path).join("0");
+    let mut out = create_partial_file(&path)?;
+    out.set_sha256(sha256);
+    let output = sync_file(&mut out)?;
+    return publish_outputs(vec![output])
+  }
+
+  let splitters = find_splitters(
+    &input_list,
+    &format,
+    key_fields,
+    collation,
+    verbose,
+    njobs,
+    buffer_size
+  )?;
+  if verbose {
+    println!(
+      "execute_parallel_merge: splitters={:?}",
+      splitters.iter().map(|key| key_to_strings(key)).collect::<Vec<Vec<String>>>()
+    );
+  }
+
+  // the byte range of each slice in each input: the slice n starts at the
+  // lower bound of the splitter n - 1 and ends at the lower bound of the
+  // splitter n
+  let mut bounds: Vec<Vec<u64>> = vec![];
+  for file_list in input_list.iter()
+  {
+    let mut input_bounds: Vec<u64> = vec![0];
+    for splitter in splitters.iter()
+    {
+      input_bounds.push(MultiFileReader::find_key_lower_bound(
+        splitter.clone(),
+        file_list,
+        &format,
+        key_fields,
+        collation,
+        verbose
+      )?);
+    }
+    input_bounds.push(MultiFileReader::len(file_list)?);
+    bounds.push(input_bounds);
+  }
+  let slice_ranges = |thread_num: usize| -> Vec<(u64, u64)>
+  {
+    bounds.iter()
+      .map(|input_bounds| (input_bounds[thread_num], input_bounds[thread_num + 1]))
+      .collect()
+  };
+
+  let mut children = vec![];
+  for thread_num in 0..(splitters.len() + 1)
+  {
+    let input_list = input_list.clone();
+    let format = format.clone();
+    let key_fields = key_fields.clone();
+    let output_path = output_path.clone();
+    let ranges = slice_ranges(thread_num);
+
+    children.push( thread::spawn(move || -> Result<OutputStats>
+    {
+      let mut readers: Vec<ByteRangeLineReader> = vec![];
+      for (file_list, &(start_pos, end_pos)) in input_list.iter().zip(ranges.iter())
+      {
+        readers.push(ByteRangeLineReader::open_range(
+          file_list.clone(), start_pos, end_pos, verbose, buffer_size
+        )?);
+      }
+
+      let path = Path::new(&output_path).join(thread_num.to_string());
+      if verbose {
+        println!("thread {}: output path: {:?}", thread_num, path);
+      }
+      let mut out = create_partial_file(path)?;
+      out.set_csv(format.csv());
+      out.set_sha256(sha256);
+
+      let mut heap: BinaryHeap<MergeLine> = BinaryHeap::new();
+      for (input, reader) in readers.iter_mut().enumerate()
+      {
+        if let Some(line) = read_merge_line(
+          reader, input, &format, &key_fields, collation, None, verbose
+        )? {
+          heap.push(line);
+        }
+      }
+
+      while let Some(merge_line) = heap.pop()
+      {
+        out.write_all(&merge_line.line)?;
+        if let Some(line) = read_merge_line(
+          &mut readers[merge_line.input],
+          merge_line.input,
+          &format,
+          &key_fields,
+          collation,
+          Some(&merge_line.key),
+          verbose
+        )? {
+          heap.push(line);
+        }
+      }
+      sync_file(&mut out)
+    }));
+  }
+
+  let mut outputs: Vec<OutputStats> = vec![];
+  let mut failures: Vec<PartitionError> = vec![];
+  let mut i: usize = 0;
+  for child in children {
+    // Wait for the thread to finish. Returns a result.
+    if verbose {
+      println!("{} secs \tbase_thread: finishing thread {}", start.elapsed().as_secs(), i);
+    }
+
+    if let Some(output) = join_worker(child, i, slice_ranges(i), &mut failures)
+    {
+      outputs.push(output);
+    }
+
+    if verbose {
+      println!("{} secs \tbase_thread: ... finished thread {}", start.elapsed().as_secs(), i);
+    }
+    i = i + 1;
+  }
+  check_workers(failures)?;
+  publish_outputs(outputs)
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::sync::Arc;
+
+  use tempdir::TempDir;
+
+  use test_helpers::_write_files;
+  use test_helpers::_assert_files_eq;
+
+  use parallel_merge::execute_parallel_merge;
+  use parallel_merge::find_splitters;
+  use source::Source;
+  use MemoryFile;
+  use test_helpers::_format;
+  use Collation;
+  use Error;
+
+  #[test]
+  fn test_merge()
+  {
+    struct Data {
+      input_strs: Vec<&'static str>,
+      collation: Collation,
+      output_str: &'static str,
+      njobs: i32
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        input_strs: vec!["1;a,3;a,5;a", "2;b,4;b,6;b"],
+        collation: Collation::Bytewise,
+        output_str: "1;a\n2;b\n3;a\n4;b\n5;a\n6;b\n",
+        njobs: 1
+      },
+      Data {
+        input_strs: vec!["1;a,3;a|5;a", "2;b,3;b,4;b", "0;c"],
+        collation: Collation::Bytewise,
+        output_str: "0;c\n1;a\n2;b\n3;a\n3;b\n4;b\n5;a\n",
+        njobs: 1
+      },
+      Data {
+        input_strs: vec!["0;a,1;a,2;a,3;a,4;a,5;a,6;a,7;a", "2;b,5;b,9;b"],
+        collation: Collation::Bytewise,
+        output_str: "0;a\n1;a\n2;a\n2;b\n3;a\n4;a\n|5;a\n5;b\n6;a\n7;a\n9;b\n",
+        njobs: 2
+      },
+      Data {
+        input_strs: vec!["8;a,9;a,10;a,11;a", "1;b,10;b,12;b"],
+        collation: Collation::Integer,
+        output_str: "1;b\n8;a\n9;a\n10;a\n10;b\n|11;a\n12;b\n",
+        njobs: 2
+      },
+      Data {
+        input_strs: vec!["1;a,1;a,1;a,1;a", "1;b"],
+        collation: Collation::Bytewise,
+        output_str: "1;a\n1;a\n1;a\n1;a\n1;b\n",
+        njobs: 3
+      },
+    ];
+    for ref s in l.iter()
+    {
+      let tmp_dirs: Vec<TempDir> = s.input_strs.iter()
+        .map(|_| TempDir::new("parallel_merge").expect("create temp dir"))
+        .collect();
+      let input_list: Vec<Vec<String>> = s.input_strs.iter().zip(tmp_dirs.iter())
+        .map(|(input_str, tmp_dir)| _write_files(input_str, tmp_dir))
+        .collect();
+      let tmp_dir_out = TempDir::new("parallel_merge_out").expect("create temp dir");
+      let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+
+      execute_parallel_merge(
+        &input_list,
+        &String::from(";"),
+        None,
+        &vec![0],
+        s.collation,
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        false,
+        s.njobs,
+        /*buffer_size*/ 16384
+      ).unwrap();
+      _assert_files_eq(&tmp_dir_out_path, s.output_str);
+    }
+  }
+
+  #[test]
+  fn test_find_splitters_uneven_ranges()
+  {
+    // the first input is divided in a big range with all the 1 keys and a
+    // small one, and the second input in two small ranges
+    let tmp_dir_1 = TempDir::new("parallel_merge_1").expect("create temp dir");
+    let tmp_dir_2 = TempDir::new("parallel_merge_2").expect("create temp dir");
+    let input_list = vec![
+      _write_files("0,1,1,1,1,1,1,1,1,1,1,1,2", &tmp_dir_1),
+      _write_files("5,6", &tmp_dir_2),
+    ];
+
+    let splitters = find_splitters(
+      &input_list, &_format(","), &vec![0], Collation::Bytewise, false, 2,
+      /*buffer_size*/ 16384
+    ).unwrap();
+    assert_eq!(splitters, vec![vec![b"2".to_vec()]]);
+  }
+
+  #[test]
+  fn test_merge_last_line_without_new_line()
+  {
+    let tmp_dir_out = TempDir::new("parallel_merge_out").expect("create temp dir");
+    let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+    let input = |name: &str, data: &[u8]| -> Vec<Arc<dyn Source>>
+    {
+      vec![Arc::new(MemoryFile::new(name, data.to_vec()))]
+    };
+
+    execute_parallel_merge(
+      &vec![input("1", b"1;a\n3;c"), input("2", b"2;b\n4;d")],
+      &String::from(";"),
+      None,
+      &vec![0],
+      Collation::Bytewise,
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      false,
+      1,
+      /*buffer_size*/ 16384
+    ).unwrap();
+    _assert_files_eq(&tmp_dir_out_path, "1;a\n2;b\n3;c\n4;d\n");
+  }
+
+  #[test]
+  fn test_merge_errors()
+  {
+    let tmp_dir_1 = TempDir::new("parallel_merge_1").expect("create temp dir");
+    let tmp_dir_2 = TempDir::new("parallel_merge_2").expect("create temp dir");
+    let tmp_dir_out = TempDir::new("parallel_merge_out").expect("create temp dir");
+    let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+
+    let result = execute_parallel_merge(
+      &vec![
+        _write_files("1;a,3;c,2;b", &tmp_dir_1),
+        _write_files("1;A,2;B", &tmp_dir_2)
+      ],
+      &String::from(";"),
+      None,
+      &vec![0],
+      Collation::Bytewise,
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      false,
+      1,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::Workers(ref failures)) if failures.len() == 1 =>
+      {
+        assert_eq!(failures[0].ranges, vec![(0, 12), (0, 8)]);
+        match failures[0].error
+        {
+          Error::UnsortedInput(ref line) => assert_eq!(line, "2;b"),
+          ref error => panic!("expected an unsorted input error, got {:?}", error)
+        }
+      },
+      result => panic!("expected a worker error, got {:?}", result)
+    }
+
+    let result = execute_parallel_merge(
+      &vec![vec![String::from("/nonexistent/paralio")]],
+      &String::from(";"),
+      None,
+      &vec![0],
+      Collation::Bytewise,
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      false,
+      1,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::Open(path, _)) => assert_eq!(path, "/nonexistent/paralio"),
+      result => panic!("expected an open error, got {:?}", result)
+    }
+
+    // no threads to write the output
+    let result = execute_parallel_merge(
+      &vec![_write_files("1;a", &tmp_dir_1)],
+      &String::from(";"),
+      None,
+      &vec![0],
+      Collation::Bytewise,
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      false,
+      /*njobs*/ 0,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::InvalidArgument(_)) => (),
+      result => panic!("expected an invalid argument error, got {:?}", result)
+    }
+  }
+}
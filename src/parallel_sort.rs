@@ -0,0 +1,368 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::thread;
+use std::time::Instant;
+use std::path::Path;
+use std::io::BufWriter;
+use std::io::prelude::*;
+
+use tempdir::TempDir;
+
+use ReadLiner;
+use ByteRangeLineReader;
+use Collation;
+use Error;
+use Result;
+use PartitionError;
+use error::join_worker;
+use error::check_workers;
+use error::check_jobs;
+use multi_file_reader::get_key;
+use FieldFormat;
+use CsvDialect;
+use output_file::create_file;
+use parallel_merge::execute_parallel_merge;
+use source::ToSource;
+use publish::OutputStats;
+
+// Approximate memory used by each line on top of its contents, used to account
+// for the memory limit
+const LINE_OVERHEAD: u64 = 64;
+
+/// Sorts the given lines by key and writes them to a new run file in run_dir,
+/// returning its path.
+fn write_run(
+  lines: &mut Vec<(Vec<Vec<u8>>, Vec<u8>)>,
+  collation: Collation,
+  run_dir: &Path,
+  run_name: String,
+  verbose: bool
+) -> Result<String>
+{
+  // sort_by is stable, so lines with equal keys keep the input order
+  lines.sort_by(|a, b| collation.compare_keys(&a.0, &b.0));
+
+  let path = String::from(run_dir.join(run_name).to_str().unwrap());
+  if verbose {
+    println!("write_run: writing {} lines to {}", lines.len(), path);
+  }
+  let mut out = BufWriter::new(create_file(&path)?);
+  for &(_, ref line) in lines.iter()
+  {
+    out.write_all(line)?;
+  }
+  out.flush()?;
+  lines.clear();
+  /*return*/ Ok(path)
+}
+
+// Executes a parallel external merge sort
+//
+// 1. Divide the input in N byte ranges (one per job/thread)
+//
+// 2. for each range:
+// 2.1. spawn a thread
+// 2.2. read lines until memory_limit is reached, sort them by key and write
+//      them to a temporary run file. Repeat until the range is finished
+//
+// 3. k-way merge all the runs in parallel, see execute_parallel_merge
+//
+// The output is a directory of numbered and ordered files, one per thread,
+// which can be used directly as input for pjoin, returned like
+// execute_parallel_merge does. The sort is stable. The temporary runs are
+// written in tmp_path and removed afterwards. The fields are separated as in
+// execute_parallel_join, also in CSV files, whose records are written
+// verbatim.
+pub fn execute_parallel_sort<S: ToSource>(
+  file_str_list: &Vec<S>,
+  separator: &String,
+  csv: Option<CsvDialect>,
+  key_fields: &Vec<usize>,
+  collation: Collation,
+  output_path: &String,
+  sha256: bool,
+  tmp_path: &String,
+  memory_limit: u64,
+  verbose: bool,
+  njobs: i32,
+  buffer_size: u32
+) -> Result<Vec<OutputStats>>
+{
+  check_jobs(njobs)?;
+  let format = FieldFormat::new(separator, csv)?;
+  let start = Instant::now();
+  let run_dir = TempDir::new_in(tmp_path, "psort")
+    .map_err(|error| Error::Open(tmp_path.clone(), error))?;
+
+  let ranges = ByteRangeLineReader::open(
+    &format.sources(file_str_list), njobs as u64, verbose, buffer_size
+  )?;
+
+  let mut children = vec![];
+  let mut thread_ranges: Vec<(u64, u64)> = vec![];
+  for (thread_num, range) in ranges.into_iter().enumerate()
+  {
+    thread_ranges.push((range.pos(), range.end()));
+    let format = format.clone();
+    let key_fields = key_fields.clone();
+    let run_dir_path = run_dir.path().to_path_buf();
+
+    children.push( thread::spawn(move || -> Result<Vec<String>>
+    {
+      let mut range = range;
+      let mut run_list: Vec<String> = vec![];
+      let mut lines: Vec<(Vec<Vec<u8>>, Vec<u8>)> = vec![];
+      let mut memory_used: u64 = 0;
+      loop
+      {
+        let mut line: Vec<u8> = vec![];
+        if range.read_line(&mut line, verbose)? == 0
+        {
+          break;
+        }
+        // the last line might not end in a new line, but it won't be the last
+        // once sorted
+        if line.last() != Some(&b'\n')
+        {
+          line.push(b'\n');
+        }
+        let key = get_key(&line[..line.len() - 1], &format, &key_fields)?;
+        memory_used += line.len() as u64 + LINE_OVERHEAD;
+        for field in key.iter()
+        {
+          memory_used += field.len() as u64 + LINE_OVERHEAD;
+        }
+        lines.push((key, line));
+
+        if memory_used >= memory_limit
+        {
+          let run_name = format!("{}_{}", thread_num, run_list.len());
+          run_list.push(
+            write_run(&mut lines, collation, &run_dir_path, run_name, verbose)?
+          );
+          memory_used = 0;
+        }
+      }
+      if !lines.is_empty()
+      {
+        let run_name = format!("{}_{}", thread_num, run_list.len());
+        run_list.push(
+          write_run(&mut lines, collation, &run_dir_path, run_name, verbose)?
+        );
+      }
+      /*return*/ Ok(run_list)
+    }));
+  }
+
+  // runs are kept in thread order, so that the merge is stable
+  let mut input_list: Vec<Vec<String>> = vec![];
+  let mut failures: Vec<PartitionError> = vec![];
+  let mut i: usize = 0;
+  for child in children {
+    if verbose {
+      println!("{} secs \tbase_thread: finishing sort thread {}", start.elapsed().as_secs(), i);
+    }
+
+    if let Some(run_list) = join_worker(child, i, vec![thread_ranges[i]], &mut failures)
+    {
+      input_list.extend(run_list.into_iter().map(|run| vec![run]));
+    }
+
+    if verbose {
+      println!("{} secs \tbase_thread: ... finished sort thread {}", start.elapsed().as_secs(), i);
+    }
+    i = i + 1;
+  }
+  check_workers(failures)?;
+
+  if verbose {
+    println!("{} secs \tbase_thread: merging {} runs", start.elapsed().as_secs(), input_list.len());
+  }
+  execute_parallel_merge(
+    &input_list,
+    separator,
+    csv,
+    key_fields,
+    collation,
+    output_path,
+    sha256,
+    verbose,
+    njobs,
+    buffer_size
+  )
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::fs::File;
+  use std::io::prelude::*;
+
+  use tempdir::TempDir;
+
+  use test_helpers::_write_files;
+  use test_helpers::_assert_files_eq;
+
+  use parallel_sort::execute_parallel_sort;
+  use Collation;
+  use Error;
+
+  #[test]
+  fn test_sort()
+  {
+    struct Data {
+      input_str: &'static str,
+      key_fields: Vec<usize>,
+      collation: Collation,
+      memory_limit: u64,
+      output_str: &'static str,
+      njobs: i32
+    }
+
+    let l: Vec<Data> = vec![
+      Data {
+        input_str: "3;a,1;b,2;c",
+        key_fields: vec![0],
+        collation: Collation::Bytewise,
+        memory_limit: 1000000,
+        output_str: "1;b\n2;c\n3;a\n",
+        njobs: 1
+      },
+      Data {
+        input_str: "5;a,3;a,1;a|4;b,2;b,0;b",
+        key_fields: vec![0],
+        collation: Collation::Bytewise,
+        memory_limit: 1,
+        output_str: "0;b\n1;a\n2;b\n3;a\n4;b\n5;a\n",
+        njobs: 1
+      },
+      Data {
+        input_str: "7;a,6;a,5;a,4;a|3;a,2;a,1;a,0;a",
+        key_fields: vec![0],
+        collation: Collation::Bytewise,
+        memory_limit: 200,
+        output_str: "0;a\n1;a\n2;a\n3;a\n|4;a\n5;a\n6;a\n7;a\n",
+        njobs: 2
+      },
+      Data {
+        // stable for equal keys
+        input_str: "2;a,1;a,2;b,1;b|2;c,1;c",
+        key_fields: vec![0],
+        collation: Collation::Bytewise,
+        memory_limit: 1,
+        output_str: "1;a\n1;b\n1;c\n|2;a\n2;b\n2;c\n",
+        njobs: 2
+      },
+      Data {
+        input_str: "10;a,9;b,100;c,1;d",
+        key_fields: vec![0],
+        collation: Collation::Integer,
+        memory_limit: 1000000,
+        output_str: "1;d\n9;b\n10;a\n100;c\n",
+        njobs: 1
+      },
+      Data {
+        input_str: "b;2;x,a;2;y,b;1;z,a;1;w",
+        key_fields: vec![0, 1],
+        collation: Collation::Bytewise,
+        memory_limit: 1000000,
+        output_str: "a;1;w\na;2;y\nb;1;z\nb;2;x\n",
+        njobs: 1
+      },
+    ];
+    for ref s in l.iter()
+    {
+      let tmp_dir = TempDir::new("parallel_sort").expect("create temp dir");
+      let file_str_list = _write_files(s.input_str, &tmp_dir);
+      let tmp_dir_out = TempDir::new("parallel_sort_out").expect("create temp dir");
+      let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+      let tmp_dir_runs = TempDir::new("parallel_sort_runs").expect("create temp dir");
+      let tmp_dir_runs_path = String::from(tmp_dir_runs.path().to_str().unwrap());
+
+      execute_parallel_sort(
+        &file_str_list,
+        &String::from(";"),
+        None,
+        &s.key_fields,
+        s.collation,
+        &tmp_dir_out_path,
+        /*sha256*/ false,
+        &tmp_dir_runs_path,
+        s.memory_limit,
+        false,
+        s.njobs,
+        /*buffer_size*/ 16384
+      ).unwrap();
+      _assert_files_eq(&tmp_dir_out_path, s.output_str);
+    }
+  }
+
+  #[test]
+  fn test_sort_last_line_without_new_line()
+  {
+    let tmp_dir = TempDir::new("parallel_sort").expect("create temp dir");
+    let path = tmp_dir.path().join("0");
+    File::create(&path).unwrap().write_all(b"3;a\n1;b\n2;c").unwrap();
+    let tmp_dir_out = TempDir::new("parallel_sort_out").expect("create temp dir");
+    let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+
+    execute_parallel_sort(
+      &vec![String::from(path.to_str().unwrap())],
+      &String::from(";"),
+      None,
+      &vec![0],
+      Collation::Bytewise,
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      &tmp_dir_out_path,
+      1000000,
+      false,
+      1,
+      /*buffer_size*/ 16384
+    ).unwrap();
+    _assert_files_eq(&tmp_dir_out_path, "1;b\n2;c\n3;a\n");
+  }
+
+  #[test]
+  fn test_sort_no_jobs()
+  {
+    let tmp_dir = TempDir::new("parallel_sort").expect("create temp dir");
+    let file_str_list = _write_files("1;a", &tmp_dir);
+    let tmp_dir_out = TempDir::new("parallel_sort_out").expect("create temp dir");
+    let tmp_dir_out_path = String::from(tmp_dir_out.path().to_str().unwrap());
+
+    let result = execute_parallel_sort(
+      &file_str_list,
+      &String::from(";"),
+      None,
+      &vec![0],
+      Collation::Bytewise,
+      &tmp_dir_out_path,
+      /*sha256*/ false,
+      &tmp_dir_out_path,
+      1000000,
+      false,
+      /*njobs*/ -1,
+      /*buffer_size*/ 16384
+    );
+    match result
+    {
+      Err(Error::InvalidArgument(_)) => (),
+      result => panic!("expected an invalid argument error, got {:?}", result)
+    }
+  }
+}
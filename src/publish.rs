@@ -0,0 +1,660 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::prelude::*;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::SyncSender;
+
+use Error;
+use Result;
+use CsvDialect;
+use csv::QuoteState;
+use output_file::create_file;
+use sha2::Digest;
+use sha2::Sha256;
+use memchr::memchr_iter;
+
+/// Name of the marker file written in the output directory after a successful
+/// run, see write_success_marker
+pub const SUCCESS_MARKER: &'static str = "_SUCCESS";
+
+/// Output path that means writing the output to stdout instead of to files
+pub const STDOUT_PATH: &'static str = "-";
+
+/// Size of the chunks in which a partition is sent to be written in order to
+/// stdout, see stream_writer
+pub const STREAM_CHUNK_SIZE: usize = 1 << 16;
+
+/// Number of chunks of each partition that can be waiting to be written to
+/// stdout before the thread writing the partition blocks
+pub const STREAM_BUFFER_CHUNKS: usize = 4;
+
+/// Returns the path where the output file with the given path is written
+/// before it is published: a hidden file in the same directory, so that
+/// whoever watches the directory doesn't read it while it's half-written.
+pub fn partial_path<P: AsRef<Path>>(path: P) -> PathBuf
+{
+  let path = path.as_ref();
+  let name = path.file_name().map_or(
+    String::new(), |name| name.to_string_lossy().into_owned()
+  );
+  /*return*/ path.with_file_name(format!(".{}.partial", name))
+}
+
+/// What was written to an output file, counted while writing it so that it
+/// doesn't need to be read again
+#[derive(Clone, Debug)]
+pub struct OutputStats
+{
+  /// Path of the output file once published
+  pub path: PathBuf,
+  /// Number of lines, or of records for CSV output, see OutputWriter::set_csv
+  pub lines: u64,
+  /// Size in bytes
+  pub bytes: u64,
+  /// Position at which the last line or record starts
+  pub last_line_pos: u64,
+  /// Digest of the contents, or None if it was not computed, see
+  /// OutputWriter::set_sha256 and concatenate_outputs
+  pub sha256: Option<Sha256>
+}
+
+/// Where an OutputWriter writes to
+enum Sink
+{
+  File(BufWriter<File>),
+  Stdout(io::Stdout),
+  /// Sends the data in chunks of STREAM_CHUNK_SIZE to be written elsewhere
+  Stream(Vec<u8>, SyncSender<Vec<u8>>)
+}
+
+impl Sink
+{
+  /// Sends the data buffered by a Stream sink
+  fn send_chunk(&mut self) -> io::Result<()>
+  {
+    if let Sink::Stream(ref mut chunk, ref sender) = *self
+    {
+      if !chunk.is_empty()
+      {
+        let full_chunk = ::std::mem::replace(
+          chunk, Vec::with_capacity(STREAM_CHUNK_SIZE)
+        );
+        sender.send(full_chunk).map_err(|_| io::Error::new(
+          io::ErrorKind::BrokenPipe, "the output stream was closed"
+        ))?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Buffered writer of an output file, that counts its lines while they are
+/// written and can keep a running digest of its contents
+pub struct OutputWriter
+{
+  out: Sink,
+  stats: OutputStats,
+  line_pos: u64,
+  /// Quoting rules of the records written, and whether the text written so
+  /// far ends inside quotes
+  csv: Option<(CsvDialect, QuoteState)>
+}
+
+impl OutputWriter
+{
+  fn new(out: Sink, path: PathBuf) -> OutputWriter
+  {
+    OutputWriter
+    {
+      out: out,
+      stats: OutputStats
+      {
+        path: path,
+        lines: 0,
+        bytes: 0,
+        last_line_pos: 0,
+        sha256: None
+      },
+      line_pos: 0,
+      csv: None
+    }
+  }
+
+  /// Counts the records of the given CSV dialect instead of lines, so that
+  /// the new lines inside quotes don't end them. Nothing changes if `csv` is
+  /// None.
+  pub fn set_csv(&mut self, csv: Option<CsvDialect>)
+  {
+    self.csv = csv.map(|dialect| (dialect, QuoteState::Unquoted));
+  }
+
+  /// Computes the SHA-256 digest of the contents if `sha256` is true, which
+  /// is only worth it if it's going to be written to a manifest or printed.
+  /// It must be called before writing anything.
+  pub fn set_sha256(&mut self, sha256: bool)
+  {
+    self.stats.sha256 = match sha256
+    {
+      true => Some(Sha256::new()),
+      false => None
+    };
+  }
+
+  /// Counts a line or record that ends at `pos`, the position of its new line
+  fn end_line(&mut self, pos: u64)
+  {
+    self.stats.lines += 1;
+    self.stats.last_line_pos = self.line_pos;
+    self.line_pos = pos + 1;
+  }
+
+  /// Counts the lines or records that end in `buf`, written at position
+  /// `self.stats.bytes`. Only the new lines, and the quote and escape chars
+  /// of CSV records, are looked at, which memchr finds many bytes at a time.
+  fn count_lines(&mut self, buf: &[u8])
+  {
+    let buf_pos = self.stats.bytes;
+    let (dialect, mut state) = match self.csv
+    {
+      Some(csv) => csv,
+      None =>
+      {
+        for i in memchr_iter(b'\n', buf)
+        {
+          self.end_line(buf_pos + i as u64);
+        }
+        return
+      }
+    };
+    let mut i: usize = 0;
+    while i < buf.len()
+    {
+      // the char after an escape char is taken as it is, whatever it is
+      if state == QuoteState::Escaped
+      {
+        state = QuoteState::Quoted;
+        i += 1;
+        continue;
+      }
+      match dialect.find_new_line_or_quote(&buf[i..])
+      {
+        Some(offset) =>
+        {
+          i += offset;
+          if buf[i] == b'\n' && state == QuoteState::Unquoted
+          {
+            self.end_line(buf_pos + i as u64);
+          }
+          state = dialect.next_state(state, buf[i]);
+          i += 1;
+        },
+        None => break
+      }
+    }
+    self.csv = Some((dialect, state));
+  }
+
+  /// Returns the path of the output file once published
+  pub fn path(&self) -> &Path
+  {
+    &self.stats.path
+  }
+
+  /// Returns what has been written so far
+  pub fn stats(&self) -> OutputStats
+  {
+    self.stats.clone()
+  }
+}
+
+impl Write for OutputWriter
+{
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+  {
+    let len = match self.out
+    {
+      Sink::File(ref mut out) => out.write(buf)?,
+      Sink::Stdout(ref mut out) => out.write(buf)?,
+      Sink::Stream(ref mut chunk, _) =>
+      {
+        chunk.extend_from_slice(buf);
+        buf.len()
+      }
+    };
+    if let Sink::Stream(ref chunk, _) = self.out
+    {
+      if chunk.len() >= STREAM_CHUNK_SIZE
+      {
+        self.out.send_chunk()?;
+      }
+    }
+    if let Some(ref mut sha256) = self.stats.sha256
+    {
+      sha256.update(&buf[..len]);
+    }
+    self.count_lines(&buf[..len]);
+    self.stats.bytes += len as u64;
+    Ok(len)
+  }
+
+  fn flush(&mut self) -> io::Result<()>
+  {
+    match self.out
+    {
+      Sink::File(ref mut out) => out.flush(),
+      Sink::Stdout(ref mut out) => out.flush(),
+      Sink::Stream(_, _) => self.out.send_chunk()
+    }
+  }
+}
+
+/// Returns the hidden directory where the numbered output files are written
+/// before being concatenated into the single output file with the given path,
+/// see concatenate_outputs
+pub fn parts_path<P: AsRef<Path>>(path: P) -> PathBuf
+{
+  let path = path.as_ref();
+  let name = path.file_name().map_or(
+    String::new(), |name| name.to_string_lossy().into_owned()
+  );
+  /*return*/ path.with_file_name(format!(".{}.parts", name))
+}
+
+/// Creates the partial file of the output file with the given path, see
+/// partial_path
+pub fn create_partial_file<P: AsRef<Path>>(path: P) -> Result<OutputWriter>
+{
+  let out = BufWriter::new(create_file(partial_path(path.as_ref()))?);
+  /*return*/ Ok(OutputWriter::new(Sink::File(out), path.as_ref().to_path_buf()))
+}
+
+/// Returns a writer of the output to stdout, whose path is STDOUT_PATH
+pub fn stdout_writer() -> OutputWriter
+{
+  OutputWriter::new(Sink::Stdout(io::stdout()), PathBuf::from(STDOUT_PATH))
+}
+
+/// Returns a writer of one partition of the output to stdout, that sends it in
+/// chunks of STREAM_CHUNK_SIZE to the thread that writes the partitions in
+/// order. Once STREAM_BUFFER_CHUNKS are waiting to be written, it blocks
+/// until one of them is, so the partitions after the one being written
+/// to stdout don't use more memory than that.
+///
+/// Returns the receiving end of the chunks too. A write returns a BrokenPipe
+/// error if the receiver was dropped.
+pub fn stream_writer() -> (OutputWriter, Receiver<Vec<u8>>)
+{
+  let (sender, receiver) = mpsc::sync_channel(STREAM_BUFFER_CHUNKS);
+  let out = Sink::Stream(Vec::with_capacity(STREAM_CHUNK_SIZE), sender);
+  /*return*/ (OutputWriter::new(out, PathBuf::from(STDOUT_PATH)), receiver)
+}
+
+/// Flushes an output file and syncs its contents to disk, returning what was
+/// written to it. Stdout and stream writers are only flushed.
+pub fn sync_file(out: &mut OutputWriter) -> Result<OutputStats>
+{
+  out.flush()?;
+  if let Sink::File(ref out) = out.out
+  {
+    out.get_ref().sync_all()?;
+  }
+  Ok(out.stats())
+}
+
+/// Syncs a directory to disk, so that the files renamed inside it stay renamed
+/// after a crash
+fn sync_dir(dir_path: &Path) -> Result<()>
+{
+  File::open(dir_path)
+    .map_err(|error| Error::Open(dir_path.to_string_lossy().into_owned(), error))?
+    .sync_all()?;
+  Ok(())
+}
+
+/// Publishes the output files with the given paths, once all of them have been
+/// written and synced to their partial files: renames each partial file into
+/// place and syncs the directories that contain them.
+pub fn publish_files(paths: &Vec<PathBuf>) -> Result<()>
+{
+  let mut dir_paths: Vec<PathBuf> = vec![];
+  for path in paths.iter()
+  {
+    fs::rename(partial_path(path), path)?;
+    let dir_path = match path.parent()
+    {
+      Some(dir_path) if dir_path != Path::new("") => dir_path.to_path_buf(),
+      _ => PathBuf::from(".")
+    };
+    if !dir_paths.contains(&dir_path)
+    {
+      dir_paths.push(dir_path);
+    }
+  }
+  for dir_path in dir_paths.iter()
+  {
+    sync_dir(dir_path)?;
+  }
+  Ok(())
+}
+
+/// Publishes the output files described by `outputs` like publish_files,
+/// returning them
+pub fn publish_outputs(outputs: Vec<OutputStats>) -> Result<Vec<OutputStats>>
+{
+  publish_files(&outputs.iter().map(|output| output.path.clone()).collect())?;
+  Ok(outputs)
+}
+
+/// Concatenates the published output files described by `outputs` in order
+/// into a single output file at `path`, which is published like them, and
+/// then removes them. Returns what was written to the single output file.
+///
+/// The files are copied with io::copy, which between files lets the kernel
+/// copy the data without going through user space where it can
+/// (copy_file_range on Linux). The digest of the output file is only computed
+/// if `sha256` is true: the digest of the first file is continued, and the
+/// rest of them are read back, usually from the page cache.
+pub fn concatenate_outputs(outputs: &Vec<OutputStats>, path: &Path, sha256: bool)
+  -> Result<OutputStats>
+{
+  let mut out = create_file(partial_path(path))?;
+  let mut stats = OutputStats
+  {
+    path: path.to_path_buf(),
+    lines: 0,
+    bytes: 0,
+    last_line_pos: 0,
+    sha256: None
+  };
+  for output in outputs.iter()
+  {
+    let output_path = output.path.to_string_lossy().into_owned();
+    let mut file = File::open(&output.path)
+      .map_err(|error| Error::Open(output_path.clone(), error))?;
+    io::copy(&mut file, &mut out)?;
+    if sha256
+    {
+      stats.sha256 = Some(match (stats.sha256.take(), output.sha256.as_ref())
+      {
+        // the digest of the first file is already known
+        (None, Some(output_sha256)) => output_sha256.clone(),
+        (digest, _) =>
+        {
+          let mut digest = digest.unwrap_or_else(Sha256::new);
+          let mut file = File::open(&output.path)
+            .map_err(|error| Error::Open(output_path, error))?;
+          io::copy(&mut file, &mut digest)?;
+          digest
+        }
+      });
+    }
+    if output.lines > 0
+    {
+      stats.last_line_pos = stats.bytes + output.last_line_pos;
+    }
+    stats.lines += output.lines;
+    stats.bytes += output.bytes;
+  }
+  out.sync_all()?;
+  publish_files(&vec![path.to_path_buf()])?;
+  for output in outputs.iter()
+  {
+    fs::remove_file(&output.path)?;
+  }
+  /*return*/ Ok(stats)
+}
+
+/// Returns the digest of the data written so far to `sha256` in hexadecimal,
+/// as `sha256sum` prints it
+pub fn hex_digest(sha256: &Sha256) -> String
+{
+  format!("{:x}", sha256.clone().finalize())
+}
+
+/// Returns the combined digest of the output files described by `outputs`:
+/// the SHA-256 digest of their digests in order, in hexadecimal and one per
+/// line, as `sha256sum $(ls -v output/[0-9]*) | cut -d' ' -f1 | sha256sum`
+/// prints it. Unlike the digest of their concatenation, it's computed from
+/// the digests of the files written in parallel without reading them again.
+/// Returns None if the digest of any of them was not computed.
+pub fn combined_digest(outputs: &Vec<OutputStats>) -> Option<String>
+{
+  let mut combined = Sha256::new();
+  for output in outputs.iter()
+  {
+    combined.update(hex_digest(output.sha256.as_ref()?).as_bytes());
+    combined.update(b"\n");
+  }
+  /*return*/ Some(hex_digest(&combined))
+}
+
+/// Prints the lines, size and digest of each output file, and the combined
+/// digest of all of them if they were computed
+pub fn print_output_stats(outputs: &Vec<OutputStats>)
+{
+  for output in outputs.iter()
+  {
+    print!("{}: lines={} bytes={}", output.path.display(), output.lines, output.bytes);
+    match output.sha256
+    {
+      Some(ref sha256) => println!(" sha256={}", hex_digest(sha256)),
+      None => println!()
+    }
+  }
+  if let Some(combined_sha256) = combined_digest(outputs)
+  {
+    println!("combined_sha256={}", combined_sha256);
+  }
+}
+
+/// Writes an empty SUCCESS_MARKER file in the output directory, to tell
+/// whoever watches it that all the output files of a run have been published
+pub fn write_success_marker(output_path: &String) -> Result<()>
+{
+  let path = Path::new(output_path).join(SUCCESS_MARKER);
+  create_file(&path)?.sync_all()?;
+  sync_dir(Path::new(output_path))
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::fs;
+  use std::io;
+  use std::io::prelude::*;
+  use std::path::Path;
+  use std::path::PathBuf;
+  use std::thread;
+  use tempdir::TempDir;
+
+  use test_helpers::_assert_files_eq;
+
+  use publish::partial_path;
+  use CsvDialect;
+  use publish::hex_digest;
+  use publish::combined_digest;
+  use publish::parts_path;
+  use publish::create_partial_file;
+  use publish::sync_file;
+  use publish::publish_outputs;
+  use publish::concatenate_outputs;
+  use publish::stream_writer;
+  use publish::STREAM_CHUNK_SIZE;
+  use publish::STREAM_BUFFER_CHUNKS;
+  use publish::write_success_marker;
+
+  #[test]
+  fn test_partial_path()
+  {
+    assert_eq!(partial_path("out/3"), Path::new("out/.3.partial"));
+    assert_eq!(partial_path("3"), Path::new(".3.partial"));
+    assert_eq!(parts_path("out/joined.csv"), Path::new("out/.joined.csv.parts"));
+  }
+
+  #[test]
+  fn test_publish_files()
+  {
+    let tmp_dir = TempDir::new("publish").expect("create temp dir");
+    let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+    let paths: Vec<PathBuf> = (0..2).map(|i| tmp_dir.path().join(i.to_string()))
+      .collect();
+
+    let mut outputs = vec![];
+    // a line can be split between writes, and only the digest of the first
+    // file is computed
+    for (path, writes) in paths.iter().zip([vec!["a\nb", "c\n"], vec!["d\n"]].iter())
+    {
+      let mut out = create_partial_file(path).unwrap();
+      out.set_sha256(outputs.is_empty());
+      for content in writes.iter()
+      {
+        out.write_all(content.as_bytes()).unwrap();
+      }
+      outputs.push(sync_file(&mut out).unwrap());
+      assert!(!path.exists());
+    }
+    assert_eq!(outputs[0].lines, 2);
+    assert_eq!(outputs[0].bytes, 5);
+    assert_eq!(outputs[0].last_line_pos, 2);
+    assert_eq!(
+      hex_digest(outputs[0].sha256.as_ref().unwrap()),
+      "4b8d1dd1e2b97bee100f449a998ac84a292fb223d969296c4737b44459e545de"
+    );
+    assert!(outputs[1].sha256.is_none());
+    assert_eq!(combined_digest(&outputs), None);
+
+    publish_outputs(outputs).unwrap();
+    _assert_files_eq(&tmp_dir_path, "a\nbc\n|d\n");
+    assert!(!partial_path(&paths[0]).exists());
+
+    write_success_marker(&tmp_dir_path).unwrap();
+    assert_eq!(fs::read_dir(&tmp_dir_path).unwrap().count(), 3);
+  }
+  #[test]
+  fn test_concatenate_outputs()
+  {
+    let tmp_dir = TempDir::new("publish").expect("create temp dir");
+    let path = tmp_dir.path().join("out");
+    let parts_dir_path = parts_path(&path);
+    fs::create_dir(&parts_dir_path).unwrap();
+
+    // the digest of the output file is only computed if asked for
+    for &sha256 in [true, false].iter()
+    {
+      let mut outputs = vec![];
+      for (i, content) in ["a\nb\n", "", "c\n"].iter().enumerate()
+      {
+        let mut out = create_partial_file(parts_dir_path.join(i.to_string())).unwrap();
+        out.set_sha256(sha256);
+        out.write_all(content.as_bytes()).unwrap();
+        outputs.push(sync_file(&mut out).unwrap());
+      }
+      let outputs = publish_outputs(outputs).unwrap();
+      // the digest of the digests of the files, not of their concatenation
+      assert_eq!(
+        combined_digest(&outputs),
+        match sha256
+        {
+          true => Some(String::from(
+            "7f6c961369a81906415dbb59418ddcce8dff484d3f070c8a2505e5bd0d3a0a9a"
+          )),
+          false => None
+        }
+      );
+
+      let output = concatenate_outputs(&outputs, &path, sha256).unwrap();
+      assert_eq!(fs::read_to_string(&path).unwrap(), "a\nb\nc\n");
+      assert_eq!(output.path, path);
+      assert_eq!(output.lines, 3);
+      assert_eq!(output.bytes, 6);
+      assert_eq!(output.last_line_pos, 4);
+      assert_eq!(
+        output.sha256.as_ref().map(hex_digest),
+        match sha256
+        {
+          true => Some(String::from(
+            "880553fca8fcea94e325ee2cfb48e5a985cc797f39a14cc6d3cedecfeb2ae4d2"
+          )),
+          false => None
+        }
+      );
+      assert_eq!(fs::read_dir(&parts_dir_path).unwrap().count(), 0);
+      assert!(!partial_path(&path).exists());
+    }
+  }
+  #[test]
+  fn test_csv_records()
+  {
+    let tmp_dir = TempDir::new("publish").expect("create temp dir");
+    let mut out = create_partial_file(tmp_dir.path().join("0")).unwrap();
+    out.set_csv(Some(CsvDialect::new('"', Some('\\')).unwrap()));
+    // escape chars at the end of a write, before a new line and before a
+    // char that is not special
+    for content in ["\"a\\", "\"\n\";b\n\"c\\", "\nd\";e\n\"\\y\";f\n"].iter()
+    {
+      out.write_all(content.as_bytes()).unwrap();
+    }
+    let output = sync_file(&mut out).unwrap();
+    assert_eq!(output.lines, 3);
+    assert_eq!(output.bytes, 25);
+    assert_eq!(output.last_line_pos, 18);
+    assert!(output.sha256.is_none());
+  }
+  #[test]
+  fn test_stream_writer()
+  {
+    // more chunks than fit in the buffer, so the writer has to wait for them
+    // to be received
+    let line = "0123456789abcdef\n";
+    let num_lines = (STREAM_BUFFER_CHUNKS + 2) * STREAM_CHUNK_SIZE / line.len() + 1;
+    let (mut out, receiver) = stream_writer();
+    let child = thread::spawn(move ||
+    {
+      for _ in 0..num_lines
+      {
+        out.write_all(line.as_bytes()).unwrap();
+      }
+      sync_file(&mut out).unwrap()
+    });
+    let mut received: Vec<u8> = vec![];
+    for chunk in receiver.iter()
+    {
+      assert!(chunk.len() <= STREAM_CHUNK_SIZE + line.len());
+      received.extend_from_slice(&chunk);
+    }
+    let output = child.join().unwrap();
+    assert_eq!(received, line.repeat(num_lines).into_bytes());
+    assert_eq!(output.lines, num_lines as u64);
+    assert_eq!(output.bytes, received.len() as u64);
+
+    // writing fails once nobody receives the chunks
+    let (mut out, receiver) = stream_writer();
+    drop(receiver);
+    match out.write_all(line.repeat(STREAM_CHUNK_SIZE / line.len() + 1).as_bytes())
+    {
+      Err(error) => assert_eq!(error.kind(), io::ErrorKind::BrokenPipe),
+      result => panic!("expected a broken pipe error, got {:?}", result)
+    }
+  }
+}
@@ -0,0 +1,353 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Cursor;
+use std::io::SeekFrom;
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use memmap::Mmap;
+use memmap::Protection;
+
+use Error;
+use Result;
+use csv::CsvFile;
+
+/// A reader that can also seek, as returned by Source::open_at
+pub trait SeekRead: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> SeekRead for T {}
+
+/// Storage of one of the files of an input, that can be read from any
+/// position. MultiFileReader reads a list of them as if they were only one
+/// file, and so ByteRangeLineReader and all the execute_* functions can read
+/// from any of them.
+///
+/// LocalFile reads a file in the local file system, and MemoryFile reads a
+/// buffer in memory or a memory-mapped file.
+pub trait Source: Send + Sync
+{
+  /// Returns a name for the file, like its path, to use in errors and
+  /// verbose output
+  fn name(&self) -> String;
+
+  /// Returns the size of the file in bytes
+  fn len(&self) -> io::Result<u64>;
+
+  /// Reads bytes starting at the position `pos` into `buf`, returning how many
+  /// were read, which are less than its length only at the end of the file
+  fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+  /// Returns a reader of the file, positioned at `pos`
+  fn open_at(&self, pos: u64) -> io::Result<Box<dyn SeekRead>>;
+
+  /// Returns the file as a CsvFile if it is one, whose records are not just
+  /// lines
+  fn csv(&self) -> Option<&CsvFile>
+  {
+    None
+  }
+}
+
+/// Something that can be used as one of the files of an input: a path to a
+/// local file, or any Source
+pub trait ToSource
+{
+  fn to_source(&self) -> Arc<dyn Source>;
+}
+
+impl ToSource for String
+{
+  fn to_source(&self) -> Arc<dyn Source>
+  {
+    Arc::new(LocalFile::new(self.clone()))
+  }
+}
+
+impl<'a> ToSource for &'a str
+{
+  fn to_source(&self) -> Arc<dyn Source>
+  {
+    Arc::new(LocalFile::new(String::from(*self)))
+  }
+}
+
+impl ToSource for Arc<dyn Source>
+{
+  fn to_source(&self) -> Arc<dyn Source>
+  {
+    self.clone()
+  }
+}
+
+/// Returns the sources of a list of files
+pub fn to_sources<S: ToSource>(file_list: &Vec<S>) -> Vec<Arc<dyn Source>>
+{
+  file_list.iter().map(|file| file.to_source()).collect()
+}
+
+/// Maps in memory each of a list of local files, see MemoryFile::map. The
+/// returned sources can be cloned and sent to other threads without mapping
+/// the files again.
+pub fn map_files(path_list: &Vec<String>) -> Result<Vec<Arc<dyn Source>>>
+{
+  path_list.iter().map(
+    |path|
+    {
+      let file = MemoryFile::map(path)
+        .map_err(|error| Error::Open(path.clone(), error))?;
+      let source: Arc<dyn Source> = Arc::new(file);
+      /*return*/ Ok(source)
+    }
+  ).collect()
+}
+
+/// Returns the sources of a list of local files: their memory maps if `mmap`
+/// is true, see map_files, or else LocalFiles
+pub fn file_sources(path_list: &Vec<String>, mmap: bool)
+  -> Result<Vec<Arc<dyn Source>>>
+{
+  if mmap
+  {
+    map_files(path_list)
+  }
+  else
+  {
+    Ok(to_sources(path_list))
+  }
+}
+
+/// Reads into `buf` from `reader` until it is full or the reader ends,
+/// returning how many bytes were read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize>
+{
+  let mut len: usize = 0;
+  while len < buf.len()
+  {
+    match reader.read(&mut buf[len..])?
+    {
+      0 => break,
+      read_len => len += read_len
+    }
+  }
+  /*return*/ Ok(len)
+}
+
+/// A file in the local file system. It's opened again each time it is read.
+#[derive(Debug, Clone)]
+pub struct LocalFile
+{
+  path: String
+}
+
+impl LocalFile
+{
+  pub fn new(path: String) -> LocalFile
+  {
+    LocalFile
+    {
+      path: path
+    }
+  }
+}
+
+impl Source for LocalFile
+{
+  fn name(&self) -> String
+  {
+    self.path.clone()
+  }
+
+  fn len(&self) -> io::Result<u64>
+  {
+    fs::metadata(&self.path).map(|metadata| metadata.len())
+  }
+
+  fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize>
+  {
+    let mut file = self.open_at(pos)?;
+    read_full(&mut file, buf)
+  }
+
+  fn open_at(&self, pos: u64) -> io::Result<Box<dyn SeekRead>>
+  {
+    let mut file = File::open(&self.path)?;
+    file.seek(SeekFrom::Start(pos))?;
+    /*return*/ Ok(Box::new(file))
+  }
+}
+
+/// A read-only memory map of a whole file
+struct MappedBytes(Mmap);
+
+impl AsRef<[u8]> for MappedBytes
+{
+  fn as_ref(&self) -> &[u8]
+  {
+    // the map is read-only and private to this struct, so the slice can only
+    // change if the file is modified while mapped, which we don't support
+    unsafe { self.0.as_slice() }
+  }
+}
+
+/// Contents of a MemoryFile, shared by all its readers
+#[derive(Clone)]
+struct SharedBytes(Arc<dyn AsRef<[u8]> + Send + Sync>);
+
+impl AsRef<[u8]> for SharedBytes
+{
+  fn as_ref(&self) -> &[u8]
+  {
+    (*self.0).as_ref()
+  }
+}
+
+/// A file kept in memory, whose contents are shared by all its readers. Useful
+/// to read data that doesn't come from a file, and in tests.
+///
+/// A MemoryFile can also be a memory-mapped local file, see `map`, so that
+/// reading it, seeking and searching for keys are just copies from memory,
+/// without any system call.
+#[derive(Clone)]
+pub struct MemoryFile
+{
+  name: String,
+  data: SharedBytes
+}
+
+impl MemoryFile
+{
+  pub fn new(name: &str, data: Vec<u8>) -> MemoryFile
+  {
+    MemoryFile
+    {
+      name: String::from(name),
+      data: SharedBytes(Arc::new(data))
+    }
+  }
+
+  /// Maps the local file at `path` in memory, read-only. The file must not be
+  /// modified while it's mapped.
+  pub fn map(path: &str) -> io::Result<MemoryFile>
+  {
+    let file = File::open(path)?;
+    // empty files can't be mapped
+    if file.metadata()?.len() == 0
+    {
+      return Ok(MemoryFile::new(path, Vec::new()))
+    }
+    let map = Mmap::open(&file, Protection::Read)?;
+    return Ok(MemoryFile
+    {
+      name: String::from(path),
+      data: SharedBytes(Arc::new(MappedBytes(map)))
+    })
+  }
+}
+
+impl Source for MemoryFile
+{
+  fn name(&self) -> String
+  {
+    self.name.clone()
+  }
+
+  fn len(&self) -> io::Result<u64>
+  {
+    Ok(self.data.as_ref().len() as u64)
+  }
+
+  fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize>
+  {
+    let data: &[u8] = self.data.as_ref();
+    let start = (pos as usize).min(data.len());
+    let len = buf.len().min(data.len() - start);
+    buf[..len].copy_from_slice(&data[start..start + len]);
+    /*return*/ Ok(len)
+  }
+
+  fn open_at(&self, pos: u64) -> io::Result<Box<dyn SeekRead>>
+  {
+    let mut cursor = Cursor::new(self.data.clone());
+    cursor.set_position(pos);
+    /*return*/ Ok(Box::new(cursor))
+  }
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::fs::File;
+  use std::io::prelude::*;
+  use std::sync::Arc;
+  use tempdir::TempDir;
+
+  use test_helpers::_write_files;
+
+  use source::Source;
+  use source::ToSource;
+  use source::MemoryFile;
+  use source::to_sources;
+  use source::map_files;
+
+  #[test]
+  fn test_sources()
+  {
+    let tmp_dir = TempDir::new("source").expect("create temp dir");
+    let files = _write_files("ab,cd", &tmp_dir);
+    let memory_file: Arc<dyn Source> = Arc::new(MemoryFile::new("memory", b"ab\ncd\n".to_vec()));
+    let mut sources = to_sources(&files);
+    sources.push(memory_file.to_source());
+    sources.extend(map_files(&files).unwrap());
+
+    for source in sources.iter()
+    {
+      assert_eq!(source.len().unwrap(), 6);
+
+      let mut buf = [0; 4];
+      assert_eq!(source.read_at(1, &mut buf).unwrap(), 4);
+      assert_eq!(&buf, b"b\ncd");
+      assert_eq!(source.read_at(4, &mut buf).unwrap(), 2);
+      assert_eq!(&buf[..2], b"d\n");
+      assert_eq!(source.read_at(7, &mut buf).unwrap(), 0);
+
+      let mut content = String::new();
+      source.open_at(3).unwrap().read_to_string(&mut content).unwrap();
+      assert_eq!(content, "cd\n");
+    }
+    assert_eq!(sources[0].name(), files[0]);
+    assert_eq!(sources[1].name(), "memory");
+    assert_eq!(sources[2].name(), files[0]);
+  }
+
+  #[test]
+  fn test_map_empty_file()
+  {
+    let tmp_dir = TempDir::new("source").expect("create temp dir");
+    let path = String::from(tmp_dir.path().join("empty").to_str().unwrap());
+    File::create(&path).unwrap();
+
+    let sources = map_files(&vec![path.clone()]).unwrap();
+    assert_eq!(sources[0].len().unwrap(), 0);
+    let mut buf = [0; 4];
+    assert_eq!(sources[0].read_at(0, &mut buf).unwrap(), 0);
+
+    assert!(map_files(&vec![path + ".missing"]).is_err());
+  }
+}
@@ -0,0 +1,164 @@
+/**
+ * Copyright (C) 2017 Eduardo Robles Elvira <edulix@nvotes.com>
+
+ * paralio is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License.
+
+ * paralio is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+
+ * You should have received a copy of the GNU Affero General Public License
+ * along with paralio.  If not, see <http://www.gnu.org/licenses/>.
+**/
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::prelude::*;
+
+use tempdir::TempDir;
+
+use Error;
+use Result;
+use output_file::create_file;
+
+/// Input path that means reading the input from stdin
+pub const STDIN_PATH: &'static str = "-";
+
+/// Returns whether the file at the given path can be read at any position,
+/// which is what reading it in parallel needs. Stdin, named pipes and devices
+/// can only be read once from start to end.
+pub fn is_seekable(path: &String) -> Result<bool>
+{
+  if path == STDIN_PATH
+  {
+    return Ok(false)
+  }
+  let metadata = fs::metadata(path)
+    .map_err(|error| Error::Open(path.clone(), error))?;
+  /*return*/ Ok(metadata.is_file() || metadata.is_dir())
+}
+
+/// An input file list in which the files that can't be read at any position,
+/// see is_seekable, have been replaced by temporary copies of them, which are
+/// removed when it's dropped. The rest of the files are read in place.
+///
+/// This allows using the output of another command as an input, like
+/// `psql ... | sort | pjoin --file2 - ...`, at the cost of writing it to disk
+/// once before the input is partitioned as usual.
+pub struct SpooledInput
+{
+  /// The input file list, with the temporary copies
+  pub file_str_list: Vec<String>,
+  tmp_dir: Option<TempDir>
+}
+
+impl SpooledInput
+{
+  /// Copies the files of the list that are not seekable to a temporary
+  /// directory created inside `tmp_path` if there is any. STDIN_PATH can be
+  /// used to read stdin.
+  pub fn new(file_str_list: &Vec<String>, tmp_path: &String, verbose: bool)
+    -> Result<SpooledInput>
+  {
+    let mut spooled = SpooledInput
+    {
+      file_str_list: vec![],
+      tmp_dir: None
+    };
+    for (i, path) in file_str_list.iter().enumerate()
+    {
+      if is_seekable(path)?
+      {
+        spooled.file_str_list.push(path.clone());
+        continue;
+      }
+      if spooled.tmp_dir.is_none()
+      {
+        spooled.tmp_dir = Some(
+          TempDir::new_in(tmp_path, "spool")
+            .map_err(|error| Error::Open(tmp_path.clone(), error))?
+        );
+      }
+      let spool_path = spooled.tmp_dir.as_ref().unwrap().path().join(i.to_string());
+      if verbose {
+        println!("SpooledInput::new: copying {} to {:?}", path, spool_path);
+      }
+      let mut out = BufWriter::new(create_file(&spool_path)?);
+      if path == STDIN_PATH
+      {
+        let stdin = io::stdin();
+        io::copy(&mut stdin.lock(), &mut out)?;
+      }
+      else
+      {
+        let mut file = File::open(path)
+          .map_err(|error| Error::Open(path.clone(), error))?;
+        io::copy(&mut file, &mut out)?;
+      }
+      out.flush()?;
+      spooled.file_str_list.push(spool_path.to_string_lossy().into_owned());
+    }
+    /*return*/ Ok(spooled)
+  }
+
+  /// Returns whether any of the files was copied
+  pub fn is_spooled(&self) -> bool
+  {
+    self.tmp_dir.is_some()
+  }
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::fs::OpenOptions;
+  use std::io::prelude::*;
+  use std::process::Command;
+  use std::thread;
+  use tempdir::TempDir;
+
+  use test_helpers::_write_files;
+  use test_helpers::_assert_file_eq;
+
+  use spool::SpooledInput;
+  use spool::is_seekable;
+
+  #[test]
+  fn test_spooled_input()
+  {
+    let tmp_dir = TempDir::new("spool").expect("create temp dir");
+    let tmp_dir_path = String::from(tmp_dir.path().to_str().unwrap());
+    let files = _write_files("a,b", &tmp_dir);
+    let fifo_path = String::from(tmp_dir.path().join("fifo").to_str().unwrap());
+    assert!(Command::new("mkfifo").arg(&fifo_path).status().unwrap().success());
+    assert_eq!(is_seekable(&files[0]).unwrap(), true);
+    assert_eq!(is_seekable(&fifo_path).unwrap(), false);
+
+    let writer_fifo_path = fifo_path.clone();
+    let writer = thread::spawn(move ||
+    {
+      OpenOptions::new().write(true).open(&writer_fifo_path).unwrap()
+        .write_all(b"c\nd\n").unwrap();
+    });
+    let spooled = SpooledInput::new(
+      &vec![files[0].clone(), fifo_path.clone()], &tmp_dir_path, false
+    ).unwrap();
+    writer.join().unwrap();
+
+    assert!(spooled.is_spooled());
+    assert_eq!(spooled.file_str_list[0], files[0]);
+    assert!(spooled.file_str_list[1] != fifo_path);
+    _assert_file_eq(&spooled.file_str_list[1], "c\nd\n");
+    assert_eq!(is_seekable(&spooled.file_str_list[1]).unwrap(), true);
+
+    // the copies are removed with it
+    let spool_path = spooled.file_str_list[1].clone();
+    drop(spooled);
+    assert!(OpenOptions::new().read(true).open(&spool_path).is_err());
+  }
+}
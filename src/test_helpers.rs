@@ -17,9 +17,20 @@
 use std::io::prelude::*;
 use std::fs::File;
 use std::path::Path;
+use std::sync::Arc;
 
 use tempdir::TempDir;
 
+use source::Source;
+use source::MemoryFile;
+use FieldFormat;
+
+// Returns the FieldFormat of lines separated by `separator`, without quotes
+pub fn _format(separator: &str) -> FieldFormat
+{
+  FieldFormat::new(&String::from(separator), None).unwrap()
+}
+
 // compares a file's contents with a string
 pub fn _assert_file_eq(path: &String, content: &str)
 {
@@ -51,6 +62,24 @@ pub fn _write_files(s: &str, tmp_dir: &TempDir) -> Vec<String>
   ).collect()
 }
 
+// Like _write_files, but keeps the files in memory
+pub fn _memory_files(s: &str) -> Vec<Arc<dyn Source>>
+{
+  return s.split('|').enumerate().map(
+    |x: (usize, &str)|
+    {
+      let mut data: Vec<u8> = Vec::new();
+      for fline in x.1.split(',')
+      {
+        data.extend_from_slice(fline.as_bytes());
+        data.push(b'\n');
+      }
+      let file: Arc<dyn Source> = Arc::new(MemoryFile::new(&x.0.to_string(), data));
+      return file
+    }
+  ).collect()
+}
+
 // compares multiple consecutive file's contents with a string. Each file is
 // a number (0, 1, 2, etc) and their content is separated in `content` by a
 // '|' character.